@@ -0,0 +1,116 @@
+// This implementation is based on the guide provided by [RetroGameDeveloper/RetroReversing].
+// Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
+// Copyright (c) 2023 Nicholas Ricciuti
+//
+// archive.rs
+//
+// This module reads zipped ROMs. It only understands what ROM archives actually use:
+// stored and deflated entries in a regular (non zip64) archive.
+
+use flate2::read::DeflateDecoder;
+use std::io::Read;
+
+// Zip record signatures and the fixed sizes of their headers.
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x02014b50;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const CENTRAL_DIRECTORY_HEADER_SIZE: usize = 46;
+const LOCAL_HEADER_SIZE: usize = 30;
+const MAX_COMMENT_SIZE: usize = 0xFFFF;
+
+// Compression methods we can extract.
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+// Represents a file inside a zip archive.
+pub struct ZipEntry {
+    pub name: String,
+    method: u16,
+    compressed_size: usize,
+    uncompressed_size: usize,
+    local_header_offset: usize,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| "Unexpected end of zip archive".to_string())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| "Unexpected end of zip archive".to_string())
+}
+
+// Lists the files in a zip archive by walking its central directory.
+pub fn list_entries(data: &[u8]) -> Result<Vec<ZipEntry>, String> {
+    // The end of central directory record sits at the very end, before an optional comment
+    let search_start = data
+        .len()
+        .saturating_sub(END_OF_CENTRAL_DIRECTORY_SIZE + MAX_COMMENT_SIZE);
+    let end_of_directory = (search_start
+        ..=data.len().saturating_sub(END_OF_CENTRAL_DIRECTORY_SIZE))
+        .rev()
+        .find(|&offset| read_u32(data, offset) == Ok(END_OF_CENTRAL_DIRECTORY_SIGNATURE))
+        .ok_or_else(|| "Not a zip archive, no central directory found".to_string())?;
+
+    let entry_count = read_u16(data, end_of_directory + 10)? as usize;
+    let mut offset = read_u32(data, end_of_directory + 16)? as usize;
+
+    let mut entries = Vec::with_capacity(entry_count);
+    for _ in 0..entry_count {
+        if read_u32(data, offset)? != CENTRAL_DIRECTORY_SIGNATURE {
+            return Err("Corrupt zip central directory".to_string());
+        }
+        let name_length = read_u16(data, offset + 28)? as usize;
+        let extra_length = read_u16(data, offset + 30)? as usize;
+        let comment_length = read_u16(data, offset + 32)? as usize;
+        let name_start = offset + CENTRAL_DIRECTORY_HEADER_SIZE;
+        let name = data
+            .get(name_start..name_start + name_length)
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .ok_or_else(|| "Unexpected end of zip archive".to_string())?;
+
+        entries.push(ZipEntry {
+            name,
+            method: read_u16(data, offset + 10)?,
+            compressed_size: read_u32(data, offset + 20)? as usize,
+            uncompressed_size: read_u32(data, offset + 24)? as usize,
+            local_header_offset: read_u32(data, offset + 42)? as usize,
+        });
+        offset = name_start + name_length + extra_length + comment_length;
+    }
+    Ok(entries)
+}
+
+// Extracts a single file from a zip archive into memory.
+pub fn extract_entry(data: &[u8], entry: &ZipEntry) -> Result<Vec<u8>, String> {
+    let offset = entry.local_header_offset;
+    if read_u32(data, offset)? != LOCAL_HEADER_SIGNATURE {
+        return Err(format!("Corrupt zip entry: {}", entry.name));
+    }
+    // The local header can carry a different extra field than the central directory
+    let name_length = read_u16(data, offset + 26)? as usize;
+    let extra_length = read_u16(data, offset + 28)? as usize;
+    let data_start = offset + LOCAL_HEADER_SIZE + name_length + extra_length;
+    let compressed = data
+        .get(data_start..data_start + entry.compressed_size)
+        .ok_or_else(|| format!("Truncated zip entry: {}", entry.name))?;
+
+    match entry.method {
+        METHOD_STORED => Ok(compressed.to_vec()),
+        METHOD_DEFLATED => {
+            let mut contents = Vec::with_capacity(entry.uncompressed_size);
+            DeflateDecoder::new(compressed)
+                .read_to_end(&mut contents)
+                .map_err(|e| format!("Failed to decompress {}: {}", entry.name, e))?;
+            Ok(contents)
+        }
+        method => Err(format!(
+            "Unsupported zip compression method {} for {}",
+            method, entry.name
+        )),
+    }
+}
@@ -5,77 +5,498 @@
 // The `audio` module handles audio processing and playback for the emulator.
 // It uses the `rodio` crate for audio output and integrates with the libretro API for audio data.
 
-use crate::{AUDIO_DATA_CHANNEL, TARGET_FPS};
+use crate::{FAST_FORWARDING, FINAL_SAMPLE_RATE, REWINDING};
 use once_cell::sync::Lazy;
 use rodio::buffer::SamplesBuffer;
-use rodio::Sink;
+use rodio::cpal::traits::HostTrait;
+use rodio::{Device, DeviceTrait, Sink};
 use std::{
-    sync::{atomic::Ordering, Arc, Mutex},
-    thread,
-    time::Duration,
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 // Constants for audio processing.
 const AUDIO_CHANNELS: usize = 2; // Stereo audio with left and right channels.
-const FINAL_SAMPLE_RATE: u32 = 48_000; // Sample rate in Hertz (48 kHz).
-const BUFFER_DURATION_MS: u32 = 64; // Duration of each audio buffer in milliseconds.
-const BUFFER_LENGTH: usize = (FINAL_SAMPLE_RATE as u32 * BUFFER_DURATION_MS / 1000) as usize; // Number of samples in each buffer.
-const POOL_SIZE: usize = 20; // Number of buffers in the audio buffer pool.
+const DEFAULT_BUFFER_DURATION_MS: u32 = 64; // Duration of each audio buffer in milliseconds.
+const DEFAULT_POOL_SIZE: usize = 20; // Number of buffers in the audio buffer pool.
+const BUFFER_DURATION_RANGE_MS: (u32, u32) = (8, 1000); // Accepted `audio_latency` values.
+const POOL_SIZE_RANGE: (usize, usize) = (1, 256); // Accepted `audio_buffer_pool_size` values.
+const LOW_FILL: f64 = 0.25; // Sink fill below which whatever is queued gets played.
+const MAX_AUDIO_WAIT: Duration = Duration::from_millis(16); // Longest wait, to follow volume changes.
+const MAX_VOLUME: f32 = 2.0; // Upper bound for the master volume.
+const VOLUME_STEP: f32 = 0.1; // Amount each volume hotkey press changes the volume by.
 
-// Represents an audio buffer containing raw audio samples.
-pub struct AudioBuffer {
-    data: Vec<i16>, // Vector to store the 16-bit audio samples.
+// Channels in each frame of the core's sample batches.
+static CORE_AUDIO_CHANNELS: AtomicUsize = AtomicUsize::new(AUDIO_CHANNELS);
+// Buffer sizing, set from the config before any audio is queued.
+static BUFFER_DURATION_MS: AtomicU32 = AtomicU32::new(DEFAULT_BUFFER_DURATION_MS);
+static POOL_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_POOL_SIZE);
+// Samples queued by the core's audio callbacks, drained by the audio thread.
+pub static AUDIO_BUFFER: Lazy<Mutex<VecDeque<i16>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+// Signalled whenever new samples are queued in `AUDIO_BUFFER`.
+pub static AUDIO_CONDVAR: Condvar = Condvar::new();
+
+// Number of samples in each buffer, derived from the sample rate the core actually runs at.
+fn buffer_length() -> usize {
+    let duration_ms = BUFFER_DURATION_MS.load(Ordering::SeqCst) as u64;
+    (FINAL_SAMPLE_RATE.load(Ordering::SeqCst) as u64 * duration_ms / 1000).max(1) as usize
+}
+
+// How full the sample queue is, relative to one buffer's worth of audio.
+pub fn buffer_fill() -> f32 {
+    let capacity = (buffer_length() * AUDIO_CHANNELS).max(1);
+    AUDIO_BUFFER.lock().unwrap().len() as f32 / capacity as f32
+}
+
+// Emptied sample buffers, reused by `play_audio` to take samples off `AUDIO_BUFFER` so it
+// doesn't allocate for every chunk. At most `audio_buffer_pool_size` are kept.
+static BUFFER_POOL: Lazy<Mutex<Vec<Vec<i16>>>> = Lazy::new(|| {
+    let pool = (0..POOL_SIZE.load(Ordering::SeqCst))
+        .map(|_| Vec::with_capacity(buffer_length() * AUDIO_CHANNELS))
+        .collect();
+    Mutex::new(pool)
+});
+
+fn take_pooled_buffer() -> Vec<i16> {
+    BUFFER_POOL.lock().unwrap().pop().unwrap_or_default()
+}
+
+fn return_pooled_buffer(mut buffer: Vec<i16>) {
+    buffer.clear();
+    let mut pool = BUFFER_POOL.lock().unwrap();
+    if pool.len() < POOL_SIZE.load(Ordering::SeqCst) {
+        pool.push(buffer);
+    }
+}
+
+// Master volume, stored as the bits of an `f32`, and the mute toggle.
+static VOLUME: AtomicU32 = AtomicU32::new(0x3F80_0000); // 1.0
+static MUTED: AtomicBool = AtomicBool::new(false);
+// Cleared when running without sound, so nothing queues samples no one will play.
+static OUTPUT_ENABLED: AtomicBool = AtomicBool::new(true);
+// Set while emulation is paused because the window lost focus, apart from the mute hotkey.
+static FOCUS_MUTED: AtomicBool = AtomicBool::new(false);
+// Whether samples are resampled and scaled by the volume as floats, see `set_output_format`.
+static FLOAT_OUTPUT: AtomicBool = AtomicBool::new(false);
+static SYNC_SOURCE: Lazy<Mutex<SyncSource>> = Lazy::new(|| Mutex::new(SyncSource::Video));
+// How many times normal speed fast-forward runs the core at, as the bits of an `f64`. Zero
+// means as fast as the host allows.
+static FAST_FORWARD_SPEED: AtomicU64 = AtomicU64::new(0);
+// How much more audio the sink holds than the rate control aims for, in microseconds. This
+// is how late sound plays relative to the frame it belongs to, above the fixed latency.
+static SYNC_OFFSET_US: AtomicI64 = AtomicI64::new(0);
+
+// Which clock the other one follows to keep sound and picture together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncSource {
+    // Frames are paced to the display and the audio is resampled to keep up
+    Video,
+    // The audio plays at a fixed rate and frames are run a little sooner or later to match,
+    // so the display shows a frame twice or skips one now and then
+    Audio,
+}
+
+impl SyncSource {
+    pub fn from_config(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "video" => Some(SyncSource::Video),
+            "audio" => Some(SyncSource::Audio),
+            _ => None,
+        }
+    }
+}
+
+// Sets the speed fast-forward runs at from `fastforward_ratio`, so the audio can keep up.
+pub fn set_fast_forward_speed(ratio: f64) {
+    FAST_FORWARD_SPEED.store(ratio.max(0.0).to_bits(), Ordering::SeqCst);
+}
+
+// How many times faster than normal the core is running, which is how much faster its
+// samples arrive. None while fast-forwarding as fast as possible, since that isn't known.
+pub fn speed() -> Option<f64> {
+    if !FAST_FORWARDING.load(Ordering::SeqCst) {
+        return Some(1.0);
+    }
+    let fast_forward_speed = f64::from_bits(FAST_FORWARD_SPEED.load(Ordering::SeqCst));
+    (fast_forward_speed > 0.0).then_some(fast_forward_speed)
+}
+
+// Whether the core's audio is thrown away rather than played back distorted: while
+// rewinding, and while fast-forwarding at no fixed speed.
+fn dropping_audio() -> bool {
+    REWINDING.load(Ordering::SeqCst) || speed().is_none()
+}
+
+// Changes the master volume by `steps` increments, clamped to [0.0, MAX_VOLUME].
+pub fn adjust_volume(steps: f32) {
+    let volume = f32::from_bits(VOLUME.load(Ordering::SeqCst));
+    let volume = (volume + steps * VOLUME_STEP).clamp(0.0, MAX_VOLUME);
+    VOLUME.store(volume.to_bits(), Ordering::SeqCst);
+    log_info!("Volume: {:.0}%", volume * 100.0);
+}
+
+// Toggles mute without touching the configured volume.
+pub fn toggle_mute() {
+    let muted = !MUTED.fetch_xor(true, Ordering::SeqCst);
+    log_info!("{}", if muted { "Muted" } else { "Unmuted" });
+}
+
+pub fn disable_output() {
+    OUTPUT_ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn set_focus_muted(muted: bool) {
+    FOCUS_MUTED.store(muted, Ordering::SeqCst);
 }
 
-impl AudioBuffer {
-    // Constructs a new `AudioBuffer` with a specified size.
-    pub fn new(size: usize) -> Self {
-        AudioBuffer {
-            data: vec![0; size],
+// Returns the volume the sink should currently play at, taking mute into account.
+pub fn effective_volume() -> f32 {
+    if MUTED.load(Ordering::SeqCst) || FOCUS_MUTED.load(Ordering::SeqCst) {
+        0.0
+    } else {
+        f32::from_bits(VOLUME.load(Ordering::SeqCst))
+    }
+}
+
+// Finds the output device called `device_name`, as set by `audio_device`, or the system
+// default when the name is empty or no device has it. Returns None if there's no device at all.
+pub fn output_device(device_name: &str) -> Option<Device> {
+    let host = rodio::cpal::default_host();
+    if !device_name.is_empty() {
+        let devices: Vec<Device> = host
+            .output_devices()
+            .map(|devices| devices.collect())
+            .unwrap_or_default();
+        let device_names: Vec<String> = devices
+            .iter()
+            .map(|device| device.name().unwrap_or_default())
+            .collect();
+        if let Some(index) = device_names.iter().position(|name| name == device_name) {
+            log_info!("Audio device: {}", device_name);
+            return devices.into_iter().nth(index);
         }
+        log_warn!(
+            "Audio device '{}' not found, using the default. Available devices: {}",
+            device_name,
+            device_names.join(", ")
+        );
     }
+    host.default_output_device()
+}
 
-    // Clears the buffer, removing all audio samples.
-    pub fn clear(&mut self) {
-        self.data.clear();
+// Returns the sample rate of the output device, which is what rodio plays at.
+// Falls back to the core's own rate, skipping resampling, when the device can't be queried.
+pub fn output_sample_rate(device: &Device) -> u32 {
+    device
+        .default_output_config()
+        .map_or(FINAL_SAMPLE_RATE.load(Ordering::SeqCst), |config| {
+            config.sample_rate().0
+        })
+}
+
+// Converts interleaved stereo audio from the core's rate to the output device's rate
+// using linear interpolation. It carries the last frame and the fractional read
+// position across batches so consecutive buffers join up without clicks.
+pub struct Resampler {
+    base_step: f64, // Input frames per output frame at the nominal rates.
+    step: f64,      // Input frames consumed per output frame.
+    position: f64,  // Read position relative to the current batch.
+    last_frame: [i16; AUDIO_CHANNELS], // Final frame of the previous batch.
+}
+
+impl Resampler {
+    // Constructs a new `Resampler` converting from `source_rate` to `output_rate`.
+    pub fn new(source_rate: f64, output_rate: u32) -> Self {
+        Resampler {
+            base_step: source_rate / output_rate as f64,
+            step: source_rate / output_rate as f64,
+            position: 0.0,
+            last_frame: [0; AUDIO_CHANNELS],
+        }
     }
 
-    // Extends the buffer with audio samples from a slice.
-    pub fn extend_from_slice(&mut self, slice: &[i16]) {
-        self.data.extend_from_slice(slice);
+    // Scales the resampling ratio; above 1.0 produces fewer output frames, below 1.0 more.
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        self.step = self.base_step * adjustment;
     }
 
-    // Returns a pointer to the audio data.
-    pub fn as_ptr(&self) -> *const i16 {
-        self.data.as_ptr()
+    // Resamples one batch of interleaved samples.
+    pub fn process(&mut self, samples: &[i16]) -> Vec<i16> {
+        self.resample(samples, |sample| sample.round() as i16)
     }
 
-    // Returns the length of the audio data in samples.
-    pub fn len(&self) -> usize {
-        self.data.len()
+    // Resamples one batch into floats in [-1.0, 1.0], scaled by `volume`, so the
+    // interpolated values aren't rounded back to 16 bits.
+    pub fn process_float(&mut self, samples: &[i16], volume: f32) -> Vec<f32> {
+        self.resample(samples, |sample| (sample / 32768.0) as f32 * volume)
     }
+
+    fn resample<T>(&mut self, samples: &[i16], convert: impl Fn(f64) -> T) -> Vec<T> {
+        let frame_count = samples.len() / AUDIO_CHANNELS;
+        if frame_count == 0 {
+            return Vec::new();
+        }
+
+        // Frame -1 is the last frame of the previous batch
+        let sample_at = |frame: isize, channel: usize| -> f64 {
+            if frame < 0 {
+                self.last_frame[channel] as f64
+            } else {
+                samples[frame as usize * AUDIO_CHANNELS + channel] as f64
+            }
+        };
+
+        let mut output =
+            Vec::with_capacity(((frame_count as f64 / self.step) as usize + 1) * AUDIO_CHANNELS);
+        let mut position = self.position;
+        while position < (frame_count - 1) as f64 {
+            let frame = position.floor();
+            let fraction = position - frame;
+            for channel in 0..AUDIO_CHANNELS {
+                let current = sample_at(frame as isize, channel);
+                let next = sample_at(frame as isize + 1, channel);
+                output.push(convert(current + (next - current) * fraction));
+            }
+            position += self.step;
+        }
+
+        self.position = position - frame_count as f64;
+        self.last_frame.copy_from_slice(
+            &samples[(frame_count - 1) * AUDIO_CHANNELS..frame_count * AUDIO_CHANNELS],
+        );
+        output
+    }
+}
+
+// Dynamic rate control: keeps the audio queued in the sink near half of its target size by
+// stretching the resampling ratio a little. The core never produces audio at exactly the
+// rate the device plays it, and without this the queue slowly drains or grows until it
+// crackles. Adjustments of a fraction of a percent aren't audible as pitch changes.
+pub struct RateControl {
+    max_adjustment: f64,   // Largest relative change to the resampling ratio.
+    target: Duration,      // Amount of queued audio that counts as full.
+    queued_until: Instant, // When the audio handed to the sink so far runs out.
 }
 
-// Global buffer pool for managing audio buffers.
-static BUFFER_POOL: Lazy<Mutex<Vec<Arc<Mutex<Vec<i16>>>>>> = Lazy::new(|| {
-    let mut pool = Vec::new();
-    for _ in 0..POOL_SIZE {
-        pool.push(Arc::new(Mutex::new(vec![0; BUFFER_LENGTH])));
+impl RateControl {
+    pub fn new(max_adjustment: f64) -> Self {
+        RateControl {
+            max_adjustment: max_adjustment.abs(),
+            target: Duration::from_millis(2 * BUFFER_DURATION_MS.load(Ordering::SeqCst) as u64),
+            queued_until: Instant::now(),
+        }
     }
-    Mutex::new(pool)
-});
 
-// Plays audio using the `rodio` library.
-pub unsafe fn play_audio(sink: &Sink, audio_samples: &AudioBuffer, sample_rate: u32) {
-    let audio_slice =
-        std::slice::from_raw_parts(audio_samples.as_ptr() as *const i16, audio_samples.len());
-    let source = SamplesBuffer::new(AUDIO_CHANNELS.try_into().unwrap(), sample_rate, audio_slice);
-    sink.append(source);
+    // Fraction of the target currently queued in the sink, from 0.0 (empty) to 1.0.
+    pub fn fill(&self) -> f64 {
+        let queued = self.queued_until.saturating_duration_since(Instant::now());
+        (queued.as_secs_f64() / self.target.as_secs_f64()).min(1.0)
+    }
+
+    // How long the audio thread may wait for more samples before the sink runs low.
+    pub fn wait_timeout(&self) -> Duration {
+        // Without the core's audio there's nothing to top up with, and waking early would
+        // only spin on an empty queue
+        if dropping_audio() {
+            return MAX_AUDIO_WAIT;
+        }
+        // Fast-forwarded samples pile up that much sooner, so check on them that much sooner
+        let queued = self.queued_until.saturating_duration_since(Instant::now());
+        queued
+            .saturating_sub(self.target.mul_f64(LOW_FILL))
+            .div_f64(playback_speed())
+            .clamp(Duration::from_millis(1), MAX_AUDIO_WAIT)
+    }
+
+    // The rate adjustment for the next batch: more output while the queue is under half
+    // full, less while it's over. With audio as the sync source the video moves instead.
+    pub fn adjustment(&self) -> f64 {
+        if sync_source() == SyncSource::Audio {
+            return 1.0;
+        }
+        1.0 - self.max_adjustment * (1.0 - 2.0 * self.fill())
+    }
+
+    // Records that `frames` frames at `output_rate` were handed to the sink.
+    pub fn queued(&mut self, frames: usize, output_rate: u32) {
+        let now = Instant::now();
+        if self.queued_until < now {
+            log_debug!("Audio underrun");
+            self.queued_until = now;
+        }
+        self.queued_until += Duration::from_secs_f64(frames as f64 / output_rate as f64);
+        let queued = self.queued_until.saturating_duration_since(now);
+        SYNC_OFFSET_US.store(
+            queued.as_micros() as i64 - (self.target / 2).as_micros() as i64,
+            Ordering::SeqCst,
+        );
+    }
+}
+
+// Samples worth handing to the sink at once, a quarter of a buffer once played. Smaller
+// chunks are mostly overhead, larger ones add latency.
+fn play_chunk_len() -> usize {
+    let chunk_len = (buffer_length() * AUDIO_CHANNELS / 4) as f64 * playback_speed();
+    (chunk_len as usize).max(AUDIO_CHANNELS)
+}
+
+// How many of the core's samples are played in the time of one, so fast-forwarded audio
+// plays as fast, and higher, rather than piling up.
+fn playback_speed() -> f64 {
+    speed().unwrap_or(1.0)
+}
+
+// Whether `queued` samples are worth playing: a whole chunk, or any whole frame once the
+// sink is close to running dry.
+pub fn ready_to_play(queued: usize, rate_control: &RateControl) -> bool {
+    queued >= play_chunk_len() || (queued >= AUDIO_CHANNELS && rate_control.fill() < LOW_FILL)
+}
+
+// Plays the queued audio using the `rodio` library, resampled to the output device's rate.
+// `volume` is only applied here with float output, otherwise the sink's volume is used.
+pub fn play_audio(
+    sink: &Sink,
+    audio_samples: &mut VecDeque<i16>,
+    resampler: &mut Resampler,
+    rate_control: &mut RateControl,
+    output_rate: u32,
+    volume: f32,
+) {
+    let samples = drain_whole_frames(audio_samples);
+    resampler.set_rate_adjustment(rate_control.adjustment() * playback_speed());
+    let channels = AUDIO_CHANNELS.try_into().unwrap();
+    if float_output() {
+        let resampled = resampler.process_float(&samples, volume);
+        rate_control.queued(resampled.len() / AUDIO_CHANNELS, output_rate);
+        sink.append(SamplesBuffer::new(channels, output_rate, resampled));
+    } else {
+        let resampled = resampler.process(&samples);
+        rate_control.queued(resampled.len() / AUDIO_CHANNELS, output_rate);
+        sink.append(SamplesBuffer::new(channels, output_rate, resampled));
+    }
+    return_pooled_buffer(samples);
+}
+
+// Moves the whole frames queued in `audio_samples` into a pooled buffer, across both halves
+// of the ring buffer, so they are never replayed. A trailing partial frame stays queued
+// until its other half arrives.
+fn drain_whole_frames(audio_samples: &mut VecDeque<i16>) -> Vec<i16> {
+    let whole_frames_len = audio_samples.len() - audio_samples.len() % AUDIO_CHANNELS;
+    let mut samples = take_pooled_buffer();
+    samples.extend(audio_samples.drain(..whole_frames_len));
+    samples
 }
 
 // Callback function for the libretro API to handle individual audio samples.
 pub unsafe extern "C" fn libretro_set_audio_sample_callback(left: i16, right: i16) {
-    println!("libretro_set_audio_sample_callback");
+    if dropping_audio() || !OUTPUT_ENABLED.load(Ordering::SeqCst) {
+        return;
+    }
+    AUDIO_BUFFER.lock().unwrap().extend([left, right]);
+    AUDIO_CONDVAR.notify_one();
+}
+
+// Queues samples from the core as interleaved stereo, duplicating mono samples into both
+// channels. Only mono and stereo are understood, anything else is treated as stereo.
+pub fn queue_as_stereo(samples: &[i16], core_channels: usize, queue: &mut VecDeque<i16>) {
+    if core_channels == 1 {
+        queue.extend(samples.iter().flat_map(|&sample| [sample, sample]));
+    } else {
+        queue.extend(samples);
+    }
+}
+
+// Reads how many channels the core's sample batches carry from `audio_core_channels`.
+// Libretro defines them as stereo, but a few cores send mono.
+pub fn set_core_channels(config: &HashMap<String, String>) {
+    let channels = match config["audio_core_channels"].trim() {
+        "1" => 1,
+        "2" => AUDIO_CHANNELS,
+        other => {
+            log_warn!("Unsupported audio_core_channels '{}', using stereo", other);
+            AUDIO_CHANNELS
+        }
+    };
+    CORE_AUDIO_CHANNELS.store(channels, Ordering::SeqCst);
+}
+
+// Reads the buffer duration from `audio_latency` and how many spare sample buffers to keep
+// from `audio_buffer_pool_size`. Shorter buffers lower the latency, longer ones ride out
+// stutters on slow systems. Values outside the supported range are clamped.
+pub fn set_buffer_config(config: &HashMap<String, String>) {
+    let duration_ms = match config["audio_latency"].trim().parse::<u32>() {
+        Ok(duration_ms) => {
+            duration_ms.clamp(BUFFER_DURATION_RANGE_MS.0, BUFFER_DURATION_RANGE_MS.1)
+        }
+        Err(_) => {
+            log_warn!(
+                "Invalid audio_latency '{}', using {}ms",
+                config["audio_latency"],
+                DEFAULT_BUFFER_DURATION_MS
+            );
+            DEFAULT_BUFFER_DURATION_MS
+        }
+    };
+    let pool_size = match config["audio_buffer_pool_size"].trim().parse::<usize>() {
+        Ok(pool_size) => pool_size.clamp(POOL_SIZE_RANGE.0, POOL_SIZE_RANGE.1),
+        Err(_) => {
+            log_warn!(
+                "Invalid audio_buffer_pool_size '{}', using {}",
+                config["audio_buffer_pool_size"],
+                DEFAULT_POOL_SIZE
+            );
+            DEFAULT_POOL_SIZE
+        }
+    };
+    BUFFER_DURATION_MS.store(duration_ms, Ordering::SeqCst);
+    POOL_SIZE.store(pool_size, Ordering::SeqCst);
+}
+
+// Reads `audio_float_output`. With it on, samples are resampled and scaled by the volume
+// as floats and handed to the device that way, instead of being rounded back to 16 bits
+// after each step.
+pub fn set_output_format(config: &HashMap<String, String>) {
+    FLOAT_OUTPUT.store(config["audio_float_output"] == "true", Ordering::SeqCst);
+}
+
+pub fn float_output() -> bool {
+    FLOAT_OUTPUT.load(Ordering::SeqCst)
+}
+
+// Reads `audio_sync_source`, "video" or "audio".
+pub fn set_sync_source(config: &HashMap<String, String>) {
+    let sync_source = SyncSource::from_config(&config["audio_sync_source"]).unwrap_or_else(|| {
+        log_warn!(
+            "Invalid audio_sync_source '{}', syncing to video",
+            config["audio_sync_source"]
+        );
+        SyncSource::Video
+    });
+    *SYNC_SOURCE.lock().unwrap() = sync_source;
+}
+
+pub fn sync_source() -> SyncSource {
+    *SYNC_SOURCE.lock().unwrap()
+}
+
+// How late the sound is compared to the picture, in seconds, beyond the latency the
+// audio queue is meant to have. Negative when it's early.
+pub fn sync_offset() -> f64 {
+    SYNC_OFFSET_US.load(Ordering::SeqCst) as f64 / 1_000_000.0
+}
+
+// Factor to stretch the next frame's duration by when syncing to audio: frames run slower
+// while sound lags behind and faster while it runs ahead, by at most `max_adjustment`.
+pub fn frame_duration_adjustment(max_adjustment: f64) -> f64 {
+    if sync_source() != SyncSource::Audio {
+        return 1.0;
+    }
+    let half_target = BUFFER_DURATION_MS.load(Ordering::SeqCst) as f64 / 1000.0;
+    1.0 + max_adjustment.abs() * (sync_offset() / half_target).clamp(-1.0, 1.0)
 }
 
 // Callback function for the libretro API to handle batches of audio samples.
@@ -83,28 +504,188 @@ pub unsafe extern "C" fn libretro_set_audio_sample_batch_callback(
     audio_data: *const i16,
     frames: libc::size_t,
 ) -> libc::size_t {
-    let sample_rate = TARGET_FPS.load(Ordering::SeqCst) as u32;
-    let frame_duration = Duration::from_secs_f64(1.0 / (sample_rate as f64));
-    thread::sleep(frame_duration);
+    if dropping_audio() {
+        return frames;
+    }
 
-    let buffer_arc: Arc<Mutex<Vec<i16>>>;
-    let mut pool = BUFFER_POOL.lock().unwrap();
-    buffer_arc = pool
-        .pop()
-        .unwrap_or_else(|| Arc::new(Mutex::new(vec![0; BUFFER_LENGTH])));
-
-    {
-        let mut buffer = AudioBuffer::new(BUFFER_LENGTH);
-        let audio_slice = std::slice::from_raw_parts(audio_data, frames * AUDIO_CHANNELS);
-        buffer.clear();
-        buffer.extend_from_slice(audio_slice);
-        let buffer_arc = Arc::new(Mutex::new(buffer));
-        if let Err(e) = AUDIO_DATA_CHANNEL.0.send(buffer_arc.clone()) {
-            eprintln!("Failed to send audio data: {:?}", e);
-        }
+    if !OUTPUT_ENABLED.load(Ordering::SeqCst) {
+        return frames;
     }
 
-    // Reuse and return buffers to the pool after processing.
-    pool.push(buffer_arc);
+    // Queued straight into the buffer the audio thread plays from
+    let core_channels = CORE_AUDIO_CHANNELS.load(Ordering::SeqCst);
+    let audio_slice = std::slice::from_raw_parts(audio_data, frames * core_channels);
+    queue_as_stereo(
+        audio_slice,
+        core_channels,
+        &mut AUDIO_BUFFER.lock().unwrap(),
+    );
+    AUDIO_CONDVAR.notify_one();
     frames
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drains_each_sample_once() {
+        let _globals = crate::lock_test_globals();
+        let mut queue = VecDeque::new();
+        let mut played = Vec::new();
+
+        // The first batch ends halfway through a frame
+        queue.extend([1, 2, 3, 4, 5]);
+        let samples = drain_whole_frames(&mut queue);
+        played.extend_from_slice(&samples);
+        return_pooled_buffer(samples);
+        assert_eq!(queue, [5]);
+
+        queue.extend([6, 7, 8]);
+        let samples = drain_whole_frames(&mut queue);
+        played.extend_from_slice(&samples);
+        return_pooled_buffer(samples);
+
+        assert_eq!(played, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn drains_across_the_ring_buffer_wrap() {
+        let _globals = crate::lock_test_globals();
+        // Popping from the front and pushing to the back wraps the samples around the end
+        let mut queue = VecDeque::with_capacity(4);
+        queue.extend([0, 0, 1, 2]);
+        queue.drain(..2);
+        queue.extend([3, 4]);
+
+        let samples = drain_whole_frames(&mut queue);
+        assert_eq!(samples, [1, 2, 3, 4]);
+        return_pooled_buffer(samples);
+    }
+
+    #[test]
+    fn queues_mono_samples_in_both_channels() {
+        let mut queue = VecDeque::new();
+        queue_as_stereo(&[7, -3], 1, &mut queue);
+        assert_eq!(queue, [7, 7, -3, -3]);
+    }
+
+    #[test]
+    fn queues_stereo_samples_as_they_are() {
+        let mut queue = VecDeque::new();
+        queue_as_stereo(&[7, -3, 2, 9], 2, &mut queue);
+        assert_eq!(queue, [7, -3, 2, 9]);
+    }
+
+    #[test]
+    fn batches_from_the_core_are_played() {
+        let _globals = crate::lock_test_globals();
+        AUDIO_BUFFER.lock().unwrap().clear();
+        let pooled = BUFFER_POOL.lock().unwrap().len();
+
+        let batch = [1i16, -1, 2, -2, 3, -3];
+        let accepted = unsafe { libretro_set_audio_sample_batch_callback(batch.as_ptr(), 3) };
+        assert_eq!(accepted, 3);
+
+        // The audio thread drains the very buffer the callback queued into
+        let mut queue = AUDIO_BUFFER.lock().unwrap();
+        let samples = drain_whole_frames(&mut queue);
+        assert_eq!(samples, batch);
+        assert!(queue.is_empty());
+        assert_eq!(BUFFER_POOL.lock().unwrap().len(), pooled.saturating_sub(1));
+
+        // And the emptied buffer goes back to the pool for the next chunk
+        return_pooled_buffer(samples);
+        let pool = BUFFER_POOL.lock().unwrap();
+        assert_eq!(pool.len(), pooled.max(1));
+        assert!(pool.last().unwrap().is_empty());
+    }
+
+    #[test]
+    fn fast_forward_without_a_speed_drops_audio() {
+        let _globals = crate::lock_test_globals();
+        AUDIO_BUFFER.lock().unwrap().clear();
+        set_fast_forward_speed(0.0);
+        FAST_FORWARDING.store(true, Ordering::SeqCst);
+
+        let batch = [1i16, -1, 2, -2];
+        let accepted = unsafe { libretro_set_audio_sample_batch_callback(batch.as_ptr(), 2) };
+        let wait_timeout = RateControl::new(0.005).wait_timeout();
+        FAST_FORWARDING.store(false, Ordering::SeqCst);
+
+        assert_eq!(accepted, 2);
+        assert!(AUDIO_BUFFER.lock().unwrap().is_empty());
+        assert_eq!(wait_timeout, MAX_AUDIO_WAIT);
+    }
+
+    #[test]
+    fn sustained_fast_forward_neither_piles_up_nor_starves() {
+        let _globals = crate::lock_test_globals();
+        AUDIO_BUFFER.lock().unwrap().clear();
+        let normal_chunk_len = play_chunk_len();
+        set_fast_forward_speed(4.0);
+        FAST_FORWARDING.store(true, Ordering::SeqCst);
+        let fast_chunk_len = play_chunk_len();
+
+        // Two seconds of 10ms frames at four times the core's 48kHz, drained by the audio
+        // thread's steps whenever a chunk is ready
+        let frame_samples = [0i16; 4 * 480 * AUDIO_CHANNELS];
+        let mut resampler = Resampler::new(48_000.0, 48_000);
+        let mut played_frames = 0;
+        let mut most_queued = 0;
+        for _ in 0..200 {
+            unsafe {
+                libretro_set_audio_sample_batch_callback(frame_samples.as_ptr(), 4 * 480);
+            }
+            let mut queue = AUDIO_BUFFER.lock().unwrap();
+            most_queued = most_queued.max(queue.len());
+            if queue.len() >= play_chunk_len() {
+                let samples = drain_whole_frames(&mut queue);
+                resampler.set_rate_adjustment(playback_speed());
+                played_frames += resampler.process(&samples).len() / AUDIO_CHANNELS;
+                return_pooled_buffer(samples);
+            }
+        }
+        FAST_FORWARDING.store(false, Ordering::SeqCst);
+        set_fast_forward_speed(0.0);
+        let left_queued = AUDIO_BUFFER.lock().unwrap().len();
+        AUDIO_BUFFER.lock().unwrap().clear();
+
+        assert_eq!(fast_chunk_len, normal_chunk_len * 4);
+        // The queue never holds much more than a chunk...
+        assert!(most_queued < fast_chunk_len + frame_samples.len());
+        assert!(left_queued < fast_chunk_len);
+        // ...and what's played lasts as long as the two seconds it took, give or take a chunk
+        let played_seconds = played_frames as f64 / 48_000.0;
+        let chunk_seconds = normal_chunk_len as f64 / AUDIO_CHANNELS as f64 / 48_000.0;
+        assert!(
+            (played_seconds - 2.0).abs() <= chunk_seconds,
+            "{}",
+            played_seconds
+        );
+    }
+
+    #[test]
+    fn audio_sync_moves_frames_both_ways() {
+        let _globals = crate::lock_test_globals();
+        let frame_duration = Duration::from_secs_f64(1.0 / 60.0);
+        let half_target_us = BUFFER_DURATION_MS.load(Ordering::SeqCst) as i64 * 1000;
+        *SYNC_SOURCE.lock().unwrap() = SyncSource::Audio;
+
+        // Sound running ahead of the picture brings the next frame in sooner...
+        SYNC_OFFSET_US.store(-half_target_us / 2, Ordering::SeqCst);
+        let ahead = frame_duration.mul_f64(frame_duration_adjustment(0.01));
+        // ...and sound lagging behind holds it back, both by at most the adjustment
+        SYNC_OFFSET_US.store(half_target_us * 4, Ordering::SeqCst);
+        let behind = frame_duration.mul_f64(frame_duration_adjustment(0.01));
+        // Video sync leaves the frames alone
+        *SYNC_SOURCE.lock().unwrap() = SyncSource::Video;
+        let video_synced = frame_duration.mul_f64(frame_duration_adjustment(0.01));
+        SYNC_OFFSET_US.store(0, Ordering::SeqCst);
+
+        assert_eq!(ahead, frame_duration.mul_f64(0.995));
+        assert_eq!(behind, frame_duration.mul_f64(1.01));
+        assert_eq!(video_synced, frame_duration);
+    }
+}
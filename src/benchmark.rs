@@ -0,0 +1,127 @@
+// This implementation is based on the guide provided by [RetroGameDeveloper/RetroReversing].
+// Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
+// Copyright (c) 2023 Nicholas Ricciuti
+//
+// benchmark.rs
+//
+// This module runs the core for a fixed number of frames without a window or audio output,
+// then reports how long the frames took. The video and audio callbacks still run, with
+// frames converted as usual and samples thrown away, so the timings stay realistic.
+// It first times the 16-bit pixel conversion on a synthetic frame, with and without SIMD.
+
+use crate::emulator::Emulator;
+use crate::{audio, libretro, video};
+use std::time::{Duration, Instant};
+
+// Runs `frames` frames of the loaded game as fast as possible and prints the frame times.
+pub fn run(emulator: &Emulator, frames: u32) {
+    let core_api = emulator.core_api();
+    let pixel_format = video::set_up_pixel_format();
+    let mut frame_converter = video::FrameConverter::spawn(
+        &pixel_format,
+        emulator.state.alpha_mode,
+        video::Crop::from_config(&emulator.config),
+    );
+
+    report_conversion();
+
+    println!("Benchmarking {} frames", frames);
+    let mut frame_times = Vec::with_capacity(frames as usize);
+    let started = Instant::now();
+    for _ in 0..frames {
+        let frame_start = Instant::now();
+        unsafe { (core_api.retro_run)() };
+        frame_times.push(frame_start.elapsed());
+        if libretro::take_shutdown_request() {
+            println!("The core shut down, stopping early");
+            break;
+        }
+        // Nothing plays the audio, so don't let it pile up
+        audio::AUDIO_BUFFER.lock().unwrap().clear();
+    }
+    let total = started.elapsed();
+    frame_converter.shutdown();
+
+    report(&mut frame_times, total);
+}
+
+fn report(frame_times: &mut [Duration], total: Duration) {
+    if frame_times.is_empty() {
+        println!("No frames were run");
+        return;
+    }
+    frame_times.sort();
+    let to_ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let percentile = |percent: usize| {
+        let index = (frame_times.len() * percent / 100).min(frame_times.len() - 1);
+        to_ms(frame_times[index])
+    };
+    let average = to_ms(frame_times.iter().sum::<Duration>()) / frame_times.len() as f64;
+
+    println!(
+        "Ran {} frames in {:.2}s ({:.1} FPS)",
+        frame_times.len(),
+        total.as_secs_f64(),
+        frame_times.len() as f64 / total.as_secs_f64()
+    );
+    println!("Average frame time: {:.3}ms", average);
+    println!(
+        "Median: {:.3}ms, 95th percentile: {:.3}ms, 99th percentile: {:.3}ms",
+        percentile(50),
+        percentile(95),
+        percentile(99)
+    );
+    println!(
+        "Fastest: {:.3}ms, slowest: {:.3}ms",
+        to_ms(frame_times[0]),
+        to_ms(frame_times[frame_times.len() - 1])
+    );
+}
+
+// Size of the synthetic frame the pixel conversion is timed on, a common 16-bit resolution.
+const CONVERSION_WIDTH: usize = 320;
+const CONVERSION_HEIGHT: usize = 240;
+const CONVERSION_FRAMES: u32 = 500;
+
+// Times converting 16-bit frames through the lookup tables alone, then through the SIMD
+// paths the frame converter uses, and prints the speedup.
+fn report_conversion() {
+    // A spread of pixel values, so the table lookups don't all hit the same cache line
+    let source: Vec<u8> = (0..CONVERSION_WIDTH * CONVERSION_HEIGHT)
+        .flat_map(|i| (i as u16).wrapping_mul(40503).to_ne_bytes())
+        .collect();
+    let time = |convert_row: &dyn Fn(&[u8], &mut [u8])| {
+        let mut frame = vec![0; CONVERSION_WIDTH * CONVERSION_HEIGHT * 4];
+        let started = Instant::now();
+        for _ in 0..CONVERSION_FRAMES {
+            for (source_row, dest_row) in source
+                .chunks_exact(CONVERSION_WIDTH * 2)
+                .zip(frame.chunks_exact_mut(CONVERSION_WIDTH * 4))
+            {
+                convert_row(source_row, dest_row);
+            }
+        }
+        started.elapsed().as_secs_f64() * 1000.0 / CONVERSION_FRAMES as f64
+    };
+
+    let rgb565_table = time(&|source, dest| video::rgb565_row_from_table(source, dest, 0));
+    let rgb565 = time(&|source, dest| video::convert_rgb565_row(source, dest));
+    let argb1555_table =
+        time(&|source, dest| video::argb1555_row_from_table(source, dest, 0, false, 0xFF000000));
+    let argb1555 = time(&|source, dest| video::convert_argb1555_row(source, dest, 0xFF000000));
+
+    for (name, table, fast) in [
+        ("RGB565", rgb565_table, rgb565),
+        ("ARGB1555", argb1555_table, argb1555),
+    ] {
+        println!(
+            "Converting a {}x{} {} frame: {:.3}ms with the lookup table, {:.3}ms with SIMD ({:.1}x)",
+            CONVERSION_WIDTH,
+            CONVERSION_HEIGHT,
+            name,
+            table,
+            fast,
+            table / fast
+        );
+    }
+}
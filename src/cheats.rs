@@ -0,0 +1,125 @@
+// This implementation is based on the guide provided by [RetroGameDeveloper/RetroReversing].
+// Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
+// Copyright (c) 2023 Nicholas Ricciuti
+//
+// cheats.rs
+//
+// This module reads cheat codes from a RetroArch style `.cht` file next to the ROM and
+// hands them to the core with retro_cheat_set. Cores without cheat support implement the
+// cheat functions as no-ops, so nothing here needs to check for it.
+//
+// File layout:
+//   cheats = 2
+//   cheat0_desc = "Infinite lives"
+//   cheat0_code = "010A-C4F7"
+//   cheat0_enable = true
+
+use crate::libretro;
+use libretro_sys::CoreAPI;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+pub struct Cheat {
+    pub description: String,
+    pub code: String,
+    pub enabled: bool,
+}
+
+pub struct CheatList {
+    path: PathBuf,
+    pub cheats: Vec<Cheat>,
+    pub selected: usize, // Cheat the toggle hotkey acts on
+}
+
+impl CheatList {
+    // Reads the `.cht` file next to the ROM. Returns None if there isn't one.
+    pub fn load(rom_path: &str) -> Option<Self> {
+        let path = Path::new(rom_path).with_extension("cht");
+        if !path.exists() {
+            return None;
+        }
+        let mut cheat_list = CheatList {
+            path,
+            cheats: Vec::new(),
+            selected: 0,
+        };
+        cheat_list.reload();
+        Some(cheat_list)
+    }
+
+    // Reads the file again, keeping the selection where it was if it still exists.
+    pub fn reload(&mut self) {
+        let values = match libretro::parse_retroarch_config(&self.path) {
+            Ok((values, _)) => values,
+            Err(err) => {
+                log_error!(
+                    "Failed to read cheats from {}: {}",
+                    self.path.display(),
+                    err
+                );
+                return;
+            }
+        };
+        let count = values
+            .get("cheats")
+            .and_then(|count| count.parse::<usize>().ok())
+            .unwrap_or(0);
+        self.cheats = (0..count)
+            .filter_map(|index| {
+                let value = |name: &str| values.get(&format!("cheat{}_{}", index, name));
+                Some(Cheat {
+                    description: value("desc").cloned().unwrap_or_default(),
+                    code: value("code")?.clone(),
+                    enabled: value("enable").map(String::as_str) == Some("true"),
+                })
+            })
+            .collect();
+        self.selected = self.selected.min(self.cheats.len().saturating_sub(1));
+        log_info!(
+            "Loaded {} cheats from {}",
+            self.cheats.len(),
+            self.path.display()
+        );
+    }
+
+    // Replaces the core's cheats with the current list.
+    pub unsafe fn apply(&self, core_api: &CoreAPI) {
+        (core_api.retro_cheat_reset)();
+        for (index, cheat) in self.cheats.iter().enumerate() {
+            // A code can't hold a NUL, so a malformed one is skipped rather than cut short
+            let Ok(code) = CString::new(cheat.code.as_str()) else {
+                continue;
+            };
+            (core_api.retro_cheat_set)(index as u32, cheat.enabled, code.as_ptr());
+        }
+    }
+
+    // Moves the selection by `step`, wrapping around the list.
+    pub fn select(&mut self, step: i32) {
+        if self.cheats.is_empty() {
+            return;
+        }
+        let count = self.cheats.len() as i32;
+        self.selected = (self.selected as i32 + step).rem_euclid(count) as usize;
+        self.print_selected();
+    }
+
+    pub fn toggle_selected(&mut self) {
+        let Some(cheat) = self.cheats.get_mut(self.selected) else {
+            return;
+        };
+        cheat.enabled = !cheat.enabled;
+        self.print_selected();
+    }
+
+    fn print_selected(&self) {
+        let cheat = &self.cheats[self.selected];
+        log_info!(
+            "Cheat {}/{}: {} ({})",
+            self.selected + 1,
+            self.cheats.len(),
+            cheat.description,
+            if cheat.enabled { "on" } else { "off" }
+        );
+    }
+}
@@ -0,0 +1,592 @@
+// This implementation is based on the guide provided by [RetroGameDeveloper/RetroReversing].
+// Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
+// Copyright (c) 2023 Nicholas Ricciuti
+//
+// emulator.rs
+//
+// This module wraps a loaded core and game behind a small API: run a frame, save or load
+// a state, press buttons. The caller decides when frames run and what to do with the
+// output, which arrives the same way it does for our frontend: frames through a
+// `video::FrameConverter` and samples in `audio::AUDIO_BUFFER`.
+
+use crate::cheats::CheatList;
+use crate::input::{JOYPAD_BUTTONS, MAX_PLAYERS};
+use crate::libretro::{self, Core, EmulatorState, RewindBuffer};
+use crate::movie::{MoviePlayer, MovieRecorder};
+use crate::netplay::Netplay;
+use crate::{audio, input, logger, video};
+use crate::{ANALOG_STATE, BUTTONS_PRESSED, MOVIE_INPUT, REWINDING, TURBO_RELEASED};
+use libretro_sys::{CoreAPI, SystemAvInfo};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+// Most frames in a row the automatic frame skip drops, so the picture still moves.
+const MAX_AUTO_SKIPPED_FRAMES: u32 = 3;
+
+// Which frames have their video skipped, when the host can't keep up.
+enum FrameSkip {
+    Fixed(u32), // Skip this many frames after each one shown
+    Auto,       // Skip frames while running behind the core's frame rate
+}
+
+// How long a frame may take before the automatic frame skip counts it as falling behind.
+// A little slack so ordinary jitter doesn't.
+fn frame_budget(fps: f64) -> Duration {
+    Duration::from_secs_f64(1.1 / fps.max(1.0))
+}
+
+struct FrameSkipper {
+    mode: FrameSkip,
+    frame_budget: Duration,
+    skipped_in_row: u32,
+    last_frame: Option<Instant>,
+}
+
+impl FrameSkipper {
+    // Reads `video_frameskip`, a number of frames or `auto`.
+    fn new(config: &HashMap<String, String>, fps: f64) -> Self {
+        let mode = match config["video_frameskip"].trim() {
+            "auto" => FrameSkip::Auto,
+            count => FrameSkip::Fixed(count.parse().unwrap_or(0)),
+        };
+        FrameSkipper {
+            mode,
+            frame_budget: frame_budget(fps),
+            skipped_in_row: 0,
+            last_frame: None,
+        }
+    }
+
+    // Decides whether the frame about to run is skipped.
+    fn skip_next(&mut self) -> bool {
+        let now = Instant::now();
+        let behind = self
+            .last_frame
+            .is_some_and(|last_frame| now - last_frame > self.frame_budget);
+        self.last_frame = Some(now);
+        let skip = match self.mode {
+            FrameSkip::Fixed(count) => self.skipped_in_row < count,
+            FrameSkip::Auto => behind && self.skipped_in_row < MAX_AUTO_SKIPPED_FRAMES,
+        };
+        self.skipped_in_row = if skip { self.skipped_in_row + 1 } else { 0 };
+        skip
+    }
+}
+
+// Settings the callbacks read, which have to be in place before the core starts calling them.
+fn apply_config(config: &HashMap<String, String>) {
+    logger::set_min_level(config);
+    audio::set_core_channels(config);
+    audio::set_buffer_config(config);
+    audio::set_output_format(config);
+    audio::set_sync_source(config);
+    video::set_1555_channel_order(config);
+    input::set_poll_behavior(config);
+    libretro::set_core_option_overrides(config);
+    libretro::set_core_directories(config);
+}
+
+pub struct Emulator {
+    core: Core,
+    pub state: EmulatorState,
+    pub config: HashMap<String, String>,
+    game_loaded: bool,
+    started_without_game: bool,
+    shut_down: bool,
+    // AV info of the core `switch_core` started, handed out by `take_av_info_update`
+    switched_av_info: Option<SystemAvInfo>,
+
+    // Turbo buttons alternate between pressed and released every half period,
+    // counted in emulated frames so the rate doesn't depend on key repeat
+    turbo_buttons: Vec<(usize, usize)>,
+    turbo_half_period: u64,
+    frame_count: u64,
+
+    // Snapshots are only taken when rewind is enabled
+    rewind_buffer: Option<RewindBuffer>,
+    rewinding: bool,
+
+    movie_recorder: Option<MovieRecorder>,
+    movie_player: Option<MoviePlayer>,
+
+    // Set when the peer is lost, until the caller takes it up with `take_netplay_lost`
+    netplay: Option<Netplay>,
+    netplay_lost: bool,
+
+    // Codes from the `.cht` file next to the ROM, if there is one
+    cheats: Option<CheatList>,
+
+    frame_skipper: FrameSkipper,
+    // Frames still to hide after the game started, from `video_startup_blank_frames`
+    startup_blank_frames: u32,
+}
+
+impl Emulator {
+    // Loads the core and the game named in `state`, set up from `config` (as returned by
+    // `libretro::setup_config`). Starts recording or playing a movie, or a netplay session,
+    // if `state` asks to.
+    pub fn new(mut state: EmulatorState, config: HashMap<String, String>) -> Result<Self, String> {
+        libretro::validate_config(&config)?;
+        apply_config(&config);
+        state.library_name = libretro::resolve_core_path(&state.library_name, &config)
+            .map_err(|err| err.to_string())?;
+        let (core, state) = Core::new(state).map_err(|err| err.to_string())?;
+        Self::start(core, state, config)
+    }
+
+    // Like `new`, but runs a core that's already in this process, such as one linked in
+    // statically or a stand-in for tests, instead of loading `state.library_name`.
+    pub unsafe fn with_core_api(
+        state: EmulatorState,
+        config: HashMap<String, String>,
+        core_api: CoreAPI,
+    ) -> Result<Self, String> {
+        libretro::validate_config(&config)?;
+        apply_config(&config);
+        let (core, state) = Core::from_api(core_api, state).map_err(|err| err.to_string())?;
+        Self::start(core, state, config)
+    }
+
+    // Hooks up an initialized core and loads the game.
+    fn start(
+        core: Core,
+        state: EmulatorState,
+        config: HashMap<String, String>,
+    ) -> Result<Self, String> {
+        let turbo_buttons = input::turbo_buttons(&config);
+        let turbo_half_period =
+            (config["input_turbo_period"].parse::<u64>().unwrap_or(6) / 2).max(1);
+        let fps = state
+            .av_info
+            .as_ref()
+            .map_or(60.0, |av_info| av_info.timing.fps);
+        let frame_skipper = FrameSkipper::new(&config, fps);
+        let startup_blank_frames = config["video_startup_blank_frames"]
+            .parse::<u32>()
+            .unwrap_or(0);
+        let mut emulator = Emulator {
+            core,
+            state,
+            config,
+            game_loaded: false,
+            started_without_game: false,
+            shut_down: false,
+            switched_av_info: None,
+            turbo_buttons,
+            turbo_half_period,
+            frame_count: 0,
+            rewind_buffer: None,
+            rewinding: false,
+            movie_recorder: None,
+            movie_player: None,
+            netplay: None,
+            netplay_lost: false,
+            cheats: None,
+            frame_skipper,
+            startup_blank_frames,
+        };
+
+        // Set up libretro callbacks for video, input, and audio
+        // (the environment callback is registered before retro_init in Core::from_api)
+        unsafe {
+            let core_api = &emulator.core.api;
+            (core_api.retro_set_video_refresh)(video::libretro_set_video_refresh_callback);
+            (core_api.retro_set_input_poll)(input::libretro_set_input_poll_callback);
+            (core_api.retro_set_input_state)(input::libretro_set_input_state_callback);
+            (core_api.retro_set_audio_sample)(audio::libretro_set_audio_sample_callback);
+            (core_api.retro_set_audio_sample_batch)(
+                audio::libretro_set_audio_sample_batch_callback,
+            );
+            // Dropping the emulator on failure still deinitializes the core
+            if let [ident, rom_names @ ..] = emulator.state.subsystem.as_slice() {
+                if !libretro::load_subsystem(core_api, ident, rom_names) {
+                    return Err(format!("Failed to load subsystem {}", ident));
+                }
+                // Saves are named after the first file, as the libretro docs suggest
+                emulator.state.rom_name = rom_names[0].clone();
+            } else if emulator.state.rom_name.is_empty() {
+                if !libretro::load_no_game(core_api) {
+                    return Err("Failed to start the core without a ROM".to_string());
+                }
+                // Saves and screenshots are named after the core instead
+                emulator.state.rom_name = libretro::core_name(&emulator.state.library_name);
+                emulator.started_without_game = true;
+            } else {
+                log_info!("About to load ROM: {}", &emulator.state.rom_name);
+                if !libretro::load_rom_file(core_api, &emulator.state.rom_name) {
+                    return Err(format!("Failed to load ROM {}", emulator.state.rom_name));
+                }
+            }
+            emulator.game_loaded = true;
+            // What the core said before the game was loaded may have been zeros
+            let av_info = libretro::query_av_info(core_api);
+            emulator.frame_skipper.frame_budget = frame_budget(av_info.timing.fps);
+            emulator.state.av_info = Some(av_info);
+            emulator.state.content_crc32 = libretro::content_crc32();
+            // Restore battery-backed save RAM if there is any
+            libretro::load_sram(core_api, &emulator.state.rom_name);
+        }
+        emulator.cheats = CheatList::load(&emulator.state.rom_name);
+        emulator.apply_cheats();
+
+        if emulator.config["rewind_enable"] == "true" {
+            let memory_budget = emulator.config["rewind_buffer_size_mb"]
+                .parse::<usize>()
+                .unwrap_or(20)
+                * 1024
+                * 1024;
+            let granularity = emulator.config["rewind_granularity"]
+                .parse::<u32>()
+                .unwrap_or(1);
+            emulator.rewind_buffer =
+                unsafe { RewindBuffer::new(&emulator.core.api, memory_budget, granularity) };
+        }
+
+        // Recording and playback both begin from a save state of the freshly loaded game
+        unsafe {
+            let core_api = &emulator.core.api;
+            let rom_name = &emulator.state.rom_name;
+            emulator.movie_recorder = emulator.state.record_movie.as_deref().and_then(|path| {
+                MovieRecorder::start(path, core_api, rom_name)
+                    .map_err(|err| log_error!("{}", err))
+                    .ok()
+            });
+            emulator.movie_player = emulator.state.play_movie.as_deref().and_then(|path| {
+                MoviePlayer::start(path, core_api, rom_name)
+                    .map_err(|err| log_error!("{}", err))
+                    .ok()
+            });
+        }
+
+        // Both players have to be there from the first frame, so failing to connect is fatal
+        if let Some(address) = emulator.state.netplay.clone() {
+            let core_api = &emulator.core.api;
+            let crc32 = emulator.state.content_crc32;
+            emulator.netplay = Some(unsafe {
+                if emulator.state.netplay_host {
+                    Netplay::host(&address, core_api, crc32)?
+                } else {
+                    Netplay::join(&address, core_api, crc32)?
+                }
+            });
+        }
+        Ok(emulator)
+    }
+
+    pub fn core_api(&self) -> &CoreAPI {
+        &self.core.api
+    }
+
+    pub fn av_info(&self) -> Option<&SystemAvInfo> {
+        self.state.av_info.as_ref()
+    }
+
+    // Runs one frame of the core, or steps back one snapshot while rewinding. Returns
+    // false if nothing ran because the rewind buffer is used up.
+    pub fn run_frame(&mut self) -> bool {
+        if !self.turbo_buttons.is_empty() {
+            let turbo_off = (self.frame_count / self.turbo_half_period) % 2 == 1;
+            let mut turbo_released = TURBO_RELEASED.lock().unwrap();
+            for &(port, device_id) in &self.turbo_buttons {
+                turbo_released[port][device_id] = turbo_off;
+            }
+        }
+        self.frame_count += 1;
+        unsafe {
+            let core_api = &self.core.api;
+            let run_frame = match self.rewind_buffer.as_mut() {
+                // Step back one snapshot per frame while the rewind key is held
+                Some(rewind_buffer) if self.rewinding => rewind_buffer.rewind(core_api),
+                Some(rewind_buffer) => {
+                    rewind_buffer.capture(core_api);
+                    true
+                }
+                None => true,
+            };
+            if !run_frame {
+                return false;
+            }
+            // Both sides step with the same input, our player 1 controls and the peer's
+            if let Some(netplay) = self.netplay.as_mut() {
+                match netplay.exchange(&input::effective_buttons()[0]) {
+                    Ok(buttons) => *MOVIE_INPUT.lock().unwrap() = Some(buttons),
+                    Err(err) => {
+                        log_warn!("Lost the netplay peer ({}), pausing", err);
+                        self.netplay = None;
+                        self.netplay_lost = true;
+                        *MOVIE_INPUT.lock().unwrap() = None;
+                        return false;
+                    }
+                }
+            }
+            if let Some(player) = self.movie_player.as_mut() {
+                let buttons = player.next_frame();
+                if buttons.is_none() {
+                    log_info!("Movie finished, back to live input");
+                    self.movie_player = None;
+                }
+                *MOVIE_INPUT.lock().unwrap() = buttons;
+            }
+            if let Some(recorder) = self.movie_recorder.as_mut() {
+                let buttons = MOVIE_INPUT
+                    .lock()
+                    .unwrap()
+                    .unwrap_or_else(input::effective_buttons);
+                if let Err(err) = recorder.record_frame(&buttons) {
+                    log_error!("Failed to record movie, stopping: {}", err);
+                    self.movie_recorder = None;
+                }
+            }
+            input::poll_before_frame();
+            video::set_skip_video(self.frame_skipper.skip_next());
+            // The window stays black until the core's framebuffer has settled
+            video::set_blank_video(self.startup_blank_frames > 0);
+            self.startup_blank_frames = self.startup_blank_frames.saturating_sub(1);
+            (core_api.retro_run)();
+        }
+        true
+    }
+
+    // Returns true once after the netplay peer disconnected, so the caller can pause.
+    pub fn take_netplay_lost(&mut self) -> bool {
+        std::mem::take(&mut self.netplay_lost)
+    }
+
+    // Returns true once after the core asked to shut down. It mustn't be run again after
+    // that, only shut down.
+    pub fn take_shutdown_request(&mut self) -> bool {
+        libretro::take_shutdown_request()
+    }
+
+    // Takes up AV info the core changed during the last frame, or that of a core switched
+    // to, returning it so the caller can follow the new timing and geometry.
+    pub fn take_av_info_update(&mut self) -> Option<SystemAvInfo> {
+        let av_info = self
+            .switched_av_info
+            .take()
+            .or_else(libretro::take_av_info_update)?;
+        self.frame_skipper.frame_budget = frame_budget(av_info.timing.fps);
+        self.state.av_info = Some(av_info.clone());
+        Some(av_info)
+    }
+
+    // The game's hash, when `savestate_content_hash` asks for it in save state names.
+    fn state_name_hash(&self) -> Option<u32> {
+        if self.config["savestate_content_hash"] == "true" {
+            self.state.content_crc32
+        } else {
+            None
+        }
+    }
+
+    // Soft-resets the game, as if pressing the console's reset button. Movies only hold
+    // button presses, so a reset would make one desync and isn't allowed while one runs.
+    // The same goes for netplay, where the peer's core wouldn't reset with ours.
+    pub fn reset(&mut self) {
+        if self.movie_recorder.is_some() || self.movie_player.is_some() {
+            log_warn!("Can't reset while a movie is recording or playing");
+            return;
+        }
+        if self.netplay.is_some() {
+            log_warn!("Can't reset during netplay");
+            return;
+        }
+        unsafe { (self.core.api.retro_reset)() };
+        log_info!("Reset");
+    }
+
+    // Saves to the current slot in `savestate_directory`.
+    pub fn save_state(&self) {
+        unsafe {
+            libretro::save_state(
+                &self.core.api,
+                &self.config["savestate_directory"],
+                &self.state.rom_name,
+                self.state_name_hash(),
+                self.state.current_save_slot,
+            )
+        }
+    }
+
+    // Loading a state mid-movie would desync it the same way a reset does.
+    pub fn load_state(&self) {
+        if self.movie_recorder.is_some() || self.movie_player.is_some() {
+            log_warn!("Can't load states while a movie is recording or playing");
+            return;
+        }
+        if self.netplay.is_some() {
+            log_warn!("Can't load states during netplay");
+            return;
+        }
+        unsafe {
+            libretro::load_state(
+                &self.core.api,
+                &self.config["savestate_directory"],
+                &self.state.rom_name,
+                self.state_name_hash(),
+                self.state.current_save_slot,
+            )
+        }
+    }
+
+    // Moves the selection the cheat toggle acts on.
+    pub fn select_cheat(&mut self, step: i32) {
+        match self.cheats.as_mut() {
+            Some(cheats) => cheats.select(step),
+            None => self.print_missing_cheats(),
+        }
+    }
+
+    pub fn toggle_cheat(&mut self) {
+        if let Some(cheats) = self.cheats.as_mut() {
+            cheats.toggle_selected();
+            self.apply_cheats();
+        } else {
+            self.print_missing_cheats();
+        }
+    }
+
+    // Reads the cheat file again, e.g. after editing it while playing.
+    pub fn reload_cheats(&mut self) {
+        match self.cheats.as_mut() {
+            Some(cheats) => cheats.reload(),
+            None => self.cheats = CheatList::load(&self.state.rom_name),
+        }
+        match self.cheats {
+            Some(_) => self.apply_cheats(),
+            None => self.print_missing_cheats(),
+        }
+    }
+
+    fn apply_cheats(&self) {
+        if let Some(cheats) = &self.cheats {
+            unsafe { cheats.apply(&self.core.api) };
+        }
+    }
+
+    fn print_missing_cheats(&self) {
+        log_info!(
+            "No cheats, put them in {}",
+            std::path::Path::new(&self.state.rom_name)
+                .with_extension("cht")
+                .display()
+        );
+    }
+
+    // Starts or stops rewinding. Returns false if rewind is disabled in the config.
+    pub fn set_rewinding(&mut self, rewinding: bool) -> bool {
+        if self.rewind_buffer.is_none() {
+            return false;
+        }
+        // Movies only hold button presses going forward
+        if self.movie_recorder.is_some() || self.movie_player.is_some() {
+            if rewinding {
+                log_warn!("Can't rewind while a movie is recording or playing");
+            }
+            return true;
+        }
+        // The peer can't step back with us
+        if self.netplay.is_some() {
+            if rewinding {
+                log_info!("Rewind is off during netplay");
+            }
+            return true;
+        }
+        self.rewinding = rewinding;
+        REWINDING.store(rewinding, Ordering::SeqCst);
+        true
+    }
+
+    // Presses or releases a libretro joypad button (DEVICE_ID_JOYPAD_*) on a port.
+    pub fn set_button(&self, port: usize, button: usize, pressed: bool) {
+        if port < MAX_PLAYERS && button < JOYPAD_BUTTONS {
+            BUTTONS_PRESSED.lock().unwrap()[port][button] = pressed as i16;
+        }
+    }
+
+    // Moves an analog stick axis, indexed like libretro's DEVICE_INDEX_ANALOG_* and
+    // DEVICE_ID_ANALOG_*.
+    pub fn set_analog(&self, port: usize, stick: usize, axis: usize, value: i16) {
+        if port < MAX_PLAYERS && stick < 2 && axis < 2 {
+            ANALOG_STATE.lock().unwrap()[port][stick][axis] = value;
+        }
+    }
+
+    pub fn release_all_buttons(&self) {
+        *BUTTONS_PRESSED.lock().unwrap() = [[0; JOYPAD_BUTTONS]; MAX_PLAYERS];
+    }
+
+    // Shuts this core down, saving SRAM, and starts `library_name` (a path or a short name,
+    // as with `-L`) on the same game. If it fails to start the previous core is started
+    // again and the error returned; should that fail too, the emulator stays shut down.
+    // Movies and netplay sessions are tied to the core, so switching is refused during them.
+    pub fn switch_core(&mut self, library_name: &str) -> Result<(), String> {
+        if self.netplay.is_some() {
+            return Err("Cores can't be switched during netplay".to_string());
+        }
+        if self.movie_recorder.is_some() || self.movie_player.is_some() {
+            return Err("Cores can't be switched while a movie records or plays".to_string());
+        }
+        self.shutdown(true);
+        // Samples still queued are at the old core's rate
+        audio::AUDIO_BUFFER.lock().unwrap().clear();
+
+        let restart = |library_name: &str| {
+            let mut state = self.state.clone();
+            state.library_name = library_name.to_string();
+            state.av_info = None;
+            state.content_crc32 = None;
+            // The format isn't cleared, the caller compares the new one against it
+            state.pixel_format_ready = false;
+            if self.started_without_game {
+                state.rom_name.clear();
+            }
+            Emulator::new(state, self.config.clone())
+        };
+        let (emulator, result) = match restart(library_name) {
+            Ok(emulator) => (emulator, Ok(())),
+            Err(err) => {
+                let err = format!("Failed to switch to {}: {}", library_name, err);
+                log_error!("{}, going back to {}", err, self.state.library_name);
+                (restart(&self.state.library_name.clone())?, Err(err))
+            }
+        };
+        *self = emulator;
+        self.switched_av_info = self.state.av_info.clone();
+        result
+    }
+
+    // Whether the core has been shut down, after which it can't run any more frames.
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    // Finishes any movie, writes SRAM if `save_sram`, then unloads the game and the core.
+    // Only the first call does anything.
+    pub fn shutdown(&mut self, save_sram: bool) {
+        if self.shut_down {
+            return;
+        }
+        self.shut_down = true;
+        if let Some(recorder) = self.movie_recorder.as_mut() {
+            recorder.finish();
+        }
+        unsafe {
+            let core_api = &self.core.api;
+            if self.game_loaded && !save_sram {
+                (core_api.retro_unload_game)();
+                libretro::shutdown_core(core_api, None);
+            } else {
+                let loaded_rom = self.game_loaded.then_some(self.state.rom_name.as_str());
+                libretro::shutdown_core(core_api, loaded_rom);
+            }
+        }
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        self.shutdown(true);
+    }
+}
@@ -8,167 +8,279 @@
 // keyboard and gamepad inputs. It utilizes the gilrs library for gamepad
 // support and minifb for keyboard inputs.
 
-use gilrs::{Button, GamepadId, Gilrs};
+use gilrs::{Axis, Button, GamepadId, Gilrs};
 use libretro_sys::{
-    DEVICE_ID_JOYPAD_A, DEVICE_ID_JOYPAD_B, DEVICE_ID_JOYPAD_DOWN, DEVICE_ID_JOYPAD_L,
-    DEVICE_ID_JOYPAD_LEFT, DEVICE_ID_JOYPAD_R, DEVICE_ID_JOYPAD_RIGHT, DEVICE_ID_JOYPAD_SELECT,
-    DEVICE_ID_JOYPAD_START, DEVICE_ID_JOYPAD_UP, DEVICE_ID_JOYPAD_X, DEVICE_ID_JOYPAD_Y,
+    Key, DEVICE_ANALOG, DEVICE_ID_ANALOG_X, DEVICE_ID_ANALOG_Y, DEVICE_ID_JOYPAD_A,
+    DEVICE_ID_JOYPAD_B, DEVICE_ID_JOYPAD_DOWN, DEVICE_ID_JOYPAD_L, DEVICE_ID_JOYPAD_LEFT,
+    DEVICE_ID_JOYPAD_R, DEVICE_ID_JOYPAD_RIGHT, DEVICE_ID_JOYPAD_SELECT, DEVICE_ID_JOYPAD_START,
+    DEVICE_ID_JOYPAD_UP, DEVICE_ID_JOYPAD_X, DEVICE_ID_JOYPAD_Y, DEVICE_ID_MOUSE_HORIZ_WHEELDOWN,
+    DEVICE_ID_MOUSE_HORIZ_WHEELUP, DEVICE_ID_MOUSE_LEFT, DEVICE_ID_MOUSE_MIDDLE,
+    DEVICE_ID_MOUSE_RIGHT, DEVICE_ID_MOUSE_WHEELDOWN, DEVICE_ID_MOUSE_WHEELUP, DEVICE_ID_MOUSE_X,
+    DEVICE_ID_MOUSE_Y, DEVICE_INDEX_ANALOG_LEFT, DEVICE_INDEX_ANALOG_RIGHT, DEVICE_JOYPAD,
+    DEVICE_KEYBOARD, DEVICE_MASK, DEVICE_MOUSE,
 };
+use once_cell::sync::Lazy;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 use winit::{
-    monitor::{MonitorHandle, VideoMode},
+    event::{ElementState, KeyboardInput, MouseButton, MouseScrollDelta, VirtualKeyCode},
+    monitor::MonitorHandle,
     window::{Fullscreen, Window},
 };
 
-use crate::BUTTONS_PRESSED;
+use crate::{ANALOG_STATE, BUTTONS_PRESSED, MOVIE_INPUT, TURBO_RELEASED};
 
-/// Maps keyboard key names to libretro device IDs based on the provided configuration.
-pub fn key_device_map(config: &HashMap<String, String>) -> HashMap<String, usize> {
-    HashMap::from([
-        (
-            config["input_player1_a"].clone(),
-            DEVICE_ID_JOYPAD_A as usize,
-        ),
-        (
-            config["input_player1_b"].clone(),
-            DEVICE_ID_JOYPAD_B as usize,
-        ),
-        (
-            config["input_player1_x"].clone(),
-            DEVICE_ID_JOYPAD_X as usize,
-        ),
-        (
-            config["input_player1_y"].clone(),
-            DEVICE_ID_JOYPAD_Y as usize,
-        ),
-        (
-            config["input_player1_l"].clone(),
-            DEVICE_ID_JOYPAD_L as usize,
-        ),
-        (
-            config["input_player1_r"].clone(),
-            DEVICE_ID_JOYPAD_R as usize,
-        ),
-        (
-            config["input_player1_down"].clone(),
-            DEVICE_ID_JOYPAD_DOWN as usize,
-        ),
-        (
-            config["input_player1_up"].clone(),
-            DEVICE_ID_JOYPAD_UP as usize,
-        ),
-        (
-            config["input_player1_right"].clone(),
-            DEVICE_ID_JOYPAD_RIGHT as usize,
-        ),
-        (
-            config["input_player1_left"].clone(),
-            DEVICE_ID_JOYPAD_LEFT as usize,
-        ),
-        (
-            config["input_player1_start"].clone(),
-            DEVICE_ID_JOYPAD_START as usize,
-        ),
-        (
-            config["input_player1_select"].clone(),
-            DEVICE_ID_JOYPAD_SELECT as usize,
-        ),
-    ])
+/// Number of controller ports the frontend exposes to the core.
+pub const MAX_PLAYERS: usize = 4;
+/// Number of libretro joypad button IDs tracked per port.
+pub const JOYPAD_BUTTONS: usize = 16;
+
+/// One past the highest RETROK_* keycode.
+pub const RETRO_KEY_COUNT: usize = Key::Undo as usize + 1;
+
+// Number of libretro mouse IDs we report, up to DEVICE_ID_MOUSE_HORIZ_WHEELDOWN.
+const MOUSE_IDS: usize = DEVICE_ID_MOUSE_HORIZ_WHEELDOWN as usize + 1;
+// Wheel directions read as pressed for one poll after the wheel turns.
+const MOUSE_WHEEL_IDS: [u32; 4] = [
+    DEVICE_ID_MOUSE_WHEELUP,
+    DEVICE_ID_MOUSE_WHEELDOWN,
+    DEVICE_ID_MOUSE_HORIZ_WHEELUP,
+    DEVICE_ID_MOUSE_HORIZ_WHEELDOWN,
+];
+
+/// Analog stick positions of one port, indexed by libretro stick index and then axis.
+pub type AnalogSticks = [[i16; 2]; 2];
+
+// Config key suffixes for each libretro joypad button, as in `input_player1_a`.
+const JOYPAD_BUTTON_NAMES: [(&str, u32); 12] = [
+    ("a", DEVICE_ID_JOYPAD_A),
+    ("b", DEVICE_ID_JOYPAD_B),
+    ("x", DEVICE_ID_JOYPAD_X),
+    ("y", DEVICE_ID_JOYPAD_Y),
+    ("l", DEVICE_ID_JOYPAD_L),
+    ("r", DEVICE_ID_JOYPAD_R),
+    ("down", DEVICE_ID_JOYPAD_DOWN),
+    ("up", DEVICE_ID_JOYPAD_UP),
+    ("right", DEVICE_ID_JOYPAD_RIGHT),
+    ("left", DEVICE_ID_JOYPAD_LEFT),
+    ("start", DEVICE_ID_JOYPAD_START),
+    ("select", DEVICE_ID_JOYPAD_SELECT),
+];
+
+/// Maps keyboard key names to the (port, libretro device ID) they control, reading the
+/// `input_playerN_*` keys for every port. Unbound (`nul`) keys are skipped.
+pub fn key_device_map(config: &HashMap<String, String>) -> HashMap<String, (usize, usize)> {
+    let mut key_device_map = HashMap::new();
+    // Walk the ports backwards so player 1 wins if two players share a key
+    for port in (0..MAX_PLAYERS).rev() {
+        for (button_name, device_id) in JOYPAD_BUTTON_NAMES {
+            let config_key = format!("input_player{}_{}", port + 1, button_name);
+            if let Some(key) = config.get(&config_key) {
+                if !key.is_empty() && key != "nul" {
+                    key_device_map.insert(key.clone(), (port, device_id as usize));
+                }
+            }
+        }
+    }
+    key_device_map
 }
 
-/// Sets up the mapping between gamepad buttons and libretro device IDs.
-pub fn setup_joypad_device_map(config: &HashMap<String, String>) -> HashMap<String, usize> {
-    HashMap::from([
-        (
-            config
-                .get("input_player1_a_btn")
-                .unwrap_or(&DEVICE_ID_JOYPAD_A.to_string())
-                .clone(),
-            DEVICE_ID_JOYPAD_A as usize,
-        ),
-        (
-            config
-                .get("input_player1_b_btn")
-                .unwrap_or(&DEVICE_ID_JOYPAD_B.to_string())
-                .clone(),
-            DEVICE_ID_JOYPAD_B as usize,
-        ),
-        (
-            config
-                .get("input_player1_x_btn")
-                .unwrap_or(&DEVICE_ID_JOYPAD_X.to_string())
-                .clone(),
-            DEVICE_ID_JOYPAD_X as usize,
-        ),
-        (
-            config
-                .get("input_player1_y_btn")
-                .unwrap_or(&DEVICE_ID_JOYPAD_Y.to_string())
-                .clone(),
-            DEVICE_ID_JOYPAD_Y as usize,
-        ),
-        (
-            config
-                .get("input_player1_l_btn")
-                .unwrap_or(&DEVICE_ID_JOYPAD_L.to_string())
-                .clone(),
-            DEVICE_ID_JOYPAD_L as usize,
-        ),
-        (
-            config
-                .get("input_player1_r_btn")
-                .unwrap_or(&DEVICE_ID_JOYPAD_R.to_string())
-                .clone(),
-            DEVICE_ID_JOYPAD_R as usize,
-        ),
-        (
-            config
-                .get("input_player1_down_btn")
-                .unwrap_or(&DEVICE_ID_JOYPAD_DOWN.to_string())
-                .clone(),
-            DEVICE_ID_JOYPAD_DOWN as usize,
-        ),
-        (
-            config
-                .get("input_player1_up_btn")
-                .unwrap_or(&DEVICE_ID_JOYPAD_UP.to_string())
-                .clone(),
-            DEVICE_ID_JOYPAD_UP as usize,
-        ),
-        (
-            config
-                .get("input_player1_right_btn")
-                .unwrap_or(&DEVICE_ID_JOYPAD_RIGHT.to_string())
-                .clone(),
-            DEVICE_ID_JOYPAD_RIGHT as usize,
-        ),
-        (
-            config
-                .get("input_player1_left_btn")
-                .unwrap_or(&DEVICE_ID_JOYPAD_LEFT.to_string())
-                .clone(),
-            DEVICE_ID_JOYPAD_LEFT as usize,
-        ),
-        (
-            config
-                .get("input_player1_start_btn")
-                .unwrap_or(&DEVICE_ID_JOYPAD_START.to_string())
-                .clone(),
-            DEVICE_ID_JOYPAD_START as usize,
-        ),
-        (
-            config
-                .get("input_player1_select_btn")
-                .unwrap_or(&DEVICE_ID_JOYPAD_SELECT.to_string())
-                .clone(),
-            DEVICE_ID_JOYPAD_SELECT as usize,
-        ),
-    ])
+/// Lists the (port, libretro device ID) pairs marked as turbo buttons in the config,
+/// e.g. `input_player1_a_turbo = true`.
+pub fn turbo_buttons(config: &HashMap<String, String>) -> Vec<(usize, usize)> {
+    let mut turbo_buttons = Vec::new();
+    for port in 0..MAX_PLAYERS {
+        for (button_name, device_id) in JOYPAD_BUTTON_NAMES {
+            let config_key = format!("input_player{}_{}_turbo", port + 1, button_name);
+            if config.get(&config_key).map(String::as_str) == Some("true") {
+                turbo_buttons.push((port, device_id as usize));
+            }
+        }
+    }
+    turbo_buttons
+}
+
+// gilrs button names, as written in `input_player1_*_btn`, lowercased without separators.
+const GAMEPAD_BUTTON_NAMES: [(&str, Button); 19] = [
+    ("south", Button::South),
+    ("east", Button::East),
+    ("north", Button::North),
+    ("west", Button::West),
+    ("c", Button::C),
+    ("z", Button::Z),
+    ("lefttrigger", Button::LeftTrigger),
+    ("lefttrigger2", Button::LeftTrigger2),
+    ("righttrigger", Button::RightTrigger),
+    ("righttrigger2", Button::RightTrigger2),
+    ("select", Button::Select),
+    ("start", Button::Start),
+    ("mode", Button::Mode),
+    ("leftthumb", Button::LeftThumb),
+    ("rightthumb", Button::RightThumb),
+    ("dpadup", Button::DPadUp),
+    ("dpaddown", Button::DPadDown),
+    ("dpadleft", Button::DPadLeft),
+    ("dpadright", Button::DPadRight),
+];
+
+// Reads a gamepad button setting: a gilrs button name such as `east` or `DPad_Up`, or, as
+// older configs have it, the libretro device ID whose default button to use.
+fn parse_gamepad_button(value: &str) -> Option<Button> {
+    if let Ok(device_id) = value.parse::<u32>() {
+        return libretro_to_button(device_id);
+    }
+    let name = value
+        .chars()
+        .filter(|c| !matches!(c, '_' | '-' | ' '))
+        .collect::<String>()
+        .to_ascii_lowercase();
+    GAMEPAD_BUTTON_NAMES
+        .iter()
+        .find(|(button_name, _)| *button_name == name)
+        .map(|&(_, button)| button)
 }
 
-/// Callback function for polling input states. Used primarily for logging in this context.
+/// Maps libretro device IDs to the gamepad buttons pressing them, from the
+/// `input_player1_*_btn` settings, which every gamepad shares. Unset buttons keep their
+/// default and `nul` unbinds one; an unknown button name is an error.
+pub fn setup_joypad_device_map(
+    config: &HashMap<String, String>,
+) -> Result<HashMap<usize, Button>, String> {
+    let mut joypad_device_map = HashMap::new();
+    for (button_name, device_id) in JOYPAD_BUTTON_NAMES {
+        let config_key = format!("input_player1_{}_btn", button_name);
+        let button = match config.get(&config_key).map(String::as_str) {
+            None | Some("") => libretro_to_button(device_id),
+            Some("nul") => None,
+            Some(value) => Some(parse_gamepad_button(value).ok_or_else(|| {
+                format!(
+                    "Unknown gamepad button '{}' for {}, expected one of: {}",
+                    value,
+                    config_key,
+                    GAMEPAD_BUTTON_NAMES
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?),
+        };
+        if let Some(button) = button {
+            joypad_device_map.insert(device_id as usize, button);
+        }
+    }
+    Ok(joypad_device_map)
+}
+
+/// When the input the core reads is copied from the live state, as RetroArch's
+/// `input_poll_type_behavior`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PollBehavior {
+    Early,  // Before every frame, whether or not the core polls
+    Normal, // When the core polls
+    Late,   // At the first query after the core polls, for the least latency
+}
+
+// Input as the core sees it for the current frame, so every query in a frame agrees.
+#[derive(Clone, Copy)]
+struct PolledInput {
+    joypad: [[bool; JOYPAD_BUTTONS]; MAX_PLAYERS],
+    analog: [AnalogSticks; MAX_PLAYERS],
+    keyboard: [bool; RETRO_KEY_COUNT],
+    mouse: [i16; MOUSE_IDS], // Motion since the last poll, then buttons, by mouse ID
+}
+
+// Mouse input gathered from the window between polls.
+struct MouseState {
+    motion: (f64, f64), // Movement not reported yet, in frame pixels
+    buttons: [bool; MOUSE_IDS],
+}
+
+static POLLED_INPUT: Lazy<Mutex<PolledInput>> = Lazy::new(|| {
+    Mutex::new(PolledInput {
+        joypad: [[false; JOYPAD_BUTTONS]; MAX_PLAYERS],
+        analog: [[[0; 2]; 2]; MAX_PLAYERS],
+        keyboard: [false; RETRO_KEY_COUNT],
+        mouse: [0; MOUSE_IDS],
+    })
+});
+static MOUSE_STATE: Lazy<Mutex<MouseState>> = Lazy::new(|| {
+    Mutex::new(MouseState {
+        motion: (0.0, 0.0),
+        buttons: [false; MOUSE_IDS],
+    })
+});
+// Which keys are held, indexed by RETROK_* keycode, for cores that read the keyboard.
+static KEYS_PRESSED: Lazy<Mutex<[bool; RETRO_KEY_COUNT]>> =
+    Lazy::new(|| Mutex::new([false; RETRO_KEY_COUNT]));
+static POLL_BEHAVIOR: Lazy<Mutex<PollBehavior>> = Lazy::new(|| Mutex::new(PollBehavior::Late));
+// Set by the core's poll in late mode, until the next query takes the snapshot.
+static POLL_PENDING: AtomicBool = AtomicBool::new(false);
+// Whether the key named by `input_enable_hotkey` is held down.
+static HOTKEY_ENABLE_HELD: AtomicBool = AtomicBool::new(false);
+
+/// Reads `input_poll_type_behavior`: 0 = early, 1 = normal, 2 = late.
+pub fn set_poll_behavior(config: &HashMap<String, String>) {
+    let behavior = match config["input_poll_type_behavior"].trim() {
+        "0" => PollBehavior::Early,
+        "1" => PollBehavior::Normal,
+        "2" => PollBehavior::Late,
+        other => {
+            log_warn!("Unknown input_poll_type_behavior '{}', using late", other);
+            PollBehavior::Late
+        }
+    };
+    *POLL_BEHAVIOR.lock().unwrap() = behavior;
+}
+
+// Copies the live input, or the movie's, into the snapshot the core reads from.
+fn snapshot_input() {
+    let joypad = MOVIE_INPUT
+        .lock()
+        .unwrap()
+        .unwrap_or_else(effective_buttons);
+    let analog = *ANALOG_STATE.lock().unwrap();
+    let keyboard = *KEYS_PRESSED.lock().unwrap();
+
+    let mut mouse_state = MOUSE_STATE.lock().unwrap();
+    let mut mouse = [0; MOUSE_IDS];
+    for (value, &pressed) in mouse.iter_mut().zip(&mouse_state.buttons) {
+        *value = pressed as i16;
+    }
+    // Only whole pixels are reported, the rest carries over to the next poll
+    let (motion_x, motion_y) = (mouse_state.motion.0.trunc(), mouse_state.motion.1.trunc());
+    mouse_state.motion.0 -= motion_x;
+    mouse_state.motion.1 -= motion_y;
+    mouse[DEVICE_ID_MOUSE_X as usize] = motion_x.clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+    mouse[DEVICE_ID_MOUSE_Y as usize] = motion_y.clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+    for wheel_id in MOUSE_WHEEL_IDS {
+        mouse_state.buttons[wheel_id as usize] = false;
+    }
+    drop(mouse_state);
+
+    *POLLED_INPUT.lock().unwrap() = PolledInput {
+        joypad,
+        analog,
+        keyboard,
+        mouse,
+    };
+}
+
+/// Takes the snapshot for the coming frame in early mode. Called right before `retro_run`.
+pub fn poll_before_frame() {
+    if *POLL_BEHAVIOR.lock().unwrap() == PollBehavior::Early {
+        snapshot_input();
+    }
+}
+
+/// Called by the core once per frame before it reads input.
 pub unsafe extern "C" fn libretro_set_input_poll_callback() {
-    println!("libretro_set_input_poll_callback")
+    match *POLL_BEHAVIOR.lock().unwrap() {
+        PollBehavior::Early => {}
+        PollBehavior::Normal => snapshot_input(),
+        PollBehavior::Late => POLL_PENDING.store(true, Ordering::SeqCst),
+    }
 }
 
 /// Retrieves the state of a specific input identified by libretro device IDs.
@@ -178,8 +290,169 @@ pub unsafe extern "C" fn libretro_set_input_state_callback(
     index: libc::c_uint,
     id: libc::c_uint,
 ) -> i16 {
-    let buttons = BUTTONS_PRESSED.lock().unwrap();
-    buttons.0.get(id as usize).copied().unwrap_or(0)
+    if POLL_PENDING.swap(false, Ordering::SeqCst) {
+        snapshot_input();
+    }
+    let polled_input = POLLED_INPUT.lock().unwrap();
+    // Cores may ask for a subclass of a device, which shares the base device's inputs
+    match device & DEVICE_MASK {
+        DEVICE_JOYPAD => polled_input
+            .joypad
+            .get(port as usize)
+            .and_then(|port_buttons| port_buttons.get(id as usize))
+            .map_or(0, |&pressed| pressed as i16),
+        DEVICE_ANALOG => polled_input
+            .analog
+            .get(port as usize)
+            .and_then(|sticks| sticks.get(index as usize))
+            .and_then(|stick| stick.get(id as usize))
+            .copied()
+            .unwrap_or(0),
+        DEVICE_MOUSE => polled_input.mouse.get(id as usize).copied().unwrap_or(0),
+        // The keyboard is shared, so it answers on every port
+        DEVICE_KEYBOARD => polled_input
+            .keyboard
+            .get(id as usize)
+            .map_or(0, |&pressed| pressed as i16),
+        _ => 0,
+    }
+}
+
+/// Adds mouse movement, already scaled to frame pixels.
+pub fn mouse_moved(delta_x: f64, delta_y: f64) {
+    let mut mouse_state = MOUSE_STATE.lock().unwrap();
+    mouse_state.motion.0 += delta_x;
+    mouse_state.motion.1 += delta_y;
+}
+
+pub fn mouse_button(button: MouseButton, state: ElementState) {
+    let id = match button {
+        MouseButton::Left => DEVICE_ID_MOUSE_LEFT,
+        MouseButton::Right => DEVICE_ID_MOUSE_RIGHT,
+        MouseButton::Middle => DEVICE_ID_MOUSE_MIDDLE,
+        MouseButton::Other(_) => return,
+    };
+    MOUSE_STATE.lock().unwrap().buttons[id as usize] = state == ElementState::Pressed;
+}
+
+pub fn mouse_wheel(delta: MouseScrollDelta) {
+    let (delta_x, delta_y) = match delta {
+        MouseScrollDelta::LineDelta(x, y) => (x as f64, y as f64),
+        MouseScrollDelta::PixelDelta(position) => (position.x, position.y),
+    };
+    let mut mouse_state = MOUSE_STATE.lock().unwrap();
+    let mut turn = |delta: f64, positive_id: u32, negative_id: u32| {
+        if delta > 0.0 {
+            mouse_state.buttons[positive_id as usize] = true;
+        } else if delta < 0.0 {
+            mouse_state.buttons[negative_id as usize] = true;
+        }
+    };
+    turn(delta_y, DEVICE_ID_MOUSE_WHEELUP, DEVICE_ID_MOUSE_WHEELDOWN);
+    turn(
+        delta_x,
+        DEVICE_ID_MOUSE_HORIZ_WHEELUP,
+        DEVICE_ID_MOUSE_HORIZ_WHEELDOWN,
+    );
+}
+
+/// How one analog axis is read, from `input_playerN_analog_<stick>_<axis>_*`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AxisSettings {
+    pub deadzone: f32,    // Fraction of the travel around the centre that reads as zero
+    pub sensitivity: f32, // Multiplier applied after the deadzone
+    pub invert: bool,
+}
+
+/// Axis settings of one port, indexed like `AnalogSticks`.
+pub type AnalogSettings = [[AxisSettings; 2]; 2];
+
+/// Reads the axis settings of every port. Axes without their own deadzone use
+/// `input_analog_deadzone`.
+pub fn analog_settings(config: &HashMap<String, String>) -> [AnalogSettings; MAX_PLAYERS] {
+    let default_deadzone = config["input_analog_deadzone"]
+        .parse::<f32>()
+        .unwrap_or(0.15);
+    let mut settings = [[[AxisSettings {
+        deadzone: default_deadzone,
+        sensitivity: 1.0,
+        invert: false,
+    }; 2]; 2]; MAX_PLAYERS];
+    for (port, port_settings) in settings.iter_mut().enumerate() {
+        for (stick, stick_name) in [
+            (DEVICE_INDEX_ANALOG_LEFT, "left"),
+            (DEVICE_INDEX_ANALOG_RIGHT, "right"),
+        ] {
+            for (axis, axis_name) in [(DEVICE_ID_ANALOG_X, "x"), (DEVICE_ID_ANALOG_Y, "y")] {
+                let prefix = format!(
+                    "input_player{}_analog_{}_{}",
+                    port + 1,
+                    stick_name,
+                    axis_name
+                );
+                let axis_settings = &mut port_settings[stick as usize][axis as usize];
+                let value = |name: &str| config.get(&format!("{}_{}", prefix, name));
+                if let Some(deadzone) = value("deadzone").and_then(|v| v.parse().ok()) {
+                    axis_settings.deadzone = deadzone;
+                }
+                if let Some(sensitivity) = value("sensitivity").and_then(|v| v.parse().ok()) {
+                    axis_settings.sensitivity = sensitivity;
+                }
+                if let Some(invert) = value("invert") {
+                    axis_settings.invert = invert == "true";
+                }
+                axis_settings.deadzone = axis_settings.deadzone.clamp(0.0, 1.0);
+                axis_settings.sensitivity = axis_settings.sensitivity.max(0.0);
+            }
+        }
+    }
+    settings
+}
+
+// Scales a gilrs axis value (-1.0..1.0) to the libretro range, zeroing anything inside
+// the deadzone and rescaling the rest so the stick still reaches full deflection.
+// Sensitivity above 1 reaches full deflection early, so the result is clamped.
+fn axis_to_analog(value: f32, settings: &AxisSettings) -> i16 {
+    let magnitude = value.abs();
+    if magnitude <= settings.deadzone || settings.deadzone >= 1.0 {
+        return 0;
+    }
+    let direction = if settings.invert {
+        -value.signum()
+    } else {
+        value.signum()
+    };
+    let scaled = ((magnitude - settings.deadzone) / (1.0 - settings.deadzone)
+        * settings.sensitivity)
+        .min(1.0)
+        * direction;
+    (scaled * i16::MAX as f32).round() as i16
+}
+
+/// Reads both analog sticks of a gamepad into the libretro analog state for its port.
+pub fn handle_gamepad_axes(
+    gilrs: &Gilrs,
+    gamepad_id: GamepadId,
+    settings: &AnalogSettings,
+    analog_sticks: &mut AnalogSticks,
+) {
+    let gamepad = gilrs.gamepad(gamepad_id);
+    let sticks = [
+        (DEVICE_INDEX_ANALOG_LEFT, Axis::LeftStickX, Axis::LeftStickY),
+        (
+            DEVICE_INDEX_ANALOG_RIGHT,
+            Axis::RightStickX,
+            Axis::RightStickY,
+        ),
+    ];
+    for (index, x_axis, y_axis) in sticks {
+        let stick = &mut analog_sticks[index as usize];
+        let stick_settings = &settings[index as usize];
+        let (x, y) = (DEVICE_ID_ANALOG_X as usize, DEVICE_ID_ANALOG_Y as usize);
+        stick[x] = axis_to_analog(gamepad.value(x_axis), &stick_settings[x]);
+        // gilrs reports up as positive while libretro expects up to be negative
+        stick[y] = axis_to_analog(-gamepad.value(y_axis), &stick_settings[y]);
+    }
 }
 
 /// Converts a libretro device ID to the corresponding gilrs Button.
@@ -203,60 +476,436 @@ fn libretro_to_button(libretro_button: u32) -> Option<Button> {
 
 /// Processes gamepad inputs and updates button states.
 pub fn handle_gamepad_input(
-    joypad_device_map: &HashMap<String, usize>,
+    joypad_device_map: &HashMap<usize, Button>,
     gilrs: &Gilrs,
-    active_gamepad: &Option<GamepadId>,
-    buttons_pressed: &mut Vec<i16>,
+    gamepad_id: GamepadId,
+    buttons_pressed: &mut [i16],
 ) {
-    if let Some(gamepad) = active_gamepad.map(|id| gilrs.gamepad(id)) {
-        for (button, libretro_button) in joypad_device_map {
-            if let Some(gilrs_button) = libretro_to_button(*libretro_button as u32) {
-                buttons_pressed[*libretro_button as usize] =
-                    gamepad.is_pressed(gilrs_button) as i16;
-            }
+    let gamepad = gilrs.gamepad(gamepad_id);
+    for (&libretro_button, &gilrs_button) in joypad_device_map {
+        buttons_pressed[libretro_button] = gamepad.is_pressed(gilrs_button) as i16;
+    }
+}
+
+// The live joypad state with turbo applied, as a movie records it.
+pub fn effective_buttons() -> [[bool; JOYPAD_BUTTONS]; MAX_PLAYERS] {
+    let buttons = BUTTONS_PRESSED.lock().unwrap();
+    let turbo_released = TURBO_RELEASED.lock().unwrap();
+    let mut effective = [[false; JOYPAD_BUTTONS]; MAX_PLAYERS];
+    for (port, port_buttons) in effective.iter_mut().enumerate() {
+        for (id, pressed) in port_buttons.iter_mut().enumerate() {
+            *pressed = buttons[port][id] != 0 && !turbo_released[port][id];
         }
     }
+    effective
+}
+
+/// Frontend actions triggered by hotkeys, carried out by the main loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotkey {
+    SaveState,
+    LoadState,
+    TogglePause,
+    FrameAdvance,
+    VolumeUp,
+    VolumeDown,
+    ToggleMute,
+    Screenshot,
+    DumpFrame,
+    CycleScaleMode,
+    ToggleSmooth,
+    ToggleShader,
+    CyclePresentMode,
+    RebindKeys,
+    DiskEjectToggle,
+    DiskNext,
+    DiskPrevious,
+    TogglePerfOverlay,
+    ToggleMouseGrab,
+    CheatNext,
+    CheatPrevious,
+    CheatToggle,
+    CheatReload,
+    RotateDisplay,
+    Reset,
+    SwitchCore,
+    FastForward(bool), // Whether the fast-forward key is held
+    Rewind(bool),      // Whether the rewind key is held
+}
+
+// Config keys naming the key bound to each hotkey.
+const HOTKEY_BINDINGS: [(&str, Hotkey); 26] = [
+    ("input_save_state", Hotkey::SaveState),
+    ("input_load_state", Hotkey::LoadState),
+    ("input_pause_toggle", Hotkey::TogglePause),
+    ("input_frame_advance", Hotkey::FrameAdvance),
+    ("input_volume_up", Hotkey::VolumeUp),
+    ("input_volume_down", Hotkey::VolumeDown),
+    ("input_audio_mute", Hotkey::ToggleMute),
+    ("input_screenshot", Hotkey::Screenshot),
+    ("input_dump_frame", Hotkey::DumpFrame),
+    ("input_scale_mode_cycle", Hotkey::CycleScaleMode),
+    ("input_smooth_toggle", Hotkey::ToggleSmooth),
+    ("input_shader_toggle", Hotkey::ToggleShader),
+    ("input_present_mode_cycle", Hotkey::CyclePresentMode),
+    ("input_rebind", Hotkey::RebindKeys),
+    ("input_disk_eject_toggle", Hotkey::DiskEjectToggle),
+    ("input_disk_next", Hotkey::DiskNext),
+    ("input_disk_prev", Hotkey::DiskPrevious),
+    ("input_perf_overlay_toggle", Hotkey::TogglePerfOverlay),
+    ("input_grab_mouse_toggle", Hotkey::ToggleMouseGrab),
+    ("input_cheat_index_plus", Hotkey::CheatNext),
+    ("input_cheat_index_minus", Hotkey::CheatPrevious),
+    ("input_cheat_toggle", Hotkey::CheatToggle),
+    ("input_cheat_reload", Hotkey::CheatReload),
+    ("input_rotate_display", Hotkey::RotateDisplay),
+    ("input_reset", Hotkey::Reset),
+    ("input_core_switch", Hotkey::SwitchCore),
+];
+
+// The name a key goes by in the config, e.g. `numpadadd` for the keypad plus key.
+fn key_name(virtual_keycode: VirtualKeyCode) -> String {
+    format!("{:?}", virtual_keycode).to_ascii_lowercase()
+}
+
+// The RETROK_* keycode for a winit key, for cores that read the keyboard directly.
+fn retro_key(virtual_keycode: VirtualKeyCode) -> Option<Key> {
+    use VirtualKeyCode as Vk;
+    Some(match virtual_keycode {
+        Vk::Key1 => Key::Number_1,
+        Vk::Key2 => Key::Number_2,
+        Vk::Key3 => Key::Number_3,
+        Vk::Key4 => Key::Number_4,
+        Vk::Key5 => Key::Number_5,
+        Vk::Key6 => Key::Number_6,
+        Vk::Key7 => Key::Number_7,
+        Vk::Key8 => Key::Number_8,
+        Vk::Key9 => Key::Number_9,
+        Vk::Key0 => Key::Number_0,
+        Vk::A => Key::A,
+        Vk::B => Key::B,
+        Vk::C => Key::C,
+        Vk::D => Key::D,
+        Vk::E => Key::E,
+        Vk::F => Key::F,
+        Vk::G => Key::G,
+        Vk::H => Key::H,
+        Vk::I => Key::I,
+        Vk::J => Key::J,
+        Vk::K => Key::K,
+        Vk::L => Key::L,
+        Vk::M => Key::M,
+        Vk::N => Key::N,
+        Vk::O => Key::O,
+        Vk::P => Key::P,
+        Vk::Q => Key::Q,
+        Vk::R => Key::R,
+        Vk::S => Key::S,
+        Vk::T => Key::T,
+        Vk::U => Key::U,
+        Vk::V => Key::V,
+        Vk::W => Key::W,
+        Vk::X => Key::X,
+        Vk::Y => Key::Y,
+        Vk::Z => Key::Z,
+        Vk::Escape => Key::Escape,
+        Vk::F1 => Key::F1,
+        Vk::F2 => Key::F2,
+        Vk::F3 => Key::F3,
+        Vk::F4 => Key::F4,
+        Vk::F5 => Key::F5,
+        Vk::F6 => Key::F6,
+        Vk::F7 => Key::F7,
+        Vk::F8 => Key::F8,
+        Vk::F9 => Key::F9,
+        Vk::F10 => Key::F10,
+        Vk::F11 => Key::F11,
+        Vk::F12 => Key::F12,
+        Vk::F13 => Key::F13,
+        Vk::F14 => Key::F14,
+        Vk::F15 => Key::F15,
+        Vk::Snapshot => Key::Print,
+        Vk::Scroll => Key::Scrollock,
+        Vk::Pause => Key::Pause,
+        Vk::Insert => Key::Insert,
+        Vk::Home => Key::Home,
+        Vk::Delete => Key::Delete,
+        Vk::End => Key::End,
+        Vk::PageDown => Key::PageDown,
+        Vk::PageUp => Key::PageUp,
+        Vk::Left => Key::Left,
+        Vk::Up => Key::Up,
+        Vk::Right => Key::Right,
+        Vk::Down => Key::Down,
+        Vk::Back => Key::Backspace,
+        Vk::Return => Key::Return,
+        Vk::Space => Key::Space,
+        Vk::Compose => Key::Compose,
+        Vk::Caret => Key::Caret,
+        Vk::Numlock => Key::Numlock,
+        Vk::Numpad0 => Key::Kp0,
+        Vk::Numpad1 => Key::Kp1,
+        Vk::Numpad2 => Key::Kp2,
+        Vk::Numpad3 => Key::Kp3,
+        Vk::Numpad4 => Key::Kp4,
+        Vk::Numpad5 => Key::Kp5,
+        Vk::Numpad6 => Key::Kp6,
+        Vk::Numpad7 => Key::Kp7,
+        Vk::Numpad8 => Key::Kp8,
+        Vk::Numpad9 => Key::Kp9,
+        Vk::NumpadAdd => Key::KpPlus,
+        Vk::NumpadDivide => Key::KpDivide,
+        Vk::NumpadDecimal => Key::KpPeriod,
+        Vk::NumpadEnter => Key::KpEnter,
+        Vk::NumpadEquals => Key::KpEquals,
+        Vk::NumpadMultiply => Key::KpMultiply,
+        Vk::NumpadSubtract => Key::KpMinus,
+        Vk::Apostrophe => Key::Quote,
+        Vk::Asterisk => Key::Asterisk,
+        Vk::At => Key::At,
+        Vk::Backslash => Key::Backslash,
+        Vk::Capital => Key::Capslock,
+        Vk::Colon => Key::Colon,
+        Vk::Comma => Key::Comma,
+        Vk::Equals => Key::Equals,
+        Vk::Grave => Key::Backquote,
+        Vk::LAlt => Key::LAlt,
+        Vk::LBracket => Key::LeftBracket,
+        Vk::LControl => Key::LCtrl,
+        Vk::LShift => Key::LShift,
+        Vk::LWin => Key::LSuper,
+        Vk::Minus => Key::Minus,
+        Vk::Period => Key::Period,
+        Vk::Plus => Key::Plus,
+        Vk::RAlt => Key::RAlt,
+        Vk::RBracket => Key::RightBracket,
+        Vk::RControl => Key::RCtrl,
+        Vk::RShift => Key::RShift,
+        Vk::RWin => Key::RSuper,
+        Vk::Semicolon => Key::Semicolon,
+        Vk::Slash => Key::Slash,
+        Vk::Sysrq => Key::Sysrq,
+        Vk::Tab => Key::Tab,
+        Vk::Underline => Key::Underscore,
+        Vk::Power => Key::Power,
+        Vk::Apps => Key::Menu,
+        _ => return None,
+    })
+}
+
+/// Walks through player 1's buttons, binding each one to the next key pressed.
+#[derive(Default)]
+pub struct KeyRebinder {
+    next_button: Option<usize>, // Index into JOYPAD_BUTTON_NAMES waiting for a key
+    pub changed: HashMap<String, String>, // Config entries rebound this session
+}
+
+impl KeyRebinder {
+    // Starts rebinding from the first button.
+    pub fn start(&mut self) {
+        self.next_button = Some(0);
+        log_info!("Rebinding player 1 controls, press Escape to stop");
+        self.prompt();
+    }
+
+    fn prompt(&self) {
+        if let Some(button) = self.next_button {
+            log_info!("Press a key for {}", JOYPAD_BUTTON_NAMES[button].0);
+        }
+    }
+
+    /// Binds the pressed key to the button being rebound. Returns true whenever the key
+    /// was consumed, so it isn't also treated as gameplay input or a hotkey.
+    pub fn handle_key(
+        &mut self,
+        input: KeyboardInput,
+        config: &mut HashMap<String, String>,
+        key_device_map: &mut HashMap<String, (usize, usize)>,
+    ) -> bool {
+        let Some(button) = self.next_button else {
+            return false;
+        };
+        let (ElementState::Pressed, Some(virtual_keycode)) = (input.state, input.virtual_keycode)
+        else {
+            return true;
+        };
+        if virtual_keycode == VirtualKeyCode::Escape {
+            self.next_button = None;
+            log_info!("Rebinding stopped");
+            return true;
+        }
+
+        let config_key = format!("input_player1_{}", JOYPAD_BUTTON_NAMES[button].0);
+        let key = key_name(virtual_keycode);
+        log_info!("Bound {} to {}", JOYPAD_BUTTON_NAMES[button].0, key);
+        config.insert(config_key.clone(), key.clone());
+        self.changed.insert(config_key, key);
+        *key_device_map = self::key_device_map(config);
+
+        self.next_button = Some(button + 1).filter(|&next| next < JOYPAD_BUTTON_NAMES.len());
+        match self.next_button {
+            Some(_) => self.prompt(),
+            None => log_info!("Rebinding finished"),
+        }
+        true
+    }
+}
+
+// Presses or releases the joypad button bound to the key, and the key itself for cores that
+// read the keyboard. Returns the keycode and its config name.
+fn update_key_state(
+    input: &winit::event::KeyboardInput,
+    buttons_pressed: &mut [[i16; JOYPAD_BUTTONS]],
+    key_device_map: &HashMap<String, (usize, usize)>,
+) -> Option<(VirtualKeyCode, String)> {
+    // Some keys, like media keys on a few platforms, come without a virtual keycode
+    let keycode = input.virtual_keycode?;
+    let key_as_string = key_name(keycode);
+
+    // Every key also reaches cores that read the keyboard device, bound or not
+    if let Some(key) = retro_key(keycode) {
+        KEYS_PRESSED.lock().unwrap()[key as usize] =
+            input.state == winit::event::ElementState::Pressed;
+    }
+
+    if let Some(&(port, device_id)) = key_device_map.get(&key_as_string) {
+        buttons_pressed[port][device_id] = match input.state {
+            winit::event::ElementState::Pressed => 1,
+            winit::event::ElementState::Released => 0,
+        };
+    }
+    Some((keycode, key_as_string))
 }
 
 /// Processes keyboard inputs, updates button states, and handles special input actions.
+/// Returns the hotkey that was pressed, if any, so the main loop can act on it.
 pub fn handle_keyboard_input(
     input: winit::event::KeyboardInput,
-    buttons_pressed: &mut Vec<i16>,
-    key_device_map: &HashMap<String, usize>,
+    buttons_pressed: &mut [[i16; JOYPAD_BUTTONS]],
+    key_device_map: &HashMap<String, (usize, usize)>,
     window: &Window,
-    primary_monitor: &MonitorHandle,
+    monitor: &MonitorHandle,
     is_fullscreen: &mut bool,
-) {
-    let key_as_string = format!("{:?}", input.virtual_keycode.unwrap()).to_ascii_lowercase();
+    config: &HashMap<String, String>,
+) -> Option<Hotkey> {
+    let (keycode, key_as_string) = update_key_state(&input, buttons_pressed, key_device_map)?;
+
+    // With `input_enable_hotkey` set, hotkeys only work while that key is held, so they
+    // can share keys with the game
+    let held = input.state == winit::event::ElementState::Pressed;
+    let hotkeys_enabled = match config.get("input_enable_hotkey").map(String::as_str) {
+        None | Some("") | Some("nul") => true,
+        Some(enable_key) => {
+            if enable_key == key_as_string {
+                HOTKEY_ENABLE_HELD.store(held, Ordering::SeqCst);
+            }
+            HOTKEY_ENABLE_HELD.load(Ordering::SeqCst)
+        }
+    };
 
-    if let Some(&device_id) = key_device_map.get(&key_as_string) {
-        buttons_pressed[device_id as usize] = if input.state == winit::event::ElementState::Pressed
-        {
-            1
+    if held && hotkeys_enabled && keycode == winit::event::VirtualKeyCode::F {
+        // Toggle based on the window's real state so the flag can't drift from it
+        *is_fullscreen = window.fullscreen().is_none();
+        let fullscreen = if *is_fullscreen {
+            Some(Fullscreen::Borderless(Some(monitor.clone())))
         } else {
-            0
+            None
         };
+        window.set_fullscreen(fullscreen);
     }
 
-    if let Some(&device_id) = key_device_map.get(&key_as_string) {
-        buttons_pressed[device_id as usize] = if input.state == winit::event::ElementState::Released
-        {
-            0
-        } else {
-            1
+    // Fast-forward and rewind only last while their key is held, so they report releases as
+    // well, even once the enable key is let go
+    if held && !hotkeys_enabled {
+        return None;
+    }
+    if config.get("input_hold_fast_forward") == Some(&key_as_string) {
+        return Some(Hotkey::FastForward(held));
+    }
+    if config.get("input_rewind") == Some(&key_as_string) {
+        return Some(Hotkey::Rewind(held));
+    }
+
+    if !held {
+        return None;
+    }
+    HOTKEY_BINDINGS
+        .iter()
+        .find(|(config_key, _)| config.get(*config_key) == Some(&key_as_string))
+        .map(|(_, hotkey)| *hotkey)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(deprecated)] // `modifiers` is deprecated but still has to be filled in
+    fn key_event(keycode: VirtualKeyCode, state: ElementState) -> KeyboardInput {
+        KeyboardInput {
+            scancode: 0,
+            state,
+            virtual_keycode: Some(keycode),
+            modifiers: Default::default(),
+        }
+    }
+
+    #[test]
+    fn mapped_keys_press_and_release_their_button() {
+        let _globals = crate::lock_test_globals();
+        let key_device_map = HashMap::from([(key_name(VirtualKeyCode::Z), (1, 8))]);
+        let mut buttons_pressed = [[0; JOYPAD_BUTTONS]; MAX_PLAYERS];
+
+        let pressed = key_event(VirtualKeyCode::Z, ElementState::Pressed);
+        update_key_state(&pressed, &mut buttons_pressed, &key_device_map);
+        assert_eq!(buttons_pressed[1][8], 1);
+
+        let released = key_event(VirtualKeyCode::Z, ElementState::Released);
+        update_key_state(&released, &mut buttons_pressed, &key_device_map);
+        assert_eq!(buttons_pressed[1][8], 0);
+
+        // Nothing else was touched
+        assert_eq!(buttons_pressed, [[0; JOYPAD_BUTTONS]; MAX_PLAYERS]);
+    }
+
+    #[test]
+    fn unmapped_keys_leave_the_buttons_alone() {
+        let _globals = crate::lock_test_globals();
+        let key_device_map = HashMap::from([(key_name(VirtualKeyCode::Z), (0, 8))]);
+        let mut buttons_pressed = [[0; JOYPAD_BUTTONS]; MAX_PLAYERS];
+
+        let pressed = key_event(VirtualKeyCode::X, ElementState::Pressed);
+        let key = update_key_state(&pressed, &mut buttons_pressed, &key_device_map);
+        assert_eq!(key, Some((VirtualKeyCode::X, key_name(VirtualKeyCode::X))));
+        assert_eq!(buttons_pressed, [[0; JOYPAD_BUTTONS]; MAX_PLAYERS]);
+    }
+
+    #[test]
+    fn axis_values_at_the_deadzone_boundary() {
+        let settings = AxisSettings {
+            deadzone: 0.25,
+            sensitivity: 1.0,
+            invert: false,
         };
+        assert_eq!(axis_to_analog(0.0, &settings), 0);
+        assert_eq!(axis_to_analog(0.25, &settings), 0);
+        assert_eq!(axis_to_analog(-0.25, &settings), 0);
+        // Just past the deadzone the stick starts from zero rather than jumping to it
+        let just_above = axis_to_analog(0.2501, &settings);
+        assert!((1..=10).contains(&just_above), "{}", just_above);
+        assert_eq!(axis_to_analog(-0.2501, &settings), -just_above);
+        assert_eq!(axis_to_analog(1.0, &settings), i16::MAX);
+        assert_eq!(axis_to_analog(-1.0, &settings), -i16::MAX);
     }
 
-    if input.state == winit::event::ElementState::Pressed
-        && input.virtual_keycode == Some(winit::event::VirtualKeyCode::F)
-    {
-        *is_fullscreen = !*is_fullscreen; // Toggle fullscreen state
-        let fullscreen = if *is_fullscreen {
-            let video_mode = primary_monitor.video_modes().next().unwrap();
-            Some(Fullscreen::Exclusive(video_mode))
-        } else {
-            None
+    #[test]
+    fn axis_values_are_inverted_and_clamped() {
+        let settings = AxisSettings {
+            deadzone: 0.0,
+            sensitivity: 2.0,
+            invert: true,
         };
-        window.set_fullscreen(fullscreen);
+        assert_eq!(axis_to_analog(0.0, &settings), 0);
+        assert_eq!(axis_to_analog(1.0, &settings), -i16::MAX);
+        assert_eq!(axis_to_analog(-0.75, &settings), i16::MAX);
     }
 }
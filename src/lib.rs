@@ -0,0 +1,81 @@
+// This implementation is based on the guide provided by [RetroGameDeveloper/RetroReversing].
+// Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
+// Copyright (c) 2023 Nicholas Ricciuti
+//
+// lib.rs
+//
+// The emulator as a library. `Emulator` loads a core and a game and runs it frame by frame,
+// so other programs can embed it; our own windowed frontend in main.rs is built on it too.
+
+// The unsafe functions all call into the core, and are safe once it has been loaded
+#![allow(clippy::missing_safety_doc)]
+
+// Import necessary modules from other files and crates
+#[macro_use]
+pub mod logger;
+pub mod archive;
+pub mod audio;
+pub mod benchmark;
+pub mod cheats;
+pub mod emulator;
+pub mod input;
+pub mod libretro;
+#[cfg(test)]
+mod mock_core;
+pub mod movie;
+pub mod netplay;
+pub mod overlay;
+pub mod picker;
+pub mod renderer;
+pub mod video;
+pub mod vrr;
+
+pub use emulator::Emulator;
+pub use libretro::EmulatorState;
+
+use libretro_sys::PixelFormat;
+use once_cell::sync::Lazy;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU32;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+
+// Define global static variables for handling input, pixel format, video, and audio data
+pub static BUTTONS_PRESSED: Lazy<Mutex<[[i16; input::JOYPAD_BUTTONS]; input::MAX_PLAYERS]>> =
+    Lazy::new(|| Mutex::new([[0; input::JOYPAD_BUTTONS]; input::MAX_PLAYERS]));
+pub static ANALOG_STATE: Lazy<Mutex<[input::AnalogSticks; input::MAX_PLAYERS]>> =
+    Lazy::new(|| Mutex::new([[[0; 2]; 2]; input::MAX_PLAYERS]));
+static TURBO_RELEASED: Lazy<Mutex<[[bool; input::JOYPAD_BUTTONS]; input::MAX_PLAYERS]>> =
+    Lazy::new(|| Mutex::new([[false; input::JOYPAD_BUTTONS]; input::MAX_PLAYERS]));
+// Buttons read back from a movie, used instead of live input while one plays.
+static MOVIE_INPUT: Lazy<Mutex<Option<movie::MovieButtons>>> = Lazy::new(|| Mutex::new(None));
+type PixelFormatChannel = (Sender<PixelFormat>, Arc<Mutex<Receiver<PixelFormat>>>);
+static PIXEL_FORMAT_CHANNEL: Lazy<PixelFormatChannel> = Lazy::new(|| {
+    let (sender, receiver) = channel::<PixelFormat>();
+    (sender, Arc::new(Mutex::new(receiver)))
+});
+// The newest frame from the core, waiting to be converted. A frame that isn't picked up
+// before the next one arrives is replaced, so a stalled renderer can't pile them up.
+static VIDEO_DATA_SLOT: Lazy<(Mutex<Option<VideoData>>, Condvar)> =
+    Lazy::new(|| (Mutex::new(None), Condvar::new()));
+pub static FINAL_SAMPLE_RATE: AtomicU32 = AtomicU32::new(48_000);
+pub static FAST_FORWARDING: AtomicBool = AtomicBool::new(false);
+static REWINDING: AtomicBool = AtomicBool::new(false);
+
+// Taken by tests that use the globals above, since the test harness runs tests in parallel.
+// A test that panicked while holding it doesn't fail the ones after it.
+#[cfg(test)]
+fn lock_test_globals() -> std::sync::MutexGuard<'static, ()> {
+    static TEST_GLOBALS: Mutex<()> = Mutex::new(());
+    TEST_GLOBALS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Structure to hold video data
+struct VideoData {
+    frame_buffer: Vec<u8>,
+    width: u32,
+    height: u32,
+    pitch: u32,
+}
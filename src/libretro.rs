@@ -7,18 +7,29 @@
 // This module provides the interface to the libretro core, including functions for
 // loading ROMs, managing save states, and handling configurations.
 
-use crate::PIXEL_FORMAT_CHANNEL;
+use crate::archive;
+use crate::logger;
+use crate::overlay;
 use crate::video;
+use crate::PIXEL_FORMAT_CHANNEL;
 use clap::Parser;
 use libc::c_void;
 use libloading::Library;
-use libretro_sys::GameInfo;
-use libretro_sys::{CoreAPI, GameGeometry, PixelFormat, SystemAvInfo, SystemTiming};
-use std::ffi::CString;
+use libretro_sys::{
+    CoreAPI, GameGeometry, PixelFormat, SystemAvInfo, SystemTiming, MEMORY_SAVE_RAM,
+};
+use libretro_sys::{DiskControlCallback, GameInfo, LogCallback, LogPrintfFn, SystemInfo, Variable};
+use libretro_sys::{SubsystemInfo, SubsystemRomInfo};
+use once_cell::sync::{Lazy, OnceCell};
+use std::ffi::{CStr, CString};
+use std::fmt;
 use std::fs;
 use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     env,
     fs::File,
     io::{BufRead, BufReader, Read},
@@ -28,53 +39,821 @@ use std::{
 // Expected version of the libretro API.
 const EXPECTED_LIB_RETRO_VERSION: u32 = 1;
 
+// Environment commands newer than libretro-sys.
+const ENVIRONMENT_GET_AUDIO_VIDEO_ENABLE: u32 = 47 | libretro_sys::ENVIRONMENT_EXPERIMENTAL;
+const ENVIRONMENT_GET_MESSAGE_INTERFACE_VERSION: u32 = 59;
+const ENVIRONMENT_SET_MESSAGE_EXT: u32 = 60;
+// retro_message_target values saying where an extended message should go.
+const MESSAGE_TARGET_LOG: libc::c_uint = 2;
+
+// struct retro_message_ext, the message ENVIRONMENT_SET_MESSAGE_EXT passes. The enums are
+// read as plain integers, like the log level.
+#[repr(C)]
+struct MessageExt {
+    msg: *const libc::c_char,
+    duration: libc::c_uint, // Milliseconds to show it for
+    priority: libc::c_uint,
+    level: libc::c_uint,
+    target: libc::c_uint,
+    kind: libc::c_uint,
+    progress: i8,
+}
+
 // Represents the emulator state and configuration.
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 pub struct EmulatorState {
     // Path to the ROM file to be loaded.
-    #[arg(help = "Sets the path to the ROM file to load", index = 1)]
+    // Empty for cores that run without any content.
+    #[arg(
+        help = "Sets the path to the ROM file to load, not needed by cores that run without one",
+        index = 1,
+        default_value = ""
+    )]
     pub rom_name: String,
-    #[arg(short = 'L', default_value = "default_library")]
-    // Name of the core library to be loaded.
+    #[arg(
+        short = 'L',
+        default_value = "default_library",
+        help = "Sets the core to load, as a path or a name in the cores directory"
+    )]
+    // Path to the core library to be loaded, or its short name such as `snes9x`.
     pub library_name: String,
+    // Path to our config file, defaults to ./rustroarch.cfg.
+    #[arg(long = "config", help = "Sets the path to the config file to load")]
+    pub config_path: Option<String>,
+    // Whether to keep the alpha bits the core sends or treat every pixel as opaque.
+    #[arg(
+        long = "alpha",
+        value_enum,
+        default_value_t = video::AlphaMode::Opaque,
+        help = "Sets how the alpha bits of the core's pixels are handled"
+    )]
+    pub alpha_mode: video::AlphaMode,
+    // Number of frames to run headless before reporting frame times, if benchmarking.
+    #[arg(
+        long = "benchmark",
+        value_name = "FRAMES",
+        help = "Runs the given number of frames without a window and reports frame times"
+    )]
+    pub benchmark_frames: Option<u32>,
+    // Movie file to record input to, or to play input back from.
+    #[arg(
+        long = "record",
+        value_name = "MOVIE",
+        conflicts_with = "play_movie",
+        help = "Records every frame's input to a movie file"
+    )]
+    pub record_movie: Option<String>,
+    #[arg(
+        long = "play",
+        value_name = "MOVIE",
+        help = "Plays back input from a movie file instead of live input"
+    )]
+    pub play_movie: Option<String>,
+    // Subsystem to load followed by its files, e.g. `--subsystem sgb sgb.bin game.gb`.
+    #[arg(
+        long = "subsystem",
+        num_args = 2..,
+        value_names = ["ID", "ROM"],
+        conflicts_with = "rom_name",
+        help = "Loads several files together as one of the core's subsystems"
+    )]
+    pub subsystem: Vec<String>,
+    // Launches the ROM played last time, as remembered in `last_rom`.
+    #[arg(
+        long = "last",
+        conflicts_with_all = ["rom_name", "subsystem"],
+        help = "Loads the ROM played last time"
+    )]
+    pub last_rom: bool,
+    // Address of the netplay host to connect to, or to listen on with `--netplay-host`.
+    #[arg(
+        long = "netplay",
+        value_name = "ADDRESS",
+        conflicts_with_all = ["record_movie", "play_movie"],
+        help = "Plays with a second player over the network, connecting to host:port"
+    )]
+    pub netplay: Option<String>,
+    #[arg(
+        long = "netplay-host",
+        requires = "netplay",
+        help = "Hosts the netplay session, listening on the --netplay address"
+    )]
+    pub netplay_host: bool,
+    // Multiple of the core's base resolution the window starts at, overriding `video_scale`.
+    #[arg(
+        long = "scale",
+        help = "Sets the starting window size as a multiple of the core's resolution"
+    )]
+    pub scale: Option<u32>,
+    // Frame rate to run at no matter the display, for reproducing pacing problems. Bypasses
+    // the VRR and refresh rate logic entirely.
+    #[arg(
+        long = "fps",
+        help = "Forces the frame rate, ignoring the display's refresh rate and VRR support"
+    )]
+    pub fps: Option<f64>,
+    // Index of the display to play on, as listed at startup.
+    #[arg(
+        long = "monitor",
+        value_name = "INDEX",
+        help = "Plays on the display with this index instead of the primary one"
+    )]
+    pub monitor: Option<usize>,
+    #[arg(long = "fullscreen", help = "Starts in fullscreen")]
+    pub fullscreen: bool,
+    #[arg(
+        long = "no-audio",
+        help = "Runs without sound, for systems with no audio device"
+    )]
+    pub no_audio: bool,
+    // Name of the output device to play sound on, overriding `audio_device`.
+    #[arg(
+        long = "audio-device",
+        value_name = "NAME",
+        help = "Plays sound on the named output device instead of the default one"
+    )]
+    pub audio_device: Option<String>,
     #[arg(skip)]
     pub current_save_slot: u8,
     #[arg(skip)]
     pub av_info: Option<SystemAvInfo>,
+    // CRC32 of the loaded game, for telling apart games that share a file name.
+    #[arg(skip)]
+    pub content_crc32: Option<u32>,
     #[arg(skip)]
     pub pixel_format: video::EmulatorPixelFormat,
+    // Whether `pixel_format` holds what the core negotiated yet. Its pixel size follows
+    // from the format, see `EmulatorPixelFormat::bytes_per_pixel`.
     #[arg(skip)]
-    pub bytes_per_pixel: u8,
+    pub pixel_format_ready: bool,
 }
 
-// Parses command-line arguments to obtain the ROM name and core library name.
-pub fn parse_command_line_arguments() -> (String, String) {
+// Parses command-line arguments to obtain the ROM name, core library name and options.
+pub fn parse_command_line_arguments() -> EmulatorState {
     let emulator_state = EmulatorState::parse();
 
-    println!("ROM name: {}", emulator_state.rom_name);
-    println!("Core Library name: {}", emulator_state.library_name);
+    if emulator_state.rom_name.is_empty() {
+        log_info!("ROM name: none");
+    } else {
+        log_info!("ROM name: {}", emulator_state.rom_name);
+    }
+    log_info!("Core Library name: {}", emulator_state.library_name);
 
-    (emulator_state.rom_name, emulator_state.library_name)
+    emulator_state
+}
+
+// Queries the core's static system information.
+pub unsafe fn get_system_info(core_api: &CoreAPI) -> SystemInfo {
+    let mut system_info = SystemInfo {
+        library_name: ptr::null(),
+        library_version: ptr::null(),
+        valid_extensions: ptr::null(),
+        need_fullpath: false,
+        block_extract: false,
+    };
+    (core_api.retro_get_system_info)(&mut system_info);
+    system_info
+}
+
+// Converts a C string owned by the core into a Rust string, treating null as empty.
+pub unsafe fn core_string(core_str: *const libc::c_char) -> String {
+    if core_str.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(core_str).to_string_lossy().into_owned()
+}
+
+// Returns the core's accepted ROM extensions, lowercased, or an empty list if it accepts anything.
+pub unsafe fn valid_extensions(system_info: &SystemInfo) -> Vec<String> {
+    core_string(system_info.valid_extensions)
+        .split('|')
+        .filter(|extension| !extension.is_empty())
+        .map(|extension| extension.to_ascii_lowercase())
+        .collect()
+}
+
+// Prints what the core says about itself, which explains most failures to load a ROM.
+pub unsafe fn print_system_info(system_info: &SystemInfo) {
+    let extensions = valid_extensions(system_info);
+    log_info!(
+        "Core: {} {}",
+        core_string(system_info.library_name),
+        core_string(system_info.library_version)
+    );
+    log_info!(
+        "Core accepts: {}",
+        if extensions.is_empty() {
+            "any file".to_string()
+        } else {
+            extensions.join(", ")
+        }
+    );
+    log_info!(
+        "Core loads ROMs from: {}, archives: {}",
+        if system_info.need_fullpath {
+            "their path"
+        } else {
+            "memory"
+        },
+        if system_info.block_extract {
+            "opened by the core"
+        } else {
+            "extracted by the frontend"
+        }
+    );
+}
+
+// ROM extracted from an archive to a temporary file, removed again on exit.
+static EXTRACTED_ROM: Lazy<Mutex<Option<PathBuf>>> = Lazy::new(|| Mutex::new(None));
+// Hash of the loaded game's contents, if it could be read.
+static CONTENT_CRC32: Lazy<Mutex<Option<u32>>> = Lazy::new(|| Mutex::new(None));
+
+// Returns the lowercased extension of a file name.
+fn file_extension(file_name: &str) -> String {
+    Path::new(file_name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+// Extracts the first file in a zip archive the core can load, returning its name and contents.
+fn extract_zipped_rom(
+    archive_data: &[u8],
+    extensions: &[String],
+) -> Result<(String, Vec<u8>), String> {
+    let entries = archive::list_entries(archive_data)?;
+    let entry = entries
+        .iter()
+        .filter(|entry| !entry.name.ends_with('/'))
+        .find(|entry| extensions.is_empty() || extensions.contains(&file_extension(&entry.name)))
+        .ok_or_else(|| "The archive contains no file the core can load".to_string())?;
+    log_info!("Loading {} from the archive", entry.name);
+    Ok((
+        entry.name.clone(),
+        archive::extract_entry(archive_data, entry)?,
+    ))
+}
+
+// Writes an extracted ROM to a temporary file for cores that insist on a path.
+fn write_extracted_rom(entry_name: &str, contents: &[u8]) -> Result<PathBuf, String> {
+    let file_name = Path::new(entry_name).file_name().unwrap_or_default();
+    let temp_path = env::temp_dir().join(format!(
+        "rustretro_{}_{}",
+        std::process::id(),
+        file_name.to_string_lossy()
+    ));
+    fs::write(&temp_path, contents)
+        .map_err(|e| format!("Failed to write {}: {}", temp_path.display(), e))?;
+    *EXTRACTED_ROM.lock().unwrap() = Some(temp_path.clone());
+    Ok(temp_path)
+}
+
+// Removes the temporary file left behind by loading a zipped ROM, if there is one.
+pub fn cleanup_extracted_rom() {
+    if let Some(temp_path) = EXTRACTED_ROM.lock().unwrap().take() {
+        if let Err(err) = fs::remove_file(&temp_path) {
+            log_warn!("Failed to remove {}: {}", temp_path.display(), err);
+        }
+    }
 }
 
 // Loads the specified ROM file using the provided Core API.
-pub unsafe fn load_rom_file(core_api: &CoreAPI, rom_name: &String) -> bool {
-    let cstr_rom_name = CString::new(rom_name.clone()).expect("Failed to create CString");
-    let contents = fs::read(rom_name).expect("Failed to read file");
-    let data: *const c_void = contents.as_ptr() as *const c_void;
+// Zipped ROMs are extracted first, unless the core can open the archive itself.
+pub unsafe fn load_rom_file(core_api: &CoreAPI, rom_name: &str) -> bool {
+    let system_info = get_system_info(core_api);
+    let extensions = valid_extensions(&system_info);
+    print_system_info(&system_info);
+
+    // Playlists list the discs of multi-disc games. Unless the core reads them itself we
+    // boot the first disc and hand the rest over through the disk control interface
+    let mut playlist_discs = Vec::new();
+    if file_extension(rom_name) == "m3u" && !extensions.contains(&"m3u".to_string()) {
+        playlist_discs = match parse_m3u(Path::new(rom_name)) {
+            Ok(discs) if !discs.is_empty() => discs,
+            Ok(_) => {
+                log_error!("Playlist {} doesn't list any discs", rom_name);
+                return false;
+            }
+            Err(err) => {
+                log_error!("{}", err);
+                return false;
+            }
+        };
+    }
+    let rom_name = playlist_discs.first().map_or(rom_name, String::as_str);
+
+    let mut rom_path = rom_name.to_string();
+    let mut rom_extension = file_extension(rom_name);
+    let mut contents = None;
+
+    if rom_extension == "zip" && !system_info.block_extract && !extensions.contains(&rom_extension)
+    {
+        let extracted = fs::read(rom_name)
+            .map_err(|e| format!("Failed to read ROM file {}: {}", rom_name, e))
+            .and_then(|archive_data| extract_zipped_rom(&archive_data, &extensions));
+        let (entry_name, entry_contents) = match extracted {
+            Ok(extracted) => extracted,
+            Err(err) => {
+                log_error!("{}", err);
+                return false;
+            }
+        };
+        rom_extension = file_extension(&entry_name);
+        if system_info.need_fullpath {
+            match write_extracted_rom(&entry_name, &entry_contents) {
+                Ok(temp_path) => rom_path = temp_path.to_string_lossy().into_owned(),
+                Err(err) => {
+                    log_error!("{}", err);
+                    return false;
+                }
+            }
+        } else {
+            // Point the core at where the file would be, so it can still see the real extension
+            rom_path = Path::new(rom_name)
+                .with_file_name(&entry_name)
+                .to_string_lossy()
+                .into_owned();
+            contents = Some(entry_contents);
+        }
+    }
+
+    // Reject files the core has told us it can't load
+    if !extensions.is_empty() && !extensions.contains(&rom_extension) {
+        log_error!(
+            "{} can't load '{}' files, it expects one of: {}",
+            core_string(system_info.library_name),
+            rom_extension,
+            extensions.join(", ")
+        );
+        return false;
+    }
+
+    // Cores that need the full path load the file themselves, so we don't read it
+    if contents.is_none() && !system_info.need_fullpath {
+        match fs::read(rom_name) {
+            Ok(rom_contents) => contents = Some(rom_contents),
+            Err(err) => {
+                log_error!("Failed to read ROM file {}: {}", rom_name, err);
+                return false;
+            }
+        }
+    }
+
+    // Hash what was already read, only streaming the file when the core reads it itself
+    let content_crc32 = match &contents {
+        Some(contents) => Some(crc32fast::hash(contents)),
+        None => file_crc32(Path::new(&rom_path))
+            .map_err(|err| log_error!("Failed to hash ROM file {}: {}", rom_path, err))
+            .ok(),
+    };
+    if let Some(crc32) = content_crc32 {
+        log_info!("Content CRC32: {:08x}", crc32);
+    }
+    *CONTENT_CRC32.lock().unwrap() = content_crc32;
+
+    let cstr_rom_name = CString::new(rom_path).expect("Failed to create CString");
+    let (data, size) = match &contents {
+        Some(contents) => (contents.as_ptr() as *const c_void, contents.len()),
+        None => (ptr::null(), 0),
+    };
 
     let game_info = GameInfo {
         path: cstr_rom_name.as_ptr(),
         data,
-        size: contents.len(),
+        size,
         meta: ptr::null(),
     };
 
     let was_load_successful = (core_api.retro_load_game)(&game_info);
     if !was_load_successful {
-        panic!("Rom Load was not successful");
+        log_error!("Rom Load was not successful");
+    } else if playlist_discs.len() > 1 {
+        add_disk_images(&playlist_discs[1..]);
+    }
+    was_load_successful
+}
+
+// Starts a core that runs without content, as set through ENVIRONMENT_SET_SUPPORT_NO_GAME.
+pub unsafe fn load_no_game(core_api: &CoreAPI) -> bool {
+    print_system_info(&get_system_info(core_api));
+    if !SUPPORTS_NO_GAME.load(Ordering::SeqCst) {
+        log_error!("No ROM given, and this core can't run without one");
+        return false;
+    }
+    let was_load_successful = (core_api.retro_load_game)(ptr::null());
+    if !was_load_successful {
+        log_error!("Core failed to start without a ROM");
+    }
+    was_load_successful
+}
+
+// Loads several files together as the subsystem named `ident`, such as `sgb`, in the order
+// the core lists them. Optional files at the end may be left out.
+pub unsafe fn load_subsystem(core_api: &CoreAPI, ident: &str, rom_names: &[String]) -> bool {
+    print_system_info(&get_system_info(core_api));
+    let subsystems = SUBSYSTEMS.lock().unwrap();
+    let Some(subsystem) = subsystems.iter().find(|subsystem| subsystem.ident == ident) else {
+        if subsystems.is_empty() {
+            log_error!("This core has no subsystems, so can't load '{}'", ident);
+        } else {
+            let offered: Vec<String> = subsystems
+                .iter()
+                .map(|subsystem| format!("{} ({})", subsystem.ident, subsystem.description))
+                .collect();
+            log_error!(
+                "This core has no subsystem '{}', it offers: {}",
+                ident,
+                offered.join(", ")
+            );
+        }
+        return false;
+    };
+    if rom_names.len() > subsystem.roms.len() {
+        log_error!(
+            "{} takes at most {} file(s), got {}",
+            subsystem.description,
+            subsystem.roms.len(),
+            rom_names.len()
+        );
+        return false;
+    }
+    if let Some(missing) = subsystem.roms[rom_names.len()..]
+        .iter()
+        .find(|rom| rom.required)
+    {
+        log_error!(
+            "{} also needs a file for: {}",
+            subsystem.description,
+            missing.description
+        );
+        return false;
+    }
+
+    // The core only borrows the paths and contents, so they're kept here until it returns
+    let mut paths = Vec::with_capacity(rom_names.len());
+    let mut contents = Vec::with_capacity(rom_names.len());
+    for (rom_name, rom) in rom_names.iter().zip(&subsystem.roms) {
+        let extension = file_extension(rom_name);
+        if !rom.extensions.is_empty() && !rom.extensions.contains(&extension) {
+            log_error!(
+                "{} for {} has to be one of: {}",
+                rom.description,
+                subsystem.description,
+                rom.extensions.join(", ")
+            );
+            return false;
+        }
+        paths.push(CString::new(rom_name.as_str()).expect("Failed to create CString"));
+        contents.push(if rom.need_fullpath {
+            None
+        } else {
+            match fs::read(rom_name) {
+                Ok(rom_contents) => Some(rom_contents),
+                Err(err) => {
+                    log_error!("Failed to read ROM file {}: {}", rom_name, err);
+                    return false;
+                }
+            }
+        });
+    }
+    // Saves are named after the first file, so that's the one identifying the game
+    *CONTENT_CRC32.lock().unwrap() = match contents.first() {
+        Some(Some(first)) => Some(crc32fast::hash(first)),
+        _ => rom_names
+            .first()
+            .and_then(|rom_name| file_crc32(Path::new(rom_name)).ok()),
+    };
+
+    let game_infos: Vec<GameInfo> = paths
+        .iter()
+        .zip(&contents)
+        .map(|(path, rom_contents)| {
+            let (data, size) = match rom_contents {
+                Some(rom_contents) => (rom_contents.as_ptr() as *const c_void, rom_contents.len()),
+                None => (ptr::null(), 0),
+            };
+            GameInfo {
+                path: path.as_ptr(),
+                data,
+                size,
+                meta: ptr::null(),
+            }
+        })
+        .collect();
+    log_info!(
+        "Loading {} from {}",
+        subsystem.description,
+        rom_names.join(", ")
+    );
+    let was_load_successful =
+        (core_api.retro_load_game_special)(subsystem.id, game_infos.as_ptr(), game_infos.len());
+    if !was_load_successful {
+        log_error!("Core failed to load {}", subsystem.description);
+    }
+    was_load_successful
+}
+
+// CRC32 of a file, read in chunks so large disc images aren't held in memory.
+fn file_crc32(path: &Path) -> std::io::Result<u32> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = crc32fast::Hasher::new();
+    loop {
+        let chunk = reader.fill_buf()?;
+        if chunk.is_empty() {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(chunk);
+        let length = chunk.len();
+        reader.consume(length);
+    }
+}
+
+// CRC32 of the game `load_rom_file` last loaded, like the ones game databases list.
+pub fn content_crc32() -> Option<u32> {
+    *CONTENT_CRC32.lock().unwrap()
+}
+
+// Reads the disc paths from an `.m3u` playlist, resolving them relative to the playlist.
+fn parse_m3u(playlist_path: &Path) -> Result<Vec<String>, String> {
+    let playlist = fs::read_to_string(playlist_path)
+        .map_err(|e| format!("Failed to read playlist {}: {}", playlist_path.display(), e))?;
+    let playlist_dir = playlist_path.parent().unwrap_or(Path::new(""));
+    Ok(playlist
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| playlist_dir.join(line).to_string_lossy().into_owned())
+        .collect())
+}
+
+// The disk control interface registered by the core, used to swap discs.
+static DISK_CONTROL: Lazy<Mutex<Option<DiskControlCallback>>> = Lazy::new(|| Mutex::new(None));
+// Paths handed to the core for the extra discs, kept alive for as long as it may read them.
+static DISK_IMAGE_PATHS: Lazy<Mutex<Vec<CString>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Appends the remaining playlist discs to the core's disk list, leaving the first inserted.
+unsafe fn add_disk_images(disc_paths: &[String]) {
+    let Some(disk_control) = DISK_CONTROL.lock().unwrap().clone() else {
+        log_warn!("Core has no disk control interface, only the first disc can be played");
+        return;
+    };
+    let mut disk_image_paths = DISK_IMAGE_PATHS.lock().unwrap();
+
+    // The core only accepts new images while the tray is open
+    let inserted_index = (disk_control.get_image_index)();
+    (disk_control.set_eject_state)(true);
+    for disc_path in disc_paths {
+        let Ok(path) = CString::new(disc_path.as_str()) else {
+            continue;
+        };
+        let game_info = GameInfo {
+            path: path.as_ptr(),
+            data: ptr::null(),
+            size: 0,
+            meta: ptr::null(),
+        };
+        let index = (disk_control.get_num_images)();
+        if (disk_control.add_image_index)() && (disk_control.replace_image_index)(index, &game_info)
+        {
+            log_info!("Added disc {}: {}", index + 1, disc_path);
+        } else {
+            log_warn!("Core refused disc {}", disc_path);
+        }
+        disk_image_paths.push(path);
+    }
+    (disk_control.set_image_index)(inserted_index);
+    (disk_control.set_eject_state)(false);
+}
+
+// Opens or closes the virtual disc tray.
+pub unsafe fn toggle_disk_eject() {
+    let Some(disk_control) = DISK_CONTROL.lock().unwrap().clone() else {
+        log_warn!("Core does not support swapping discs");
+        return;
+    };
+    let ejected = !(disk_control.get_eject_state)();
+    if (disk_control.set_eject_state)(ejected) {
+        log_info!("Disc tray {}", if ejected { "opened" } else { "closed" });
+    }
+}
+
+// Selects the next (`step` = 1) or previous (`step` = -1) disc. The tray must be open.
+pub unsafe fn cycle_disk(step: i32) {
+    let Some(disk_control) = DISK_CONTROL.lock().unwrap().clone() else {
+        log_warn!("Core does not support swapping discs");
+        return;
+    };
+    if !(disk_control.get_eject_state)() {
+        log_info!("Open the disc tray before changing discs");
+        return;
+    }
+    let image_count = (disk_control.get_num_images)() as i32;
+    if image_count == 0 {
+        return;
+    }
+    let index = ((disk_control.get_image_index)() as i32 + step).rem_euclid(image_count);
+    if (disk_control.set_image_index)(index as u32) {
+        log_info!("Disc {} of {} selected", index + 1, image_count);
+    }
+}
+
+// Directories handed to cores: one for BIOS and other system files, one for the saves a
+// core writes itself. They're set once, before the core starts, so the pointers handed out
+// stay valid for as long as the core runs.
+static SYSTEM_DIRECTORY: OnceCell<CString> = OnceCell::new();
+static SAVE_DIRECTORY: OnceCell<CString> = OnceCell::new();
+
+// Reads the core directories from the config, creating them if they're missing.
+pub fn set_core_directories(config: &HashMap<String, String>) {
+    for (directory, config_key) in [
+        (&SYSTEM_DIRECTORY, "system_directory"),
+        (&SAVE_DIRECTORY, "savefile_directory"),
+    ] {
+        let path = shellexpand::tilde(&config[config_key]).into_owned();
+        if let Err(err) = fs::create_dir_all(&path) {
+            log_warn!("Failed to create {} {}: {}", config_key, path, err);
+        }
+        match CString::new(path) {
+            Ok(path) => {
+                let _ = directory.set(path);
+            }
+            Err(_) => log_warn!("Ignoring {}, it contains a NUL byte", config_key),
+        }
+    }
+}
+
+// Hands one of the core directories to the core, if it's been set.
+unsafe fn return_directory(directory: &OnceCell<CString>, return_data: *mut c_void) -> bool {
+    match directory.get() {
+        Some(path) => {
+            *(return_data as *mut *const libc::c_char) = path.as_ptr();
+            true
+        }
+        None => false,
+    }
+}
+
+// Current values of the options a core advertised through ENVIRONMENT_SET_VARIABLES,
+// plus the config values that override their defaults. These live in a
+// global because the environment callback has no other way to reach frontend state.
+#[derive(Default)]
+struct CoreOptions {
+    options: HashMap<String, CString>,
+    overrides: HashMap<String, String>,
+    updated: bool,
+}
+
+static CORE_OPTIONS: Lazy<Mutex<CoreOptions>> = Lazy::new(|| Mutex::new(CoreOptions::default()));
+
+// Set by cores that can start without a game, like test cores and standalone games.
+static SUPPORTS_NO_GAME: AtomicBool = AtomicBool::new(false);
+
+// A kind of multi-file content the core can load, as declared through
+// ENVIRONMENT_SET_SUBSYSTEM_INFO, e.g. a Super Game Boy BIOS plus a Game Boy ROM.
+pub struct Subsystem {
+    pub description: String,
+    pub ident: String,
+    pub id: libc::c_uint,
+    pub roms: Vec<SubsystemRom>,
+}
+
+pub struct SubsystemRom {
+    pub description: String,
+    pub extensions: Vec<String>,
+    pub need_fullpath: bool,
+    pub required: bool,
+}
+
+static SUBSYSTEMS: Lazy<Mutex<Vec<Subsystem>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Copies the subsystems a core declares, a list ended by an entry without a description.
+unsafe fn set_subsystems(mut subsystem: *const SubsystemInfo) {
+    let mut subsystems = Vec::new();
+    while !subsystem.is_null() && !(*subsystem).desc.is_null() {
+        let info = &*subsystem;
+        let roms = if info.roms.is_null() {
+            &[][..]
+        } else {
+            std::slice::from_raw_parts(info.roms, info.num_roms as usize)
+        };
+        subsystems.push(Subsystem {
+            description: core_string(info.desc),
+            ident: core_string(info.ident),
+            id: info.id,
+            roms: roms.iter().map(|rom| subsystem_rom(rom)).collect(),
+        });
+        subsystem = subsystem.add(1);
+    }
+    *SUBSYSTEMS.lock().unwrap() = subsystems;
+}
+
+unsafe fn subsystem_rom(rom: &SubsystemRomInfo) -> SubsystemRom {
+    SubsystemRom {
+        description: core_string(rom.desc),
+        extensions: core_string(rom.valid_extensions)
+            .split('|')
+            .filter(|extension| !extension.is_empty())
+            .map(|extension| extension.to_ascii_lowercase())
+            .collect(),
+        need_fullpath: rom.need_fullpath,
+        required: rom.required,
+    }
+}
+
+// AV info the core replaced during `retro_run`, waiting for the frontend to pick it up.
+static AV_INFO_UPDATE: Lazy<Mutex<Option<SystemAvInfo>>> = Lazy::new(|| Mutex::new(None));
+
+// The frame rate the core reports, as f64 bits, for timing what it counts in frames.
+static CORE_FPS: AtomicU64 = AtomicU64::new(0);
+
+fn set_core_fps(fps: f64) {
+    CORE_FPS.store(fps.to_bits(), Ordering::SeqCst);
+}
+
+// The core's frame rate, or 60 if it hasn't reported a usable one.
+fn core_fps() -> f64 {
+    match f64::from_bits(CORE_FPS.load(Ordering::SeqCst)) {
+        fps if fps > 0.0 => fps,
+        _ => 60.0,
+    }
+}
+
+// Returns the AV info the core last set through ENVIRONMENT_SET_SYSTEM_AV_INFO, if it
+// changed since the previous call.
+pub fn take_av_info_update() -> Option<SystemAvInfo> {
+    AV_INFO_UPDATE.lock().unwrap().take()
+}
+
+// Set when the core asks through ENVIRONMENT_SHUTDOWN to stop running, e.g. after an
+// internal error or when its game quits.
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Returns true once after the core asked to shut down.
+pub fn take_shutdown_request() -> bool {
+    SHUTDOWN_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+// Lets config file entries override the defaults of core options with the same key.
+pub fn set_core_option_overrides(config: &HashMap<String, String>) {
+    CORE_OPTIONS.lock().unwrap().overrides = config.clone();
+}
+
+// Stores the options a core declares, each formatted as "Description; default|other|...".
+unsafe fn set_core_variables(mut variable: *const Variable) {
+    let mut core_options = CORE_OPTIONS.lock().unwrap();
+    while !variable.is_null() && !(*variable).key.is_null() {
+        let key = core_string((*variable).key);
+        let declaration = core_string((*variable).value);
+        let choices: Vec<String> = declaration
+            .split_once(';')
+            .map_or("", |(_, choices)| choices)
+            .trim()
+            .split('|')
+            .map(|choice| choice.to_string())
+            .collect();
+
+        // The first choice is the default, unless the config picks another valid one
+        let mut value = choices[0].clone();
+        if let Some(override_value) = core_options.overrides.get(&key) {
+            if choices.contains(override_value) {
+                value = override_value.clone();
+            } else {
+                log_warn!(
+                    "Ignoring invalid value '{}' for core option {}, expected one of: {}",
+                    override_value,
+                    key,
+                    choices.join(", ")
+                );
+            }
+        }
+        log_info!("Core option {} = {}", key, value);
+
+        let value = CString::new(value).unwrap_or_default();
+        core_options.options.insert(key, value);
+        variable = variable.add(1);
+    }
+    // Let the core know there are values to read
+    core_options.updated = true;
+}
+
+// Answers a core's query for the current value of one of its options.
+unsafe fn get_core_variable(variable: *mut Variable) -> bool {
+    if variable.is_null() || (*variable).key.is_null() {
+        return false;
+    }
+    let key = core_string((*variable).key);
+    let core_options = CORE_OPTIONS.lock().unwrap();
+    match core_options.options.get(&key) {
+        Some(value) => {
+            // The CString is stored in the global, so the pointer stays valid for the core
+            (*variable).value = value.as_ptr();
+            true
+        }
+        None => {
+            (*variable).value = ptr::null();
+            false
+        }
     }
-    return was_load_successful;
 }
 
 // Callback function for the libretro environment.
@@ -82,17 +861,153 @@ unsafe extern "C" fn libretro_environment_callback(command: u32, return_data: *m
     match command {
         libretro_sys::ENVIRONMENT_GET_CAN_DUPE => {
             *(return_data as *mut bool) = true; // Set the return_data to the value true
-            println!("ENVIRONMENT_GET_CAN_DUPE");
+            log_debug!("ENVIRONMENT_GET_CAN_DUPE");
+            return true;
         }
         libretro_sys::ENVIRONMENT_SET_PIXEL_FORMAT => {
             let pixel_format = *(return_data as *const u32);
+            let Some(pixel_format) = PixelFormat::from_uint(pixel_format) else {
+                // Refusing lets the core fall back to a format we do support
+                log_warn!("Core requested unsupported pixel format: {}", pixel_format);
+                return false;
+            };
             let sender = &PIXEL_FORMAT_CHANNEL.0; // Use the global sender
             sender
-                .send(PixelFormat::from_uint(pixel_format).unwrap())
+                .send(pixel_format)
                 .expect("Failed to send pixel format");
             return true;
         }
-        _ => println!(
+        libretro_sys::ENVIRONMENT_SET_VARIABLES => {
+            set_core_variables(return_data as *const Variable);
+            return true;
+        }
+        libretro_sys::ENVIRONMENT_GET_VARIABLE => {
+            return get_core_variable(return_data as *mut Variable);
+        }
+        libretro_sys::ENVIRONMENT_GET_VARIABLE_UPDATE => {
+            // Report whether options changed since the core last asked, then clear the flag
+            let mut core_options = CORE_OPTIONS.lock().unwrap();
+            *(return_data as *mut bool) = core_options.updated;
+            core_options.updated = false;
+            return true;
+        }
+        libretro_sys::ENVIRONMENT_SET_GEOMETRY => {
+            // The video refresh callback reports the new size with the next frame,
+            // which is where the frame buffer gets resized
+            let geometry = &*(return_data as *const GameGeometry);
+            log_info!(
+                "Core set geometry to {}x{}",
+                geometry.base_width,
+                geometry.base_height
+            );
+            return true;
+        }
+        libretro_sys::ENVIRONMENT_SET_ROTATION => {
+            // Vertical arcade games ask for this, frames are rotated as they're converted
+            let quarter_turns = *(return_data as *const libc::c_uint);
+            log_info!("Core set rotation to {} degrees", quarter_turns % 4 * 90);
+            video::set_core_rotation(quarter_turns);
+            return true;
+        }
+        libretro_sys::ENVIRONMENT_SET_SUBSYSTEM_INFO => {
+            set_subsystems(return_data as *const SubsystemInfo);
+            for subsystem in SUBSYSTEMS.lock().unwrap().iter() {
+                log_info!(
+                    "Core offers subsystem {} ({}), loading {} file(s)",
+                    subsystem.ident,
+                    subsystem.description,
+                    subsystem.roms.len()
+                );
+            }
+            return true;
+        }
+        libretro_sys::ENVIRONMENT_SET_SUPPORT_NO_GAME => {
+            SUPPORTS_NO_GAME.store(*(return_data as *const bool), Ordering::SeqCst);
+            return true;
+        }
+        libretro_sys::ENVIRONMENT_SET_SYSTEM_AV_INFO => {
+            // Cores do this when switching e.g. between PAL and NTSC. The timing is
+            // applied after the frame, since pacing and audio live outside the core's call
+            let av_info = &*(return_data as *const SystemAvInfo);
+            log_info!(
+                "Core changed AV info to {}x{} at {:.3} fps, {} Hz",
+                av_info.geometry.base_width,
+                av_info.geometry.base_height,
+                av_info.timing.fps,
+                av_info.timing.sample_rate
+            );
+            set_core_fps(av_info.timing.fps);
+            *AV_INFO_UPDATE.lock().unwrap() = Some(av_info.clone());
+            return true;
+        }
+        libretro_sys::ENVIRONMENT_GET_LOG_INTERFACE => {
+            let log_callback: unsafe extern "C" fn(libc::c_uint, *const libc::c_char) =
+                logger::libretro_log_callback;
+            // Our callback takes the level as a plain integer so an unexpected value from
+            // the core can't form an invalid enum
+            (*(return_data as *mut LogCallback)).log = std::mem::transmute::<
+                unsafe extern "C" fn(libc::c_uint, *const libc::c_char),
+                LogPrintfFn,
+            >(log_callback);
+            return true;
+        }
+        libretro_sys::ENVIRONMENT_SET_DISK_CONTROL_INTERFACE => {
+            let disk_control = &*(return_data as *const DiskControlCallback);
+            *DISK_CONTROL.lock().unwrap() = Some(disk_control.clone());
+            return true;
+        }
+        libretro_sys::ENVIRONMENT_SET_HW_RENDER => {
+            // We only draw software frames. Refusing lets cores that can fall back to
+            // software rendering do so, instead of waiting for a GL context that never comes.
+            // The context type is read as a plain integer, like the log level
+            let context_type = *(return_data as *const libc::c_uint);
+            log_warn!(
+                "Core requested hardware rendering ({}), which isn't supported",
+                hw_context_name(context_type)
+            );
+            return false;
+        }
+        libretro_sys::ENVIRONMENT_SHUTDOWN => {
+            log_info!("Core requested shutdown");
+            SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+            return true;
+        }
+        libretro_sys::ENVIRONMENT_SET_MESSAGE => {
+            // The duration is in the core's frames, whatever rate the display refreshes at
+            let message = &*(return_data as *const libretro_sys::Message);
+            let text = core_string(message.msg);
+            log_info!("Core message: {}", text);
+            let duration = Duration::from_secs_f64(message.frames as f64 / core_fps());
+            overlay::show_message(text, duration);
+            return true;
+        }
+        ENVIRONMENT_GET_MESSAGE_INTERFACE_VERSION => {
+            *(return_data as *mut libc::c_uint) = 1;
+            return true;
+        }
+        ENVIRONMENT_SET_MESSAGE_EXT => {
+            let message = &*(return_data as *const MessageExt);
+            let text = core_string(message.msg);
+            log_info!("Core message: {}", text);
+            if message.target != MESSAGE_TARGET_LOG {
+                overlay::show_message(text, Duration::from_millis(message.duration as u64));
+            }
+            return true;
+        }
+        ENVIRONMENT_GET_AUDIO_VIDEO_ENABLE => {
+            // Bit 0 enables video and bit 1 audio. Audio always plays, even on skipped frames
+            *(return_data as *mut libc::c_int) = if video::video_skipped() { 2 } else { 3 };
+            return true;
+        }
+        libretro_sys::ENVIRONMENT_GET_SYSTEM_DIRECTORY => {
+            log_debug!("ENVIRONMENT_GET_SYSTEM_DIRECTORY");
+            return return_directory(&SYSTEM_DIRECTORY, return_data);
+        }
+        libretro_sys::ENVIRONMENT_GET_SAVE_DIRECTORY => {
+            log_debug!("ENVIRONMENT_GET_SAVE_DIRECTORY");
+            return return_directory(&SAVE_DIRECTORY, return_data);
+        }
+        _ => log_debug!(
             "libretro_environment_callback Called with command: {}",
             command
         ),
@@ -100,107 +1015,270 @@ unsafe extern "C" fn libretro_environment_callback(command: u32, return_data: *m
     false
 }
 
+// Names the RETRO_HW_CONTEXT_* values for messages.
+fn hw_context_name(context_type: libc::c_uint) -> &'static str {
+    match context_type {
+        1 => "OpenGL",
+        2 => "OpenGL ES 2",
+        3 => "OpenGL core profile",
+        4 => "OpenGL ES 3",
+        5 => "OpenGL ES",
+        6 => "Vulkan",
+        7 => "Direct3D 11",
+        8 => "Direct3D 10",
+        9 => "Direct3D 12",
+        10 => "Direct3D 9",
+        _ => "unknown context",
+    }
+}
+
+// Reasons a libretro core can fail to load.
+#[derive(Debug)]
+pub enum CoreLoadError {
+    // The shared library couldn't be opened, with the path and the loader's error.
+    LibraryNotFound(String, String),
+    // No core by that name in the cores directory, with every path that was tried.
+    CoreNotFound(String, Vec<PathBuf>),
+    // A required libretro function is missing, with the symbol name and the loader's error.
+    MissingSymbol(String, String),
+    // The core reports a libretro API version other than the one we support.
+    IncompatibleApiVersion(u32),
+}
+
+impl fmt::Display for CoreLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoreLoadError::LibraryNotFound(path, err) => write!(
+                f,
+                "Failed to load core library '{}', check that the path after -L is correct: {}",
+                path, err
+            ),
+            CoreLoadError::CoreNotFound(name, searched) => write!(
+                f,
+                "No core named '{}' found, looked for: {}",
+                name,
+                searched
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            CoreLoadError::MissingSymbol(symbol, err) => write!(
+                f,
+                "The core is missing the libretro function '{}', is it a libretro core? {}",
+                symbol, err
+            ),
+            CoreLoadError::IncompatibleApiVersion(api_version) => write!(
+                f,
+                "The Core has been compiled with a LibRetro API that is unexpected, we expected version to be: {} but it was: {}",
+                EXPECTED_LIB_RETRO_VERSION, api_version
+            ),
+        }
+    }
+}
+
+// Finds the core library `-L` names. A path to an existing file is used as it is, anything
+// else is taken as a short name like `snes9x` and looked up in the cores directory:
+// $RUSTRETRO_CORE_DIR if set, otherwise `libretro_directory`.
+pub fn resolve_core_path(
+    library_name: &str,
+    config: &HashMap<String, String>,
+) -> Result<String, CoreLoadError> {
+    if Path::new(library_name).is_file() {
+        return Ok(library_name.to_string());
+    }
+    let cores_directory =
+        env::var("RUSTRETRO_CORE_DIR").unwrap_or_else(|_| config["libretro_directory"].clone());
+    let cores_directory = PathBuf::from(shellexpand::tilde(&cores_directory).into_owned());
+    let extension = env::consts::DLL_EXTENSION;
+    let candidates = [
+        cores_directory.join(library_name),
+        cores_directory.join(format!("{}_libretro.{}", library_name, extension)),
+        cores_directory.join(format!("{}.{}", library_name, extension)),
+    ];
+    match candidates.iter().find(|path| path.is_file()) {
+        Some(path) => Ok(path.to_string_lossy().into_owned()),
+        None => {
+            let mut searched = vec![PathBuf::from(library_name)];
+            searched.extend(candidates);
+            Err(CoreLoadError::CoreNotFound(
+                library_name.to_string(),
+                searched,
+            ))
+        }
+    }
+}
+
+// Looks up a libretro function in the core library.
+unsafe fn load_symbol<T: Copy>(dylib: &Library, symbol: &str) -> Result<T, CoreLoadError> {
+    dylib
+        .get::<T>(symbol.as_bytes())
+        .map(|function| *function)
+        .map_err(|err| CoreLoadError::MissingSymbol(symbol.to_string(), err.to_string()))
+}
+
+// Asks the core at `library_path` which ROM extensions it accepts, without initializing it.
+// Libretro allows retro_get_system_info to be called before anything else.
+pub unsafe fn core_valid_extensions(library_path: &str) -> Result<Vec<String>, CoreLoadError> {
+    let dylib = Library::new(library_path)
+        .map_err(|err| CoreLoadError::LibraryNotFound(library_path.to_string(), err.to_string()))?;
+    let retro_get_system_info: unsafe extern "C" fn(info: *mut SystemInfo) =
+        load_symbol(&dylib, "retro_get_system_info")?;
+    let mut system_info = SystemInfo {
+        library_name: ptr::null(),
+        library_version: ptr::null(),
+        valid_extensions: ptr::null(),
+        need_fullpath: false,
+        block_extract: false,
+    };
+    retro_get_system_info(&mut system_info);
+    // Copied out before the library goes away with the strings it owns
+    Ok(valid_extensions(&system_info))
+}
+
 // Represents a loaded libretro core with associated functions.
 pub struct Core {
-    pub dylib: Library,
+    pub dylib: Option<Library>, // None for cores that were already in the process
     pub api: CoreAPI,
 }
 
 impl Core {
-    pub fn new(mut state: EmulatorState) -> (Self, EmulatorState) {
+    pub fn new(state: EmulatorState) -> Result<(Self, EmulatorState), CoreLoadError> {
         unsafe {
-            let dylib = Library::new(&state.library_name).expect("Failed to load Core");
+            let dylib = Library::new(&state.library_name).map_err(|err| {
+                CoreLoadError::LibraryNotFound(state.library_name.clone(), err.to_string())
+            })?;
 
             let core_api = CoreAPI {
-                retro_set_environment: *(dylib.get(b"retro_set_environment").unwrap()),
-                retro_set_video_refresh: *(dylib.get(b"retro_set_video_refresh").unwrap()),
-                retro_set_audio_sample: *(dylib.get(b"retro_set_audio_sample").unwrap()),
-                retro_set_audio_sample_batch: *(dylib
-                    .get(b"retro_set_audio_sample_batch")
-                    .unwrap()),
-                retro_set_input_poll: *(dylib.get(b"retro_set_input_poll").unwrap()),
-                retro_set_input_state: *(dylib.get(b"retro_set_input_state").unwrap()),
-
-                retro_init: *(dylib.get(b"retro_init").unwrap()),
-                retro_deinit: *(dylib.get(b"retro_deinit").unwrap()),
-
-                retro_api_version: *(dylib.get(b"retro_api_version").unwrap()),
-
-                retro_get_system_info: *(dylib.get(b"retro_get_system_info").unwrap()),
-                retro_get_system_av_info: *(dylib.get(b"retro_get_system_av_info").unwrap()),
-                retro_set_controller_port_device: *(dylib
-                    .get(b"retro_set_controller_port_device")
-                    .unwrap()),
-
-                retro_reset: *(dylib.get(b"retro_reset").unwrap()),
-                retro_run: *(dylib.get(b"retro_run").unwrap()),
-
-                retro_serialize_size: *(dylib.get(b"retro_serialize_size").unwrap()),
-                retro_serialize: *(dylib.get(b"retro_serialize").unwrap()),
-                retro_unserialize: *(dylib.get(b"retro_unserialize").unwrap()),
-
-                retro_cheat_reset: *(dylib.get(b"retro_cheat_reset").unwrap()),
-                retro_cheat_set: *(dylib.get(b"retro_cheat_set").unwrap()),
-
-                retro_load_game: *(dylib.get(b"retro_load_game").unwrap()),
-                retro_load_game_special: *(dylib.get(b"retro_load_game_special").unwrap()),
-                retro_unload_game: *(dylib.get(b"retro_unload_game").unwrap()),
-
-                retro_get_region: *(dylib.get(b"retro_get_region").unwrap()),
-                retro_get_memory_data: *(dylib.get(b"retro_get_memory_data").unwrap()),
-                retro_get_memory_size: *(dylib.get(b"retro_get_memory_size").unwrap()),
-            };
+                retro_set_environment: load_symbol(&dylib, "retro_set_environment")?,
+                retro_set_video_refresh: load_symbol(&dylib, "retro_set_video_refresh")?,
+                retro_set_audio_sample: load_symbol(&dylib, "retro_set_audio_sample")?,
+                retro_set_audio_sample_batch: load_symbol(&dylib, "retro_set_audio_sample_batch")?,
+                retro_set_input_poll: load_symbol(&dylib, "retro_set_input_poll")?,
+                retro_set_input_state: load_symbol(&dylib, "retro_set_input_state")?,
 
-            let api_version = (core_api.retro_api_version)();
-            println!("API Version: {}", api_version);
-            if api_version != EXPECTED_LIB_RETRO_VERSION {
-                panic!("The Core has been compiled with a LibRetro API that is unexpected, we expected version to be: {} but it was: {}", EXPECTED_LIB_RETRO_VERSION, api_version)
-            }
-            (core_api.retro_set_environment)(libretro_environment_callback);
-            (core_api.retro_init)();
-            let mut av_info = SystemAvInfo {
-                geometry: GameGeometry {
-                    base_width: 0,
-                    base_height: 0,
-                    max_width: 0,
-                    max_height: 0,
-                    aspect_ratio: 0.0,
-                },
-                timing: SystemTiming {
-                    fps: 0.0,
-                    sample_rate: 0.0,
-                },
+                retro_init: load_symbol(&dylib, "retro_init")?,
+                retro_deinit: load_symbol(&dylib, "retro_deinit")?,
+
+                retro_api_version: load_symbol(&dylib, "retro_api_version")?,
+
+                retro_get_system_info: load_symbol(&dylib, "retro_get_system_info")?,
+                retro_get_system_av_info: load_symbol(&dylib, "retro_get_system_av_info")?,
+                retro_set_controller_port_device: load_symbol(
+                    &dylib,
+                    "retro_set_controller_port_device",
+                )?,
+
+                retro_reset: load_symbol(&dylib, "retro_reset")?,
+                retro_run: load_symbol(&dylib, "retro_run")?,
+
+                retro_serialize_size: load_symbol(&dylib, "retro_serialize_size")?,
+                retro_serialize: load_symbol(&dylib, "retro_serialize")?,
+                retro_unserialize: load_symbol(&dylib, "retro_unserialize")?,
+
+                retro_cheat_reset: load_symbol(&dylib, "retro_cheat_reset")?,
+                retro_cheat_set: load_symbol(&dylib, "retro_cheat_set")?,
+
+                retro_load_game: load_symbol(&dylib, "retro_load_game")?,
+                retro_load_game_special: load_symbol(&dylib, "retro_load_game_special")?,
+                retro_unload_game: load_symbol(&dylib, "retro_unload_game")?,
+
+                retro_get_region: load_symbol(&dylib, "retro_get_region")?,
+                retro_get_memory_data: load_symbol(&dylib, "retro_get_memory_data")?,
+                retro_get_memory_size: load_symbol(&dylib, "retro_get_memory_size")?,
             };
-            (core_api.retro_get_system_av_info)(&mut av_info);
-            println!("AV Info: {:?}", &av_info);
-            state.av_info = Some(av_info);
 
-            // Construct and return a Core instance
-            (
-                Core {
-                    dylib,
-                    api: core_api,
-                },
-                state,
-            )
+            let (mut core, state) = Self::from_api(core_api, state)?;
+            core.dylib = Some(dylib);
+            Ok((core, state))
+        }
+    }
+
+    // Initializes a core from its functions, checking it speaks our version of the API.
+    pub unsafe fn from_api(
+        core_api: CoreAPI,
+        mut state: EmulatorState,
+    ) -> Result<(Self, EmulatorState), CoreLoadError> {
+        let api_version = (core_api.retro_api_version)();
+        log_info!("API Version: {}", api_version);
+        if api_version != EXPECTED_LIB_RETRO_VERSION {
+            return Err(CoreLoadError::IncompatibleApiVersion(api_version));
         }
+        (core_api.retro_set_environment)(libretro_environment_callback);
+        (core_api.retro_init)();
+        // Only a guess until a game is loaded, see `query_av_info`
+        state.av_info = Some(query_av_info(&core_api));
+
+        Ok((
+            Core {
+                dylib: None,
+                api: core_api,
+            },
+            state,
+        ))
     }
 }
 
-// Handles dropping of the Core, which could include cleanup tasks.
-impl Drop for Core {
-    fn drop(&mut self) {
-        // Cleanup code here...
+// Asks the core for its geometry and timing. Libretro only defines the answer once a game
+// is loaded, so many cores report zeros before that.
+pub unsafe fn query_av_info(core_api: &CoreAPI) -> SystemAvInfo {
+    let mut av_info = SystemAvInfo {
+        geometry: GameGeometry {
+            base_width: 0,
+            base_height: 0,
+            max_width: 0,
+            max_height: 0,
+            aspect_ratio: 0.0,
+        },
+        timing: SystemTiming {
+            fps: 0.0,
+            sample_rate: 0.0,
+        },
+    };
+    (core_api.retro_get_system_av_info)(&mut av_info);
+    log_info!("AV Info: {:?}", &av_info);
+    set_core_fps(av_info.timing.fps);
+    av_info
+}
+
+// Shuts the core down: writes SRAM while the game is still loaded, unloads the game if
+// one was loaded, then deinitializes the core and removes any extracted ROM.
+pub unsafe fn shutdown_core(core_api: &CoreAPI, loaded_rom: Option<&str>) {
+    if let Some(rom_name) = loaded_rom {
+        save_sram(core_api, rom_name);
+        (core_api.retro_unload_game)();
     }
+    (core_api.retro_deinit)();
+    cleanup_extracted_rom();
+    forget_core_declarations();
+}
+
+// Clears what the core told us through the environment callback, so a core loaded after it
+// in the same process doesn't inherit its options, disks or subsystems.
+fn forget_core_declarations() {
+    let mut core_options = CORE_OPTIONS.lock().unwrap();
+    core_options.options.clear();
+    core_options.updated = false;
+    drop(core_options);
+    SUPPORTS_NO_GAME.store(false, Ordering::SeqCst);
+    SUBSYSTEMS.lock().unwrap().clear();
+    *DISK_CONTROL.lock().unwrap() = None;
+    DISK_IMAGE_PATHS.lock().unwrap().clear();
+    *AV_INFO_UPDATE.lock().unwrap() = None;
+    SHUTDOWN_REQUESTED.store(false, Ordering::SeqCst);
+    video::set_core_rotation(0);
 }
 
 // Utility functions for managing save states and configuration files follow.
 
 // `get_save_state_path` computes the path for a save state file.
+// With `content_crc32`, the name also carries the game's hash, so different games with the
+// same file name don't share states.
 fn get_save_state_path(
-    save_directory: &String,
+    save_directory: &str,
     game_file_name: &str,
-    save_state_index: &u8,
+    content_crc32: Option<u32>,
+    save_state_index: u8,
 ) -> Option<PathBuf> {
     // Expand the tilde to the home directory
     let expanded_save_directory = shellexpand::tilde(save_directory);
@@ -208,12 +1286,13 @@ fn get_save_state_path(
     // Create a subdirectory named "saves" in the specified directory
     let saves_dir = PathBuf::from(expanded_save_directory.into_owned());
     if !saves_dir.exists() {
-        match std::fs::create_dir_all(&saves_dir) {
-            Ok(_) => {}
-            Err(err) => panic!(
+        if let Err(err) = std::fs::create_dir_all(&saves_dir) {
+            log_warn!(
                 "Failed to create save directory: {:?} Error: {}",
-                &saves_dir, err
-            ),
+                &saves_dir,
+                err
+            );
+            return None;
         }
     }
 
@@ -222,8 +1301,11 @@ fn get_save_state_path(
         .file_stem()
         .unwrap_or_default()
         .to_string_lossy()
-        .replace(" ", "_");
-    let save_state_file_name = format!("{}_{}.state", game_name, save_state_index);
+        .replace(' ', "_");
+    let save_state_file_name = match content_crc32 {
+        Some(crc32) => format!("{}_{:08x}_{}.state", game_name, crc32, save_state_index),
+        None => format!("{}_{}.state", game_name, save_state_index),
+    };
 
     // Combine the saves directory and the save state filename to create the full path
     let save_state_path = saves_dir.join(save_state_file_name);
@@ -231,39 +1313,67 @@ fn get_save_state_path(
     Some(save_state_path)
 }
 
+// `serialize_state` snapshots the core into `state_buffer`, resizing it to the core's state size.
+// Returns false when the core doesn't support serialization or the snapshot failed.
+pub unsafe fn serialize_state(core_api: &CoreAPI, state_buffer: &mut Vec<u8>) -> bool {
+    let state_size = (core_api.retro_serialize_size)();
+    if state_size == 0 {
+        return false;
+    }
+    state_buffer.resize(state_size, 0);
+    // libretro-sys declares retro_serialize as returning nothing, but the libretro API
+    // returns a bool reporting success, so call it through the correct signature
+    let retro_serialize: unsafe extern "C" fn(*mut c_void, libc::size_t) -> bool =
+        std::mem::transmute(core_api.retro_serialize);
+    retro_serialize(state_buffer.as_mut_ptr() as *mut c_void, state_size)
+}
+
 // `save_state` saves the current state of the emulator to a file.
 pub unsafe fn save_state(
     core_api: &CoreAPI,
-    save_directory: &String,
-    rom_name: &String,
-    save_index: &u8,
+    save_directory: &str,
+    rom_name: &str,
+    content_crc32: Option<u32>,
+    save_index: u8,
 ) {
     let save_state_buffer_size = (core_api.retro_serialize_size)();
-    let mut state_buffer: Vec<u8> = vec![0; save_state_buffer_size];
-    // Call retro_serialize to create the save state
-    (core_api.retro_serialize)(
-        state_buffer.as_mut_ptr() as *mut c_void,
-        save_state_buffer_size,
-    );
+    if save_state_buffer_size == 0 {
+        log_warn!("Core does not support save states");
+        return;
+    }
+    let mut state_buffer: Vec<u8> = Vec::new();
+    if !serialize_state(core_api, &mut state_buffer) {
+        log_warn!("Core failed to serialize save state");
+        return;
+    }
 
-    let file_path = get_save_state_path(save_directory, &rom_name, &save_index).unwrap();
+    let file_path = match get_save_state_path(save_directory, rom_name, content_crc32, save_index) {
+        Some(file_path) => file_path,
+        None => return,
+    };
 
-    std::fs::write(&file_path, &state_buffer).unwrap();
-    println!(
-        "Save state saved to: {} with size: {}",
-        file_path.display(),
-        save_state_buffer_size
-    );
+    match std::fs::write(&file_path, &state_buffer) {
+        Ok(_) => log_info!(
+            "Save state saved to: {} with size: {}",
+            file_path.display(),
+            save_state_buffer_size
+        ),
+        Err(err) => log_warn!("Error writing save state file: {}", err),
+    }
 }
 
 // `load_state` loads the emulator state from a file.
 pub unsafe fn load_state(
     core_api: &CoreAPI,
-    save_directory: &String,
-    rom_name: &String,
-    save_index: &u8,
+    save_directory: &str,
+    rom_name: &str,
+    content_crc32: Option<u32>,
+    save_index: u8,
 ) {
-    let file_path = get_save_state_path(save_directory, &rom_name, &save_index).unwrap();
+    let file_path = match get_save_state_path(save_directory, rom_name, content_crc32, save_index) {
+        Some(file_path) => file_path,
+        None => return,
+    };
 
     let mut state_buffer = Vec::new();
     match File::open(&file_path) {
@@ -274,84 +1384,461 @@ pub unsafe fn load_state(
                     // Call retro_unserialize to apply the save state
                     let result = (core_api.retro_unserialize)(
                         state_buffer.as_mut_ptr() as *mut c_void,
-                        state_buffer.len() as usize,
+                        state_buffer.len(),
                     );
                     if result {
-                        println!("Save state loaded from: {}", &file_path.display());
+                        log_info!("Save state loaded from: {}", &file_path.display());
                     } else {
-                        println!("Failed to load save state: error code {}", result);
+                        log_warn!("Core rejected save state: {}", &file_path.display());
                     }
                 }
-                Err(err) => println!("Error reading save state file: {}", err),
+                Err(err) => log_warn!("Error reading save state file: {}", err),
             }
         }
-        Err(_) => println!("Save state file not found"),
+        Err(_) => log_warn!("Save state file not found"),
     }
 }
 
-// `get_retroarch_config_path` finds the path to the RetroArch configuration.
-fn get_retroarch_config_path() -> PathBuf {
-    return match std::env::consts::OS {
-        "windows" => PathBuf::from(env::var("APPDATA").ok().unwrap()).join("retroarch"),
-        "macos" => PathBuf::from(env::var("HOME").ok().unwrap())
-            .join("Library/Application Support/RetroArch"),
-        _ => PathBuf::from(env::var("XDG_CONFIG_HOME").ok().unwrap()).join("retroarch"),
+// Holds recent snapshots of the core so gameplay can be stepped backwards.
+// The number of snapshots is bounded by a memory budget, so cores with large states
+// simply get a shorter rewind window.
+pub struct RewindBuffer {
+    states: VecDeque<Vec<u8>>,
+    capacity: usize,  // Most snapshots the memory budget allows
+    granularity: u32, // Frames between snapshots
+    frame_counter: u32,
+}
+
+impl RewindBuffer {
+    // Sizes the buffer for the loaded game. Returns None when the core can't serialize
+    // or a single snapshot wouldn't fit in the budget.
+    pub unsafe fn new(core_api: &CoreAPI, memory_budget: usize, granularity: u32) -> Option<Self> {
+        let state_size = (core_api.retro_serialize_size)();
+        if state_size == 0 {
+            log_warn!("Core does not support save states, rewind disabled");
+            return None;
+        }
+        let capacity = memory_budget / state_size;
+        if capacity == 0 {
+            log_warn!(
+                "Rewind buffer of {} bytes can't hold a {} byte state, rewind disabled",
+                memory_budget,
+                state_size
+            );
+            return None;
+        }
+        log_info!(
+            "Rewind enabled, keeping up to {} states of {} bytes",
+            capacity,
+            state_size
+        );
+        Some(RewindBuffer {
+            states: VecDeque::with_capacity(capacity),
+            capacity,
+            granularity: granularity.max(1),
+            frame_counter: 0,
+        })
+    }
+
+    // Snapshots the core every `granularity` frames, dropping the oldest state when full.
+    pub unsafe fn capture(&mut self, core_api: &CoreAPI) {
+        self.frame_counter += 1;
+        if self.frame_counter < self.granularity {
+            return;
+        }
+        self.frame_counter = 0;
+
+        // Reuse the oldest snapshot's allocation once the buffer is full
+        let mut state_buffer = if self.states.len() >= self.capacity {
+            self.states.pop_front().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        if serialize_state(core_api, &mut state_buffer) {
+            self.states.push_back(state_buffer);
+        }
+    }
+
+    // Restores the most recent snapshot. Returns false once there is nothing left to rewind.
+    pub unsafe fn rewind(&mut self, core_api: &CoreAPI) -> bool {
+        let Some(mut state_buffer) = self.states.pop_back() else {
+            return false;
+        };
+        self.frame_counter = 0;
+        let restored = (core_api.retro_unserialize)(
+            state_buffer.as_mut_ptr() as *mut c_void,
+            state_buffer.len(),
+        );
+        // Keep the oldest state around so holding rewind parks on it instead of running on
+        if self.states.is_empty() {
+            self.states.push_back(state_buffer);
+        }
+        restored
+    }
+}
+
+// `get_sram_path` computes the path for the battery save file, kept next to the ROM.
+fn get_sram_path(rom_name: &str) -> PathBuf {
+    Path::new(rom_name).with_extension("srm")
+}
+
+// `save_sram` writes the core's save RAM to a `.srm` file next to the ROM.
+pub unsafe fn save_sram(core_api: &CoreAPI, rom_name: &str) {
+    let sram_size = (core_api.retro_get_memory_size)(MEMORY_SAVE_RAM);
+    let sram_data = (core_api.retro_get_memory_data)(MEMORY_SAVE_RAM);
+    if sram_size == 0 || sram_data.is_null() {
+        return;
+    }
+
+    let sram = std::slice::from_raw_parts(sram_data as *const u8, sram_size);
+    let file_path = get_sram_path(rom_name);
+    match std::fs::write(&file_path, sram) {
+        Ok(_) => log_info!(
+            "SRAM saved to: {} with size: {}",
+            file_path.display(),
+            sram_size
+        ),
+        Err(err) => log_warn!("Error writing SRAM file: {}", err),
+    }
+}
+
+// `load_sram` restores the core's save RAM from the `.srm` file next to the ROM.
+pub unsafe fn load_sram(core_api: &CoreAPI, rom_name: &str) {
+    let sram_size = (core_api.retro_get_memory_size)(MEMORY_SAVE_RAM);
+    let sram_data = (core_api.retro_get_memory_data)(MEMORY_SAVE_RAM);
+    if sram_size == 0 || sram_data.is_null() {
+        return;
+    }
+
+    let file_path = get_sram_path(rom_name);
+    let contents = match fs::read(&file_path) {
+        Ok(contents) => contents,
+        Err(_) => return,
     };
+    if contents.len() != sram_size {
+        log_warn!(
+            "SRAM file size {} doesn't match the core's save RAM size {}",
+            contents.len(),
+            sram_size
+        );
+    }
+
+    // Never copy more than the core's save RAM can hold
+    let copy_size = contents.len().min(sram_size);
+    ptr::copy_nonoverlapping(contents.as_ptr(), sram_data as *mut u8, copy_size);
+    log_info!("SRAM loaded from: {}", file_path.display());
+}
+
+// `get_retroarch_config_path` finds the path to the RetroArch configuration.
+// Returns None when the environment doesn't tell us where it would be.
+fn get_retroarch_config_path() -> Option<PathBuf> {
+    match std::env::consts::OS {
+        "windows" => env::var("APPDATA")
+            .ok()
+            .map(|app_data| PathBuf::from(app_data).join("retroarch")),
+        "macos" => env::var("HOME")
+            .ok()
+            .map(|home| PathBuf::from(home).join("Library/Application Support/RetroArch")),
+        _ => env::var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .ok()
+            .map(|config_home| config_home.join("retroarch")),
+    }
+}
+
+// Settings grouped under `[section]` headers, keyed by the section name.
+type ConfigSections = HashMap<String, HashMap<String, String>>;
+
+// Returns the section name if `line` is a `[section]` header.
+fn section_header(line: &str) -> Option<&str> {
+    line.trim()
+        .strip_prefix('[')?
+        .strip_suffix(']')
+        .map(str::trim)
 }
 
 // `parse_retroarch_config` parses the RetroArch configuration file.
-fn parse_retroarch_config(config_file: &Path) -> Result<HashMap<String, String>, String> {
+// Settings before the first `[section]` header are returned on their own, the ones after
+// it grouped by section, so e.g. `[core:snes9x]` can hold overrides for one core.
+pub fn parse_retroarch_config(
+    config_file: &Path,
+) -> Result<(HashMap<String, String>, ConfigSections), String> {
     let file = File::open(config_file).map_err(|e| format!("Failed to open file: {}", e))?;
     let reader = BufReader::new(file);
     let mut config_map = HashMap::new();
-    for line in reader.lines() {
+    let mut sections = ConfigSections::new();
+    let mut current_section: Option<String> = None;
+    for (line_number, line) in reader.lines().enumerate() {
         let line = line.map_err(|e| format!("Failed to read line: {}", e))?;
-        if let Some((key, value)) = line.split_once("=") {
-            config_map.insert(
-                key.trim().to_string(),
-                value.trim().replace("\"", "").to_string(),
-            );
+        let trimmed_line = line.trim();
+        // Skip blank lines and comments
+        if trimmed_line.is_empty() || trimmed_line.starts_with('#') {
+            continue;
+        }
+        if let Some(section) = section_header(trimmed_line) {
+            current_section = Some(section.to_ascii_lowercase());
+            continue;
         }
+        match trimmed_line.split_once('=') {
+            Some((key, value)) => {
+                let values = match &current_section {
+                    Some(section) => sections.entry(section.clone()).or_default(),
+                    None => &mut config_map,
+                };
+                values.insert(key.trim().to_string(), value.trim().replace('"', ""));
+            }
+            None => {
+                return Err(format!(
+                    "Malformed line {} in {}, expected `key = value`: {}",
+                    line_number + 1,
+                    config_file.display(),
+                    trimmed_line
+                ))
+            }
+        }
+    }
+    Ok((config_map, sections))
+}
+
+// Name a core's settings section goes by, e.g. `snes9x` for `cores/snes9x_libretro.so`.
+pub fn core_name(library_path: &str) -> String {
+    let file_stem = Path::new(library_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    file_stem
+        .strip_suffix("_libretro")
+        .unwrap_or(&file_stem)
+        .to_string()
+}
+
+// `save_config_values` writes `values` into our config file, replacing the lines that
+// already set those keys and appending the rest, so the user's other settings survive.
+// Only the settings before the first section are touched, leaving per-core ones alone.
+pub fn save_config_values(
+    config_path: Option<&str>,
+    values: &HashMap<String, String>,
+) -> Result<(), String> {
+    let config_path = Path::new(config_path.unwrap_or("./rustroarch.cfg"));
+    let existing = match fs::read_to_string(config_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Failed to read {}: {}", config_path.display(), e)),
+    };
+
+    let mut remaining = values.clone();
+    let mut in_section = false;
+    let mut lines: Vec<String> = existing
+        .lines()
+        .map(|line| {
+            in_section = in_section || section_header(line).is_some();
+            if in_section || line.trim_start().starts_with('#') {
+                return line.to_string();
+            }
+            let key = line.split_once('=').map(|(key, _)| key.trim());
+            match key.and_then(|key| remaining.remove_entry(key)) {
+                Some((key, value)) => format!("{} = \"{}\"", key, value),
+                None => line.to_string(),
+            }
+        })
+        .collect();
+    let mut appended: Vec<_> = remaining.into_iter().collect();
+    appended.sort();
+    // New settings go in ahead of the first section, or they'd only apply to that core
+    let first_section = lines
+        .iter()
+        .position(|line| section_header(line).is_some())
+        .unwrap_or(lines.len());
+    lines.splice(
+        first_section..first_section,
+        appended
+            .into_iter()
+            .map(|(key, value)| format!("{} = \"{}\"", key, value)),
+    );
+
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    fs::write(config_path, contents)
+        .map_err(|e| format!("Failed to write {}: {}", config_path.display(), e))
+}
+
+// Settings as (key, value) pairs.
+type ConfigDefaults = &'static [(&'static str, &'static str)];
+
+// Every setting we read, with the value used when no config file sets it.
+const DEFAULT_CONFIG: ConfigDefaults = &[
+    ("input_player1_a", "a"),
+    ("input_player1_b", "s"),
+    ("input_player1_x", "z"),
+    ("input_player1_y", "x"),
+    ("input_player1_l", "q"),
+    ("input_player1_r", "w"),
+    ("input_player1_down", "down"),
+    ("input_player1_up", "up"),
+    ("input_player1_left", "left"),
+    ("input_player1_right", "right"),
+    ("input_player1_select", "space"),
+    ("input_player1_start", "enter"),
+    ("input_reset", "h"),
+    ("input_enable_hotkey", "nul"),
+    ("input_save_state", "f2"),
+    ("input_load_state", "f4"),
+    ("input_screenshot", "f8"),
+    // Unbound by default, it's only for looking into conversion bugs
+    ("input_dump_frame", "nul"),
+    // Cycles through core_switch_list, comma-separated core names or paths
+    ("input_core_switch", "nul"),
+    ("core_switch_list", ""),
+    ("input_scale_mode_cycle", "f6"),
+    ("input_pause_toggle", "p"),
+    ("input_frame_advance", "period"),
+    ("input_hold_fast_forward", "l"),
+    ("input_volume_up", "numpadadd"),
+    ("input_volume_down", "numpadsubtract"),
+    ("input_audio_mute", "m"),
+    ("fastforward_ratio", "0.0"),
+    ("pause_nonactive", "false"),
+    ("audio_rate_control_delta", "0.005"),
+    ("audio_core_channels", "2"),
+    ("audio_device", ""),
+    ("audio_latency", "64"),
+    ("audio_buffer_pool_size", "20"),
+    ("audio_float_output", "false"),
+    ("audio_sync_source", "video"),
+    ("last_rom", ""),
+    ("savestate_directory", "./states"),
+    ("savestate_content_hash", "false"),
+    ("system_directory", "./system"),
+    ("libretro_directory", "./cores"),
+    ("savefile_directory", "./saves"),
+    ("screenshot_directory", "./screenshots"),
+    ("video_scale_mode", "aspect"),
+    ("video_pixel_aspect", "core"),
+    ("video_crop_top", "0"),
+    ("video_crop_bottom", "0"),
+    ("video_crop_left", "0"),
+    ("video_crop_right", "0"),
+    ("video_smooth", "false"),
+    ("video_shader", ""),
+    ("video_shader_enable", "false"),
+    ("video_scale", "3"),
+    ("video_fullscreen", "false"),
+    ("input_smooth_toggle", "f7"),
+    ("input_shader_toggle", "j"),
+    ("video_present_mode", "auto_vsync"),
+    ("video_vrr_nonstandard_rates", "2"),
+    ("input_present_mode_cycle", "f5"),
+    ("input_analog_deadzone", "0.15"),
+    ("input_turbo_period", "6"),
+    ("input_poll_type_behavior", "2"),
+    ("video_frameskip", "0"),
+    // Frames hidden after the game starts, for cores that draw garbage while booting
+    ("video_startup_blank_frames", "0"),
+    ("video_1555_channel_order", "rgb"),
+    ("video_precise_pacing", "false"),
+    ("close_on_core_shutdown", "true"),
+    ("video_swap_interval", "auto"),
+    ("video_black_frame_insertion", "false"),
+    ("libretro_log_level", "1"),
+    ("input_rewind", "r"),
+    ("input_rebind", "f9"),
+    ("input_disk_eject_toggle", "f10"),
+    ("input_disk_next", "f11"),
+    ("input_disk_prev", "f12"),
+    ("input_perf_overlay_toggle", "f3"),
+    ("input_grab_mouse_toggle", "f1"),
+    ("input_cheat_index_plus", "y"),
+    ("input_cheat_index_minus", "t"),
+    ("input_cheat_toggle", "u"),
+    ("input_cheat_reload", "i"),
+    ("input_rotate_display", "o"),
+    ("rewind_enable", "false"),
+    ("rewind_granularity", "1"),
+    ("rewind_buffer_size_mb", "20"),
+];
+
+// Defaults that suit the systems certain cores emulate, over the general ones but under
+// any config file. NES games scroll garbage into the border a TV would have hidden.
+const CORE_DEFAULT_CONFIG: &[(&[&str], ConfigDefaults)] = &[(
+    &["fceumm", "nestopia", "mesen", "quicknes"],
+    &[
+        ("video_crop_top", "8"),
+        ("video_crop_bottom", "8"),
+        ("video_crop_left", "8"),
+        ("video_crop_right", "8"),
+    ],
+)];
+
+pub fn default_config() -> HashMap<String, String> {
+    DEFAULT_CONFIG
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+// Checks that `config` has every setting we read, so a hand-built config fails up front
+// instead of panicking on the first missing key. Lists all missing settings with their
+// defaults, ready to paste into a config file.
+pub fn validate_config(config: &HashMap<String, String>) -> Result<(), String> {
+    let missing: Vec<String> = DEFAULT_CONFIG
+        .iter()
+        .filter(|(key, _)| !config.contains_key(*key))
+        .map(|(key, value)| format!("  {} = \"{}\"", key, value))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
     }
-    Ok(config_map)
+    Err(format!(
+        "The config is missing {} setting(s), which default to:\n{}\n\
+         Start from libretro::default_config() or libretro::setup_config() to get them all",
+        missing.len(),
+        missing.join("\n")
+    ))
 }
 
 // `setup_config` merges various configuration sources into a single HashMap.
-pub fn setup_config() -> Result<HashMap<String, String>, String> {
-    let retro_arch_config_path = get_retroarch_config_path();
-    let our_config = parse_retroarch_config(Path::new("./rustroarch.cfg"));
-    let retro_arch_config =
-        parse_retroarch_config(&retro_arch_config_path.join("config/retroarch.cfg"));
-    let mut merged_config: HashMap<String, String> = HashMap::from([
-        ("input_player1_a", "a"),
-        ("input_player1_b", "s"),
-        ("input_player1_x", "z"),
-        ("input_player1_y", "x"),
-        ("input_player1_l", "q"),
-        ("input_player1_r", "w"),
-        ("input_player1_down", "down"),
-        ("input_player1_up", "up"),
-        ("input_player1_left", "left"),
-        ("input_player1_right", "right"),
-        ("input_player1_select", "space"),
-        ("input_player1_start", "enter"),
-        ("input_reset", "h"),
-        ("input_save_state", "f2"),
-        ("input_load_state", "f4"),
-        ("input_screenshot", "f8"),
-        ("savestate_directory", "./states"),
-    ])
-    .iter()
-    .map(|(k, v)| (k.to_string(), v.to_string()))
-    .collect();
+// A config path given explicitly must exist and parse, while the default one is optional.
+// Settings in our config's `[core:<core_name>]` section override everything else.
+pub fn setup_config(
+    config_path: Option<&str>,
+    core_name: &str,
+) -> Result<HashMap<String, String>, String> {
+    let our_config = match config_path {
+        Some(config_path) => Ok(parse_retroarch_config(Path::new(config_path))
+            .map_err(|e| format!("Could not load config file {}: {}", config_path, e))?),
+        None => parse_retroarch_config(Path::new("./rustroarch.cfg")),
+    };
+    let retro_arch_config = match get_retroarch_config_path() {
+        Some(retro_arch_config_path) => {
+            parse_retroarch_config(&retro_arch_config_path.join("config/retroarch.cfg"))
+        }
+        None => Err("RetroArch config directory is unknown".to_string()),
+    };
+    let mut merged_config = default_config();
+    for (core_names, core_defaults) in CORE_DEFAULT_CONFIG {
+        if core_names.contains(&core_name) {
+            merged_config.extend(
+                core_defaults
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string())),
+            );
+        }
+    }
     match retro_arch_config {
-        Ok(config) => merged_config.extend(config),
-        _ => println!("We don't have RetroArch config"),
+        Ok((config, _)) => merged_config.extend(config),
+        _ => log_info!("We don't have RetroArch config"),
     }
     match our_config {
-        Ok(config) => merged_config.extend(config),
-        _ => println!("We don't have RustroArch config",),
+        Ok((config, mut sections)) => {
+            merged_config.extend(config);
+            let core_section = format!("core:{}", core_name);
+            if let Some(core_config) = sections.remove(&core_section) {
+                log_info!("Using the [{}] settings", core_section);
+                merged_config.extend(core_config);
+            }
+        }
+        _ => log_info!("We don't have RustroArch config",),
     }
-    // println!("retro_arch_config_path: {} merged_config: {:?}", retro_arch_config_path.join("config/retroarch.cfg").display(), merged_config);
     Ok(merged_config)
 }
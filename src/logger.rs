@@ -0,0 +1,81 @@
+// This implementation is based on the guide provided by [RetroGameDeveloper/RetroReversing].
+// Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
+// Copyright (c) 2023 Nicholas Ricciuti
+//
+// logger.rs
+//
+// This module provides a small leveled logger shared by the frontend and the core.
+// Messages below the configured minimum level (`libretro_log_level`) are dropped. The
+// macros are exported so the frontend in main.rs logs the same way.
+
+use libretro_sys::LogLevel;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Least severe level that still gets printed, stored as the libretro level number.
+static MIN_LOG_LEVEL: AtomicU32 = AtomicU32::new(LogLevel::Info as u32);
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => { $crate::logger::log(libretro_sys::LogLevel::Debug, format_args!($($arg)*)) };
+}
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => { $crate::logger::log(libretro_sys::LogLevel::Info, format_args!($($arg)*)) };
+}
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => { $crate::logger::log(libretro_sys::LogLevel::Warn, format_args!($($arg)*)) };
+}
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => { $crate::logger::log(libretro_sys::LogLevel::Error, format_args!($($arg)*)) };
+}
+
+// Reads the minimum level from the config, either as RetroArch's number (0 = debug
+// through 3 = error) or by name.
+pub fn set_min_level(config: &HashMap<String, String>) {
+    let level = match config["libretro_log_level"]
+        .trim()
+        .to_ascii_lowercase()
+        .as_str()
+    {
+        "0" | "debug" => LogLevel::Debug,
+        "1" | "info" => LogLevel::Info,
+        "2" | "warn" => LogLevel::Warn,
+        "3" | "error" => LogLevel::Error,
+        other => {
+            eprintln!("[WARN] Unknown libretro_log_level '{}', using info", other);
+            LogLevel::Info
+        }
+    };
+    MIN_LOG_LEVEL.store(level as u32, Ordering::Relaxed);
+}
+
+// Prints a message if its level is at or above the minimum. Warnings and errors go to stderr.
+pub fn log(level: LogLevel, message: fmt::Arguments) {
+    if (level as u32) < MIN_LOG_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+    match level {
+        LogLevel::Debug => println!("[DEBUG] {}", message),
+        LogLevel::Info => println!("[INFO] {}", message),
+        LogLevel::Warn => eprintln!("[WARN] {}", message),
+        LogLevel::Error => eprintln!("[ERROR] {}", message),
+    }
+}
+
+// Log callback handed to cores through ENVIRONMENT_GET_LOG_INTERFACE.
+// The libretro signature is printf-style and variadic, which stable Rust can't read,
+// so the format string is logged as the core wrote it.
+pub unsafe extern "C" fn libretro_log_callback(level: libc::c_uint, fmt: *const libc::c_char) {
+    if fmt.is_null() {
+        return;
+    }
+    let message = CStr::from_ptr(fmt).to_string_lossy();
+    // Unknown levels are treated as errors so they're never silently dropped
+    let level = LogLevel::from_uint(level).unwrap_or(LogLevel::Error);
+    log(level, format_args!("[core] {}", message.trim_end()));
+}
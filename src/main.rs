@@ -2,188 +2,824 @@
 // Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
 // Copyright (c) 2023 Nicholas Ricciuti
 
-// Import necessary modules from other files and crates
-mod audio;
-mod input;
-mod libretro;
-mod video;
-use audio::AudioBuffer;
-//use gilrs::{Event as gEvent, GamepadId, Gilrs};
-use libretro_sys::PixelFormat;
-use once_cell::sync::Lazy;
+// The windowed frontend: draws the emulator's frames, plays its audio and feeds it input
+use gilrs::{Event as gEvent, EventType, GamepadId, Gilrs};
+use libretro_sys::GameGeometry;
 use pixels::wgpu::PresentMode;
-use pixels::PixelsBuilder;
-use pixels::SurfaceTexture;
 use rodio::{OutputStream, Sink};
+use rustretro::{audio, benchmark, input, libretro, overlay, picker, renderer, video};
+use rustretro::{log_error, log_info, log_warn};
+use rustretro::{Emulator, EmulatorState};
+use rustretro::{ANALOG_STATE, BUTTONS_PRESSED, FAST_FORWARDING, FINAL_SAMPLE_RATE};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::atomic::AtomicU32;
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use winit::dpi::LogicalSize;
-use winit::event::{Event, WindowEvent};
+use winit::dpi::{PhysicalPosition, PhysicalSize};
+use winit::event::{DeviceEvent, Event, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
-use winit::window::WindowBuilder;
-
-// Define global static variables for handling input, pixel format, video, and audio data
-static BUTTONS_PRESSED: Lazy<Mutex<(Vec<i16>, Vec<i16>)>> =
-    Lazy::new(|| Mutex::new((vec![0; 16], vec![0; 16])));
-static PIXEL_FORMAT_CHANNEL: Lazy<(Sender<PixelFormat>, Arc<Mutex<Receiver<PixelFormat>>>)> =
-    Lazy::new(|| {
-        let (sender, receiver) = channel::<PixelFormat>();
-        (sender, Arc::new(Mutex::new(receiver)))
-    });
-static VIDEO_DATA_CHANNEL: Lazy<(Sender<VideoData>, Arc<Mutex<Receiver<VideoData>>>)> =
-    Lazy::new(|| {
-        let (sender, receiver) = channel::<VideoData>();
-        (sender, Arc::new(Mutex::new(receiver)))
+use winit::monitor::MonitorHandle;
+use winit::window::{CursorGrabMode, Fullscreen, WindowBuilder};
+
+// Reasons the frontend can fail to start.
+#[derive(Debug)]
+enum StartupError {
+    // The config file couldn't be read or has a bad setting, or the core or game couldn't
+    // be loaded.
+    Emulator(String),
+    // There is no display to open the window on.
+    NoMonitor,
+    Window(winit::error::OsError),
+    Surface(pixels::Error),
+    // The default audio output couldn't be opened, with rodio's error.
+    NoAudioDevice(String),
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StartupError::Emulator(err) => write!(f, "{}", err),
+            StartupError::NoMonitor => write!(
+                f,
+                "No display found, the emulator needs one to open its window (--benchmark runs without)"
+            ),
+            StartupError::Window(err) => write!(f, "Failed to create the window: {}", err),
+            StartupError::Surface(err) => write!(
+                f,
+                "Failed to set up the GPU for the window, check the graphics drivers: {}",
+                err
+            ),
+            StartupError::NoAudioDevice(err) => write!(
+                f,
+                "No audio device found ({}), run with --no-audio to play without sound",
+                err
+            ),
+        }
+    }
+}
+
+// Lists the displays and picks the one to play on: the one at `index` if given, otherwise
+// the primary one. Wayland has no primary monitor, so fall back to any monitor there is.
+fn select_monitor(
+    event_loop: &EventLoop<()>,
+    index: Option<usize>,
+) -> Result<MonitorHandle, StartupError> {
+    let monitors: Vec<MonitorHandle> = event_loop.available_monitors().collect();
+    for (monitor_index, monitor) in monitors.iter().enumerate() {
+        let size = monitor.size();
+        log_info!(
+            "Monitor {}: {} {}x{}",
+            monitor_index,
+            monitor.name().unwrap_or_else(|| "unnamed".to_string()),
+            size.width,
+            size.height
+        );
+    }
+    if let Some(index) = index {
+        match monitors.get(index) {
+            Some(monitor) => return Ok(monitor.clone()),
+            None => log_warn!("There's no monitor {}, using the primary one", index),
+        }
+    }
+    event_loop
+        .primary_monitor()
+        .or_else(|| monitors.into_iter().next())
+        .ok_or(StartupError::NoMonitor)
+}
+
+// Paces the main loop for the present mode and the core's timing, and stretches the core's
+// audio rate to match.
+fn apply_frame_pacing(
+    present_mode: PresentMode,
+    monitor_refresh_rate_hz: f64,
+    core_fps: f64,
+    core_sample_rate: f64,
+    is_vrr_ready: bool,
+    pacing_overrides: video::PacingOverrides,
+) -> video::FramePacing {
+    let pacing = video::frame_pacing(
+        video::is_vsync(present_mode),
+        monitor_refresh_rate_hz,
+        core_fps,
+        is_vrr_ready,
+        pacing_overrides,
+    );
+    FINAL_SAMPLE_RATE.store(
+        (core_sample_rate * pacing.sample_rate_factor).round() as u32,
+        Ordering::SeqCst,
+    );
+    pacing
+}
+
+// The display aspect ratio the core reports, or the one its pixels have if it reports none.
+// A configured pixel aspect ratio replaces the core's, stretching the base resolution.
+// Cropping keeps the pixels' shape, so the picture narrows or widens with the crop.
+fn display_aspect_ratio(
+    geometry: &GameGeometry,
+    pixel_aspect: video::PixelAspect,
+    crop: video::Crop,
+) -> f64 {
+    let square_pixels = geometry.base_width as f64 / geometry.base_height.max(1) as f64;
+    let aspect_ratio = match pixel_aspect {
+        video::PixelAspect::Ratio(ratio) => square_pixels * ratio,
+        video::PixelAspect::Core if geometry.aspect_ratio > 0.0 => geometry.aspect_ratio as f64,
+        video::PixelAspect::Core => square_pixels,
+    };
+    crop.cropped_aspect_ratio(aspect_ratio, geometry.base_width, geometry.base_height)
+}
+
+// Lists the ROMs in the directory given as the ROM and lets the player choose one.
+fn pick_rom(
+    event_loop: &mut EventLoop<()>,
+    state: &EmulatorState,
+    config: &HashMap<String, String>,
+) -> Result<Option<PathBuf>, StartupError> {
+    let directory = Path::new(&state.rom_name);
+    let library_path = libretro::resolve_core_path(&state.library_name, config)
+        .map_err(|err| StartupError::Emulator(err.to_string()))?;
+    let extensions = unsafe { libretro::core_valid_extensions(&library_path) }
+        .map_err(|err| StartupError::Emulator(err.to_string()))?;
+    let roms = picker::list_roms(directory, &extensions).map_err(|err| {
+        StartupError::Emulator(format!("Failed to read {}: {}", directory.display(), err))
+    })?;
+    if roms.is_empty() {
+        return Err(StartupError::Emulator(format!(
+            "No ROMs the core can load in {}",
+            directory.display()
+        )));
+    }
+    let last_rom = Some(Path::new(&config["last_rom"])).filter(|path| !path.as_os_str().is_empty());
+    picker::pick_rom(event_loop, directory, &roms, last_rom).map_err(StartupError::Emulator)
+}
+
+// The core after the running one in `core_switch_list`, wrapping around, or the first one
+// listed if the running core isn't. None if there's no other core to switch to.
+fn next_core(emulator: &Emulator) -> Option<String> {
+    let cores: Vec<&str> = emulator.config["core_switch_list"]
+        .split(',')
+        .map(str::trim)
+        .filter(|core| !core.is_empty())
+        .collect();
+    let running = cores.iter().position(|core| {
+        libretro::resolve_core_path(core, &emulator.config)
+            .ok()
+            .as_deref()
+            == Some(emulator.state.library_name.as_str())
     });
-static AUDIO_DATA_CHANNEL: Lazy<(
-    Sender<Arc<Mutex<AudioBuffer>>>,
-    Arc<Mutex<Receiver<Arc<Mutex<AudioBuffer>>>>>,
-)> = Lazy::new(|| {
-    let (sender, receiver) = channel::<Arc<Mutex<AudioBuffer>>>();
-    (sender, Arc::new(Mutex::new(receiver)))
-});
-static TARGET_FPS: AtomicU32 = AtomicU32::new(0);
-
-// Structure to hold video data
-struct VideoData {
-    frame_buffer: Vec<u8>,
-    pitch: u32,
+    match running {
+        Some(_) if cores.len() == 1 => None,
+        Some(index) => Some(cores[(index + 1) % cores.len()].to_string()),
+        None => cores.first().map(|core| core.to_string()),
+    }
+}
+
+// Stores the game in `last_rom`, so `--last` and the picker can come back to it.
+fn remember_last_rom(emulator: &Emulator) {
+    // Cores started without a game are named after themselves, there's nothing to remember
+    let Ok(rom_path) = Path::new(&emulator.state.rom_name).canonicalize() else {
+        return;
+    };
+    let rom_path = rom_path.to_string_lossy().into_owned();
+    if rom_path == emulator.config["last_rom"] {
+        return;
+    }
+    let values = HashMap::from([("last_rom".to_string(), rom_path)]);
+    if let Err(err) = libretro::save_config_values(emulator.state.config_path.as_deref(), &values) {
+        log_error!("Failed to remember the last ROM: {}", err);
+    }
 }
 
 // The main function, entry point of the application
 fn main() {
+    // Only returns if starting up failed or no game was picked, the event loop exits the
+    // process itself
+    if let Err(err) = run() {
+        log_error!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), StartupError> {
     // Parse command line arguments to get ROM and library names
-    let (rom_name, library_name) = libretro::parse_command_line_arguments();
-    // Initialize emulator state with default values
-    let mut current_state = libretro::EmulatorState {
-        rom_name,
-        library_name,
-        current_save_slot: 0,
-        av_info: None,
-        pixel_format: video::EmulatorPixelFormat(PixelFormat::ARGB8888),
-        bytes_per_pixel: 0,
-    };
+    let mut current_state = libretro::parse_command_line_arguments();
+
+    // Prepare configurations for input handling
+    let core_name = libretro::core_name(&current_state.library_name);
+    let config = libretro::setup_config(current_state.config_path.as_deref(), &core_name)
+        .map_err(StartupError::Emulator)?;
+
+    if current_state.last_rom {
+        if config["last_rom"].is_empty() {
+            return Err(StartupError::Emulator("No ROM has been played yet".into()));
+        }
+        current_state.rom_name = config["last_rom"].clone();
+    }
+    // Given a directory, let the player pick a game from it first. The window needs an event
+    // loop, and as there can only be one it's kept for the game's window afterwards
+    let mut picker_event_loop = None;
+    if Path::new(&current_state.rom_name).is_dir() {
+        let mut event_loop = EventLoop::new();
+        match pick_rom(&mut event_loop, &current_state, &config)? {
+            Some(rom) => current_state.rom_name = rom.to_string_lossy().into_owned(),
+            None => return Ok(()),
+        }
+        picker_event_loop = Some(event_loop);
+    }
 
-    // Initialize the core of the emulator and update the emulator state
-    let (core, updated_state) = libretro::Core::new(current_state);
-    let core = Arc::new(Mutex::new(core));
-    current_state = updated_state;
-    let av_info = &current_state.av_info;
-    let video_width = (av_info.as_ref().unwrap().geometry).base_width;
-    let video_height = (av_info.as_ref().unwrap().geometry).base_height;
-    let mut is_fullscreen = false;
-    let event_loop = EventLoop::new();
+    // Load the core and the game
+    let mut emulator = Emulator::new(current_state, config).map_err(StartupError::Emulator)?;
+
+    // Benchmarks skip the window and audio output entirely, and leave saves alone
+    if let Some(frames) = emulator.state.benchmark_frames {
+        benchmark::run(&emulator, frames);
+        emulator.shutdown(false);
+        process::exit(0);
+    }
+    remember_last_rom(&emulator);
+    let av_info = &emulator.state.av_info;
+    let mut geometry = av_info
+        .as_ref()
+        .map(|av_info| av_info.geometry.clone())
+        .ok_or_else(|| StartupError::Emulator("The core didn't report its video size".into()))?;
+    // Cores for vertical games ask for rotation while loading, frames arrive turned already
+    let crop = video::Crop::from_config(&emulator.config);
+    let (cropped_width, cropped_height) =
+        crop.cropped_size(geometry.base_width, geometry.base_height);
+    let (mut video_width, mut video_height) = video::rotated_size(cropped_width, cropped_height);
+    let mut is_fullscreen =
+        emulator.state.fullscreen || emulator.config["video_fullscreen"] == "true";
+    let event_loop = picker_event_loop.unwrap_or_else(EventLoop::new);
 
     // Auto refresh setup
-    let primary_monitor = event_loop.primary_monitor().unwrap();
-    let monitor_refresh_rate_mhz = primary_monitor.refresh_rate_millihertz().unwrap();
-    let monitor_refresh_rate_hz = monitor_refresh_rate_mhz as f64 / 1000.0;
-    let original_framerate = av_info.as_ref().map_or(60.0, |av_info| av_info.timing.fps);
-    let is_vrr_ready = video::is_vrr_ready(&primary_monitor, original_framerate);
-
-    let mut target_fps = monitor_refresh_rate_hz;
-    if is_vrr_ready {
-        target_fps = original_framerate;
+    let monitor = select_monitor(&event_loop, emulator.state.monitor)?;
+    // Virtual displays may report no rate at all, or zero
+    let monitor_refresh_rate_hz = match monitor.refresh_rate_millihertz() {
+        Some(refresh_rate_mhz) if refresh_rate_mhz > 0 => refresh_rate_mhz as f64 / 1000.0,
+        _ => {
+            log_warn!("The display's refresh rate is unknown, assuming 60Hz");
+            60.0
+        }
+    };
+    let mut original_framerate = av_info.as_ref().map_or(60.0, |av_info| av_info.timing.fps);
+    let vrr_nonstandard_rates = emulator.config["video_vrr_nonstandard_rates"]
+        .parse::<usize>()
+        .unwrap_or(2);
+    let mut is_vrr_ready = video::is_vrr_ready(&monitor, original_framerate, vrr_nonstandard_rates);
+
+    let mut present_mode = video::present_mode_from_config(&emulator.config["video_present_mode"])
+        .unwrap_or(PresentMode::AutoVsync);
+    let fps_override = emulator.state.fps.filter(|&fps| fps > 0.0);
+    if let Some(fps) = fps_override {
+        log_warn!(
+            "Running at a forced {} FPS, ignoring the display and VRR",
+            fps
+        );
+    }
+    let pacing_overrides = video::PacingOverrides::from_config(&emulator.config, fps_override);
+    let mut pacing = video::frame_pacing(
+        video::is_vsync(present_mode),
+        monitor_refresh_rate_hz,
+        original_framerate,
+        is_vrr_ready,
+        pacing_overrides,
+    );
+    if pacing_overrides.black_frame_insertion && pacing.black_frames == 0 {
+        log_warn!("Black frame insertion needs a swap interval of 2 or more, leaving it off");
     }
-    let swap_interval = (monitor_refresh_rate_hz / original_framerate).round();
-    let vsync_sample_factor = monitor_refresh_rate_hz / original_framerate;
+    let vsync_sample_factor = pacing.sample_rate_factor;
 
+    // Base resolutions are tiny on modern displays, so start the window scaled up
+    let window_scale = emulator
+        .state
+        .scale
+        .or_else(|| emulator.config["video_scale"].parse::<u32>().ok())
+        .unwrap_or(1)
+        .max(1);
     let window = WindowBuilder::new()
         .with_title("Retro Emulator")
-        .with_inner_size(LogicalSize::new(video_width, video_height))
+        .with_inner_size(LogicalSize::new(
+            video_width * window_scale,
+            video_height * window_scale,
+        ))
+        .with_position(monitor.position())
+        .with_fullscreen(is_fullscreen.then(|| Fullscreen::Borderless(Some(monitor.clone()))))
         .build(&event_loop)
-        .unwrap();
+        .map_err(StartupError::Window)?;
     let window_id: winit::window::WindowId = window.id();
 
-    // use winit::window::Fullscreen;
-    // // Assume `window` is the `winit` window that `pixels` is rendering to.
+    let pixels = video::build_pixels(&window, video_width, video_height, present_mode)
+        .map_err(StartupError::Surface)?;
 
-    let physical_width = video_width;
-    let physical_height = video_height;
+    // Draw the frame with the configured scale mode, keeping the core's aspect ratio
+    let scale_mode = if emulator
+        .config
+        .get("video_scale_integer")
+        .map(String::as_str)
+        == Some("true")
+    {
+        renderer::ScaleMode::Integer
+    } else {
+        renderer::ScaleMode::from_config(&emulator.config["video_scale_mode"])
+            .unwrap_or(renderer::ScaleMode::Aspect)
+    };
+    let pixel_aspect = video::PixelAspect::from_config(&emulator.config["video_pixel_aspect"])
+        .unwrap_or_else(|| {
+            log_warn!(
+                "Invalid video_pixel_aspect '{}', using the core's aspect ratio",
+                emulator.config["video_pixel_aspect"]
+            );
+            video::PixelAspect::Core
+        });
+    let mut core_aspect_ratio = display_aspect_ratio(&geometry, pixel_aspect, crop);
+    let window_size = window.inner_size();
+    let shader_source = renderer::load_shader(&emulator.config["video_shader"]);
+    let mut frame_renderer = renderer::FrameRenderer::new(
+        &pixels,
+        scale_mode,
+        video::rotated_aspect_ratio(core_aspect_ratio),
+        emulator.config["video_smooth"] == "true",
+        (window_size.width, window_size.height),
+        &shader_source,
+        emulator.config["video_shader_enable"] == "true",
+    );
+    let mut frame_converter = video::FrameConverter::spawn(
+        &emulator.state.pixel_format,
+        emulator.state.alpha_mode,
+        crop,
+    );
 
-    let pixels_build_result = PixelsBuilder::new(
-        physical_width,
-        physical_height,
-        SurfaceTexture::new(physical_width, physical_height, &window),
-    )
-    .present_mode(PresentMode::AutoVsync)
-    .build();
+    // Extract the audio sample rate from the emulator state
+    let mut core_sample_rate = av_info
+        .as_ref()
+        .map_or(0.0, |av_info| av_info.timing.sample_rate);
+    let sample_rate = core_sample_rate * vsync_sample_factor;
+    FINAL_SAMPLE_RATE.store(sample_rate.round() as u32, Ordering::SeqCst);
 
-    let mut pixels = pixels_build_result.unwrap();
+    let audio_device_name = emulator
+        .state
+        .audio_device
+        .clone()
+        .unwrap_or_else(|| emulator.config["audio_device"].clone());
+    let audio_rate_control_delta = emulator.config["audio_rate_control_delta"]
+        .parse::<f64>()
+        .unwrap_or(0.005);
 
-    // Extract the audio sample rate from the emulator state
-    let sample_rate = av_info.as_ref().map_or(0.0, |av_info| {
-        av_info.timing.sample_rate * vsync_sample_factor
-    });
-    TARGET_FPS.store(target_fps as u32, Ordering::SeqCst);
-
-    // Spawn a new thread for audio handling
-    let _audio_thread = thread::spawn(move || {
-        println!("Audio Thread Started");
-        let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-        let sink = Sink::try_new(&stream_handle).unwrap();
-        loop {
-            let receiver = AUDIO_DATA_CHANNEL.1.lock().unwrap();
-            // Play audio in a loop
-            for buffer_arc in receiver.try_iter() {
-                let buffer = buffer_arc.lock().unwrap();
-                unsafe {
-                    audio::play_audio(&sink, &*buffer, sample_rate as u32);
+    // Spawn a new thread for audio handling, unless running without sound. The output
+    // stream can't leave the thread that opens it, so the thread reports back whether it could
+    let audio_running = Arc::new(AtomicBool::new(true));
+    let audio_thread_running = Arc::clone(&audio_running);
+    let (audio_started_sender, audio_started) = channel();
+    let audio_thread = (!emulator.state.no_audio).then(|| {
+        thread::spawn(move || {
+            log_info!("Audio Thread Started");
+            let output = audio::output_device(&audio_device_name)
+                .ok_or_else(|| "no output device found".to_string())
+                .and_then(|device| {
+                    let (stream, stream_handle) =
+                        OutputStream::try_from_device(&device).map_err(|err| err.to_string())?;
+                    let sink = Sink::try_new(&stream_handle).map_err(|err| err.to_string())?;
+                    Ok((device, stream, sink))
+                });
+            let (device, _stream, sink) = match output {
+                Ok(output) => {
+                    let _ = audio_started_sender.send(Ok(()));
+                    output
+                }
+                Err(err) => {
+                    let _ = audio_started_sender.send(Err(err));
+                    return;
+                }
+            };
+            // Resample from the core's rate to whatever rate the output device runs at
+            let output_rate = audio::output_sample_rate(&device);
+            let mut resampler = audio::Resampler::new(sample_rate, output_rate);
+            log_info!(
+                "Resampling audio from {}Hz to {}Hz",
+                sample_rate,
+                output_rate
+            );
+            let mut volume = 1.0;
+            let mut rate_control = audio::RateControl::new(audio_rate_control_delta);
+            let mut source_rate = FINAL_SAMPLE_RATE.load(Ordering::SeqCst);
+            while audio_thread_running.load(Ordering::SeqCst) {
+                // Follow sample rate changes, e.g. from turning vsync on or off
+                let new_source_rate = FINAL_SAMPLE_RATE.load(Ordering::SeqCst);
+                if new_source_rate != source_rate {
+                    source_rate = new_source_rate;
+                    resampler = audio::Resampler::new(source_rate as f64, output_rate);
+                }
+
+                // Apply volume changes as soon as they're made, so muting is instant
+                let new_volume = audio::effective_volume();
+                if new_volume != volume {
+                    volume = new_volume;
+                    if !audio::float_output() {
+                        sink.set_volume(volume);
+                    }
+                }
+
+                // Play once enough samples are queued, waiting for the core otherwise. Waking
+                // before the sink runs low tops it up in time even when the core is slow
+                let mut buffer = audio::AUDIO_BUFFER.lock().unwrap();
+                if !audio::ready_to_play(buffer.len(), &rate_control)
+                    && audio_thread_running.load(Ordering::SeqCst)
+                {
+                    buffer = audio::AUDIO_CONDVAR
+                        .wait_timeout(buffer, rate_control.wait_timeout())
+                        .unwrap()
+                        .0;
+                }
+                if audio::ready_to_play(buffer.len(), &rate_control) {
+                    audio::play_audio(
+                        &sink,
+                        &mut buffer,
+                        &mut resampler,
+                        &mut rate_control,
+                        output_rate,
+                        volume,
+                    );
                 }
             }
-        }
+        })
     });
+    let mut audio_thread = match audio_thread {
+        Some(audio_thread) => match audio_started.recv() {
+            Ok(Ok(())) => Some(audio_thread),
+            Ok(Err(err)) => return Err(StartupError::NoAudioDevice(err)),
+            Err(_) => return Err(StartupError::NoAudioDevice("audio thread panicked".into())),
+        },
+        None => {
+            audio::disable_output();
+            None
+        }
+    };
 
-    // Set up libretro callbacks for video, input, and audio
-    unsafe {
-        let core_api = &core.lock().unwrap().api;
-        (core_api.retro_init)();
-        (core_api.retro_set_video_refresh)(video::libretro_set_video_refresh_callback);
-        (core_api.retro_set_input_poll)(input::libretro_set_input_poll_callback);
-        (core_api.retro_set_input_state)(input::libretro_set_input_state_callback);
-        (core_api.retro_set_audio_sample)(audio::libretro_set_audio_sample_callback);
-        (core_api.retro_set_audio_sample_batch)(audio::libretro_set_audio_sample_batch_callback);
-        println!("About to load ROM: {}", &current_state.rom_name);
-        // Load the ROM file
-        libretro::load_rom_file(core_api, &current_state.rom_name);
-    }
+    let mut key_device_map = input::key_device_map(&emulator.config);
+    let mut key_rebinder = input::KeyRebinder::default();
 
-    // Prepare configurations for input handling
-    let config = libretro::setup_config().unwrap();
-    let key_device_map = input::key_device_map(&config);
-    // let joypad_device_map = input::setup_joypad_device_map(&config);
-    // let mut gilrs = Gilrs::new().unwrap(); // Initialize gamepad handling
-    // let mut active_gamepad: Option<GamepadId> = None;
+    let joypad_device_map =
+        input::setup_joypad_device_map(&emulator.config).map_err(StartupError::Emulator)?;
+    // Initialize gamepad handling, falling back to keyboard-only when unavailable
+    let mut gilrs = match Gilrs::new() {
+        Ok(gilrs) => Some(gilrs),
+        Err(err) => {
+            log_warn!("Gamepad support unavailable: {}", err);
+            None
+        }
+    };
+    let mut gamepad_ports: [Option<GamepadId>; input::MAX_PLAYERS] = [None; input::MAX_PLAYERS];
+    let analog_settings = input::analog_settings(&emulator.config);
+
+    // Mouse state, while grabbed the cursor is hidden and held in the window
+    let mut mouse_grabbed = false;
+    let mut cursor_position: Option<PhysicalPosition<f64>> = None;
+
+    // Pause and single-frame stepping state
+    let mut paused = false;
+    let mut frame_advance = false;
+    // Optionally pause and mute while the window is in the background
+    let pause_nonactive = emulator.config["pause_nonactive"] == "true";
+    let mut focus_paused = false;
+    // A core that asked to shut down mustn't run again, even if the window stays open
+    let close_on_core_shutdown = emulator.config["close_on_core_shutdown"] == "true";
+    let mut core_stopped = false;
+
+    // Fast-forward state, a ratio of 0 means run as fast as the host allows
+    let mut fast_forward = false;
+    let fast_forward_ratio = emulator
+        .config
+        .get("fastforward_ratio")
+        .and_then(|ratio| ratio.parse::<f64>().ok())
+        .unwrap_or(0.0);
+    audio::set_fast_forward_speed(fast_forward_ratio);
+
+    let mut perf_overlay = overlay::PerfOverlay::new(original_framerate);
+    // Spinning out the end of each frame's wait is steadier than the OS timer, but busier
+    let precise_pacing = emulator.config["video_precise_pacing"] == "true";
 
     // Main application loop
     let mut last_update = Instant::now();
 
-    // TODO, IMPLEMENT IN AUDIO THREAD
-    let frame_duration = Duration::from_secs_f64(swap_interval / target_fps); // for 60 FPS
+    // Black refreshes still to present before the core's next frame
+    let mut black_frames_left = 0;
+
+    // Held in an option because changing the present mode has to drop the old surface
+    // before a new one can be created for the window
+    let mut pixels_slot = Some(pixels);
+    let mut pending_present_mode: Option<PresentMode> = None;
 
     event_loop.run(move |event, _, control_flow| {
-        *control_flow = ControlFlow::WaitUntil(last_update + frame_duration);
+        // Every way out of the event loop ends up here, so shut down before anything else
+        if let Event::LoopDestroyed = event {
+            emulator.shutdown(true);
+            // Signal under the buffer lock, so the wakeup can't fall between the audio
+            // thread checking the flag and starting to wait
+            let buffer = audio::AUDIO_BUFFER.lock().unwrap();
+            audio_running.store(false, Ordering::SeqCst);
+            audio::AUDIO_CONDVAR.notify_one();
+            drop(buffer);
+            if let Some(audio_thread) = audio_thread.take() {
+                if audio_thread.join().is_err() {
+                    log_error!("Audio thread panicked");
+                }
+            }
+            if !key_rebinder.changed.is_empty() {
+                if let Err(err) = libretro::save_config_values(
+                    emulator.state.config_path.as_deref(),
+                    &key_rebinder.changed,
+                ) {
+                    log_error!("Failed to save key bindings: {}", err);
+                }
+            }
+            frame_converter.shutdown();
+            return;
+        }
+
+        if let Some(new_present_mode) = pending_present_mode.take() {
+            let old_frame = pixels_slot.take().map(|pixels| pixels.frame().to_vec());
+            let mut rebuilt =
+                video::build_pixels(&window, video_width, video_height, new_present_mode);
+            if let Err(err) = &rebuilt {
+                log_error!("Failed to switch to {:?}: {}", new_present_mode, err);
+                rebuilt = video::build_pixels(&window, video_width, video_height, present_mode);
+            } else {
+                present_mode = new_present_mode;
+            }
+            let Ok(mut pixels) = rebuilt else {
+                log_error!("Failed to recreate the window surface");
+                *control_flow = ControlFlow::Exit;
+                return;
+            };
+            if let Some(old_frame) = old_frame {
+                pixels.frame_mut().copy_from_slice(&old_frame);
+            }
+            let window_size = window.inner_size();
+            frame_renderer = renderer::FrameRenderer::new(
+                &pixels,
+                frame_renderer.scale_mode,
+                frame_renderer.aspect_ratio,
+                frame_renderer.smooth(),
+                (window_size.width, window_size.height),
+                &shader_source,
+                frame_renderer.shader_enabled,
+            );
+
+            // Without vsync the loop no longer runs at the monitor's rate, so the pacing
+            // and the audio rate stretched to it have to follow
+            pacing = apply_frame_pacing(
+                present_mode,
+                monitor_refresh_rate_hz,
+                original_framerate,
+                core_sample_rate,
+                is_vrr_ready,
+                pacing_overrides,
+            );
+            log_info!("Present mode: {:?}", present_mode);
+            let _ = frame_renderer.redraw(&pixels);
+            pixels_slot = Some(pixels);
+        }
+        let Some(pixels) = pixels_slot.as_mut() else {
+            return;
+        };
+
+        let frame_deadline = if !fast_forward {
+            Some(
+                last_update
+                    + pacing
+                        .pass_duration()
+                        .mul_f64(audio::frame_duration_adjustment(audio_rate_control_delta)),
+            )
+        } else if fast_forward_ratio > 0.0 {
+            Some(last_update + pacing.frame_duration().div_f64(fast_forward_ratio))
+        } else {
+            None
+        };
+        // With precise pacing the loop wakes a little early and waits out the rest itself
+        *control_flow = match frame_deadline {
+            Some(deadline) if precise_pacing => {
+                ControlFlow::WaitUntil(deadline.checked_sub(video::SPIN_MARGIN).unwrap_or(deadline))
+            }
+            Some(deadline) => ControlFlow::WaitUntil(deadline),
+            None => ControlFlow::Poll,
+        };
         match event {
             Event::WindowEvent {
                 event: WindowEvent::KeyboardInput { input, .. },
                 ..
             } => {
+                // Keys pressed while rebinding only set the new binding
+                if key_rebinder.handle_key(input, &mut emulator.config, &mut key_device_map) {
+                    return;
+                }
                 let mut buttons = BUTTONS_PRESSED.lock().unwrap();
-                let buttons_pressed = &mut buttons.0;
 
-                input::handle_keyboard_input(
+                let hotkey = input::handle_keyboard_input(
                     input,
-                    buttons_pressed,
+                    &mut *buttons,
                     &key_device_map,
                     &window,
-                    &primary_monitor,
+                    &monitor,
                     &mut is_fullscreen,
+                    &emulator.config,
+                );
+                drop(buttons);
+
+                if let Some(hotkey) = hotkey {
+                    match hotkey {
+                        input::Hotkey::SaveState => emulator.save_state(),
+                        input::Hotkey::LoadState => emulator.load_state(),
+                        input::Hotkey::TogglePause => {
+                            paused = !paused;
+                            // Time spent paused isn't a slow frame
+                            perf_overlay.reset();
+                            log_info!("{}", if paused { "Paused" } else { "Resumed" });
+                        }
+                        input::Hotkey::FrameAdvance => {
+                            // Stepping only makes sense while paused
+                            frame_advance = paused;
+                        }
+                        input::Hotkey::VolumeUp => audio::adjust_volume(1.0),
+                        input::Hotkey::VolumeDown => audio::adjust_volume(-1.0),
+                        input::Hotkey::ToggleMute => audio::toggle_mute(),
+                        input::Hotkey::Screenshot => video::save_screenshot(
+                            pixels.frame(),
+                            video_width,
+                            video_height,
+                            &emulator.config["screenshot_directory"],
+                            &emulator.state.rom_name,
+                        ),
+                        input::Hotkey::DumpFrame => video::request_frame_dump(
+                            &emulator.config["screenshot_directory"],
+                            &emulator.state.rom_name,
+                        ),
+                        input::Hotkey::CycleScaleMode => {
+                            frame_renderer.scale_mode = frame_renderer.scale_mode.next();
+                            log_info!("Scale mode: {:?}", frame_renderer.scale_mode);
+                            let _ = frame_renderer.redraw(pixels);
+                        }
+                        input::Hotkey::ToggleSmooth => {
+                            let smooth = !frame_renderer.smooth();
+                            frame_renderer.set_smooth(pixels, smooth);
+                            log_info!("Filtering: {}", if smooth { "bilinear" } else { "nearest" });
+                            let _ = frame_renderer.redraw(pixels);
+                        }
+                        input::Hotkey::ToggleShader => {
+                            if frame_renderer.toggle_shader() {
+                                log_info!(
+                                    "Shader: {}",
+                                    if frame_renderer.shader_enabled {
+                                        "on"
+                                    } else {
+                                        "off"
+                                    }
+                                );
+                                let _ = frame_renderer.redraw(pixels);
+                            } else {
+                                log_warn!("The shader failed to build, see the error at startup");
+                            }
+                        }
+                        input::Hotkey::CyclePresentMode => {
+                            pending_present_mode = Some(video::next_present_mode(present_mode));
+                        }
+                        input::Hotkey::RebindKeys => {
+                            // Release everything so keys held now don't stay stuck down
+                            emulator.release_all_buttons();
+                            key_rebinder.start();
+                        }
+                        input::Hotkey::DiskEjectToggle => unsafe { libretro::toggle_disk_eject() },
+                        input::Hotkey::DiskNext => unsafe { libretro::cycle_disk(1) },
+                        input::Hotkey::DiskPrevious => unsafe { libretro::cycle_disk(-1) },
+                        input::Hotkey::TogglePerfOverlay => {
+                            perf_overlay.visible = !perf_overlay.visible;
+                            perf_overlay.reset();
+                        }
+                        input::Hotkey::CheatNext => emulator.select_cheat(1),
+                        input::Hotkey::CheatPrevious => emulator.select_cheat(-1),
+                        input::Hotkey::CheatToggle => emulator.toggle_cheat(),
+                        input::Hotkey::CheatReload => emulator.reload_cheats(),
+                        input::Hotkey::ToggleMouseGrab => {
+                            let grab = if mouse_grabbed {
+                                window.set_cursor_grab(CursorGrabMode::None)
+                            } else {
+                                // Not every platform can lock the cursor in place
+                                window
+                                    .set_cursor_grab(CursorGrabMode::Locked)
+                                    .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined))
+                            };
+                            match grab {
+                                Ok(()) => {
+                                    mouse_grabbed = !mouse_grabbed;
+                                    window.set_cursor_visible(!mouse_grabbed);
+                                    log_info!(
+                                        "Mouse {}",
+                                        if mouse_grabbed { "grabbed" } else { "released" }
+                                    );
+                                }
+                                Err(err) => log_error!("Failed to grab the mouse: {}", err),
+                            }
+                        }
+                        input::Hotkey::Reset => emulator.reset(),
+                        input::Hotkey::SwitchCore => match next_core(&emulator) {
+                            Some(next_core) => {
+                                // The new core's timing and geometry are picked up like a
+                                // change the core makes itself, after its first frame
+                                if let Err(err) = emulator.switch_core(&next_core) {
+                                    log_error!("{}", err);
+                                }
+                                if emulator.is_shut_down() {
+                                    *control_flow = ControlFlow::Exit;
+                                    return;
+                                }
+                                core_stopped = false;
+                                perf_overlay.reset();
+                                log_info!("Running core {}", emulator.state.library_name);
+                            }
+                            None => log_info!("No other core in core_switch_list to switch to"),
+                        },
+                        input::Hotkey::RotateDisplay => {
+                            video::rotate_display();
+                            frame_renderer.aspect_ratio =
+                                video::rotated_aspect_ratio(core_aspect_ratio);
+                            // Turn the window with the picture, a fullscreen one can't be
+                            if !is_fullscreen {
+                                let window_size = window.inner_size();
+                                window.set_inner_size(PhysicalSize::new(
+                                    window_size.height,
+                                    window_size.width,
+                                ));
+                            }
+                            let _ = frame_renderer.redraw(pixels);
+                            log_info!("Rotation: {} degrees", video::rotation() * 90);
+                        }
+                        input::Hotkey::Rewind(held) => {
+                            if !emulator.set_rewinding(held) && held {
+                                log_warn!("Rewind is disabled, set rewind_enable = true");
+                            }
+                        }
+                        input::Hotkey::FastForward(held) => {
+                            fast_forward = held;
+                            FAST_FORWARDING.store(held, Ordering::SeqCst);
+                        }
+                    }
+                }
+            }
+            Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                window_id: id,
+                ..
+            } if id == window_id => {
+                *control_flow = ControlFlow::Exit;
+            }
+            Event::WindowEvent {
+                event: WindowEvent::CursorMoved { position, .. },
+                window_id: id,
+            } if id == window_id => {
+                // A grabbed cursor can't move, so the raw motion below is used instead
+                if let (Some(last_position), false) = (cursor_position, mouse_grabbed) {
+                    let (scale_x, scale_y) = frame_renderer.frame_scale();
+                    input::mouse_moved(
+                        (position.x - last_position.x) / scale_x,
+                        (position.y - last_position.y) / scale_y,
+                    );
+                }
+                cursor_position = Some(position);
+            }
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta },
+                ..
+            } if mouse_grabbed => {
+                let (scale_x, scale_y) = frame_renderer.frame_scale();
+                input::mouse_moved(delta.0 / scale_x, delta.1 / scale_y);
+            }
+            Event::WindowEvent {
+                event: WindowEvent::MouseInput { state, button, .. },
+                window_id: id,
+            } if id == window_id => input::mouse_button(button, state),
+            Event::WindowEvent {
+                event: WindowEvent::MouseWheel { delta, .. },
+                window_id: id,
+            } if id == window_id => input::mouse_wheel(delta),
+            Event::WindowEvent {
+                event: WindowEvent::Focused(focused),
+                window_id: id,
+            } if id == window_id && pause_nonactive && focus_paused == focused => {
+                focus_paused = !focused;
+                audio::set_focus_muted(focus_paused);
+                perf_overlay.reset();
+                log_info!(
+                    "{}",
+                    if focus_paused {
+                        "Paused while in the background"
+                    } else {
+                        "Resumed"
+                    }
                 );
             }
             Event::WindowEvent {
@@ -192,7 +828,19 @@ fn main() {
             } if id == window_id => {
                 let new_inner_size = match event {
                     WindowEvent::Resized(new_inner_size) => new_inner_size,
-                    WindowEvent::ScaleFactorChanged { new_inner_size, .. } => *new_inner_size,
+                    WindowEvent::ScaleFactorChanged {
+                        scale_factor,
+                        new_inner_size,
+                    } => {
+                        // The window keeps its logical size, so it gains or loses physical
+                        // pixels, and the last cursor position no longer lines up with them
+                        cursor_position = None;
+                        log_info!(
+                            "Display scale changed to {}%",
+                            (scale_factor * 100.0).round()
+                        );
+                        *new_inner_size
+                    }
                     WindowEvent::Moved(_) => window.inner_size(),
                     _ => return,
                 };
@@ -200,71 +848,182 @@ fn main() {
                 let new_physical_width = new_inner_size.width;
                 let new_physical_height = new_inner_size.height;
 
-                let _ =
-                    pixels.resize_surface(new_physical_width as u32, new_physical_height as u32);
+                let _ = pixels.resize_surface(new_physical_width, new_physical_height);
+                frame_renderer.resize_surface(new_physical_width, new_physical_height);
+                // Redraw right away so the new size shows even while paused
+                let _ = frame_renderer.redraw(pixels);
                 //handle refresh set
                 //handle audio set
             }
-            Event::WindowEvent {
-                event: WindowEvent::CloseRequested,
-                window_id: id,
-                ..
-            } if id == window_id => *control_flow = ControlFlow::Exit,
             Event::MainEventsCleared => {
+                if let (Some(deadline), true) = (frame_deadline, precise_pacing) {
+                    video::wait_until(deadline);
+                }
                 last_update = Instant::now();
 
-                // Render your emulator frame here
-                unsafe {
-                    let core_api = &core.lock().unwrap().api;
-                    (core_api.retro_run)();
+                // Poll the gamepads and only touch a port's button state when its gamepad
+                // reported something, so a held keyboard key isn't overwritten by an idle one
+                if let Some(gilrs) = gilrs.as_mut() {
+                    let mut ports_with_events = [false; input::MAX_PLAYERS];
+                    while let Some(gEvent { id, event, .. }) = gilrs.next_event() {
+                        let assigned_port = gamepad_ports
+                            .iter()
+                            .position(|&port_id| port_id == Some(id));
+                        if event == EventType::Disconnected {
+                            if let Some(port) = assigned_port {
+                                // Nothing will release what the gamepad was holding, so do it
+                                // here and leave the port to the keyboard
+                                gamepad_ports[port] = None;
+                                ports_with_events[port] = false;
+                                BUTTONS_PRESSED.lock().unwrap()[port] = [0; input::JOYPAD_BUTTONS];
+                                ANALOG_STATE.lock().unwrap()[port] = [[0; 2]; 2];
+                                log_info!("Gamepad {} disconnected from port {}", id, port + 1);
+                            }
+                            continue;
+                        }
+                        // Gamepads take the first free port when plugged in or first used
+                        let port = match assigned_port {
+                            Some(port) => port,
+                            None => match gamepad_ports.iter().position(Option::is_none) {
+                                Some(port) => {
+                                    gamepad_ports[port] = Some(id);
+                                    log_info!("Gamepad {} assigned to port {}", id, port + 1);
+                                    port
+                                }
+                                None => continue,
+                            },
+                        };
+                        ports_with_events[port] = true;
+                    }
+                    let mut buttons = BUTTONS_PRESSED.lock().unwrap();
+                    let mut analog_state = ANALOG_STATE.lock().unwrap();
+                    for (port, &gamepad_id) in gamepad_ports.iter().enumerate() {
+                        let Some(gamepad_id) = gamepad_id else {
+                            continue;
+                        };
+                        if ports_with_events[port] {
+                            input::handle_gamepad_input(
+                                &joypad_device_map,
+                                gilrs,
+                                gamepad_id,
+                                &mut buttons[port],
+                            );
+                            input::handle_gamepad_axes(
+                                gilrs,
+                                gamepad_id,
+                                &analog_settings[port],
+                                &mut analog_state[port],
+                            );
+                        }
+                    }
+                }
+
+                // If needed, set up pixel format, before the core sends frames in it
+                if !emulator.state.pixel_format_ready {
+                    let pixel_format = video::set_up_pixel_format();
+                    if pixel_format.0 != emulator.state.pixel_format.0 {
+                        frame_converter.set_pixel_format(&pixel_format);
+                    }
+                    emulator.state.pixel_format = pixel_format;
+                    emulator.state.pixel_format_ready = true;
                 }
-                // If needed, set up pixel format
-                if current_state.bytes_per_pixel == 0 {
-                    (current_state.bytes_per_pixel, current_state.pixel_format) =
-                        video::set_up_pixel_format();
+
+                // Blank the refreshes after each frame, while it's running at its own pace
+                if black_frames_left > 0 {
+                    black_frames_left -= 1;
+                    if !(paused || focus_paused || fast_forward) {
+                        if frame_renderer.present_black(pixels).is_err() {
+                            *control_flow = ControlFlow::Exit;
+                        }
+                        return;
+                    }
+                    // Nothing new may come to replace a black frame, so show the last one
+                    black_frames_left = 0;
+                    let _ = frame_renderer.redraw(pixels);
                 }
-                *control_flow =
-                    video::render_frame(&mut pixels, &current_state, video_height, video_width);
+
+                // Render your emulator frame here, unless paused and not stepping
+                if (!(paused || focus_paused) || frame_advance) && !core_stopped {
+                    frame_advance = false;
+                    if emulator.run_frame() {
+                        perf_overlay.record_frame();
+                    }
+                    black_frames_left = pacing.black_frames;
+                    if emulator.take_shutdown_request() {
+                        if close_on_core_shutdown {
+                            log_info!("The core shut down, exiting");
+                            *control_flow = ControlFlow::Exit;
+                            return;
+                        }
+                        core_stopped = true;
+                        log_info!("The core shut down, close the window to exit");
+                        // No frame may follow to carry the message, so draw it on this one
+                        overlay::show_message(
+                            "THE CORE SHUT DOWN".to_string(),
+                            Duration::from_secs(5),
+                        );
+                        overlay::draw_messages(
+                            pixels.frame_mut(),
+                            video_width as usize,
+                            video_height as usize,
+                        );
+                        let _ = frame_renderer.redraw(pixels);
+                    }
+                    if emulator.take_netplay_lost() {
+                        paused = true;
+                    }
+
+                    // A core switching e.g. from NTSC to PAL changes its frame and sample
+                    // rates, and maybe its size, in the middle of a frame
+                    if let Some(new_av_info) = emulator.take_av_info_update() {
+                        original_framerate = new_av_info.timing.fps;
+                        core_sample_rate = new_av_info.timing.sample_rate;
+                        is_vrr_ready = video::is_vrr_ready(
+                            &monitor,
+                            original_framerate,
+                            vrr_nonstandard_rates,
+                        );
+                        pacing = apply_frame_pacing(
+                            present_mode,
+                            monitor_refresh_rate_hz,
+                            original_framerate,
+                            core_sample_rate,
+                            is_vrr_ready,
+                            pacing_overrides,
+                        );
+                        perf_overlay.set_core_fps(original_framerate);
+
+                        let new_geometry = &new_av_info.geometry;
+                        core_aspect_ratio = display_aspect_ratio(new_geometry, pixel_aspect, crop);
+                        frame_renderer.aspect_ratio =
+                            video::rotated_aspect_ratio(core_aspect_ratio);
+                        // The frame buffer follows the size of the frames themselves, the
+                        // window only needs resizing to keep the same scale
+                        if (new_geometry.base_width, new_geometry.base_height)
+                            != (geometry.base_width, geometry.base_height)
+                            && !is_fullscreen
+                        {
+                            let (width, height) = crop
+                                .cropped_size(new_geometry.base_width, new_geometry.base_height);
+                            let (width, height) = video::rotated_size(width, height);
+                            window.set_inner_size(LogicalSize::new(
+                                width * window_scale,
+                                height * window_scale,
+                            ));
+                        }
+                        geometry = new_geometry.clone();
+                    }
+                }
+                *control_flow = video::render_frame(
+                    pixels,
+                    &mut frame_renderer,
+                    &perf_overlay,
+                    &mut video_height,
+                    &mut video_width,
+                );
             }
 
             _ => (),
         }
     });
 }
-
-// Old Input handling Example
-////////////////////////////////////////////////////////////////////
-// while window.is_open() && !window.is_key_down(Key::Escape) {
-//     {
-//         let mut buttons = BUTTONS_PRESSED.lock().unwrap();
-//         let buttons_pressed = &mut buttons.0;
-//         let mut game_pad_active: bool = false;
-
-//         while let Some(gEvent { id, .. }) = gilrs.next_event() {
-//             // println!("{:?} New event from {}: {:?}", time, id, event);
-//             active_gamepad = Some(id);
-//         }
-
-//         // Handle gamepad and keyboard input
-//         if let Some(gamepad) = active_gamepad {
-//             input::handle_gamepad_input(
-//                 &joypad_device_map,
-//                 &gilrs,
-//                 &Some(gamepad),
-//                 buttons_pressed,
-//             );
-//             game_pad_active = true;
-//         }
-//         input::handle_keyboard_input(
-//             core_api,
-//             &window,
-//             &mut current_state,
-//             buttons_pressed,
-//             &key_device_map,
-//             &config,
-//             game_pad_active,
-//         );
-//     }
-//     // graphics processing...
-// }
-//}
@@ -0,0 +1,330 @@
+// This implementation is based on the guide provided by [RetroGameDeveloper/RetroReversing].
+// Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
+// Copyright (c) 2023 Nicholas Ricciuti
+//
+// mock_core.rs
+//
+// A stand-in libretro core for tests, so the video, audio and input paths can be driven
+// end to end without a real `.so`. It runs without content and every frame draws a small
+// RGB565 picture, blue until player 1 holds A and red while they do, then sends a few
+// stereo samples counting up with the frames.
+
+use crate::emulator::Emulator;
+use crate::libretro::{self, EmulatorState};
+use crate::{audio, video};
+use clap::Parser;
+use libretro_sys::{
+    AudioSampleBatchFn, AudioSampleFn, CoreAPI, EnvironmentFn, GameInfo, InputPollFn, InputStateFn,
+    PixelFormat, SystemAvInfo, SystemInfo, VideoRefreshFn, DEVICE_ID_JOYPAD_A, DEVICE_JOYPAD,
+};
+use std::sync::atomic::{AtomicI16, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const FRAME_WIDTH: u32 = 4;
+pub const FRAME_HEIGHT: u32 = 2;
+// Rows are padded past their pixels, as many cores do
+const FRAME_PITCH: usize = FRAME_WIDTH as usize * 2 + 4;
+const RED: u16 = 0xF800;
+const BLUE: u16 = 0x001F;
+// Stereo frames sent through the batch callback each frame, plus one through the single
+// sample callback.
+pub const BATCH_FRAMES: usize = 4;
+
+// The callbacks the frontend registered, called back from `retro_run`.
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample: Option<AudioSampleFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+// Number of `retro_run` calls since the game was loaded, which the samples count with.
+static FRAME_COUNT: AtomicI16 = AtomicI16::new(0);
+
+unsafe extern "C" fn set_environment(callback: EnvironmentFn) {
+    CALLBACKS.lock().unwrap().environment = Some(callback);
+    let mut supports_no_game = true;
+    callback(
+        libretro_sys::ENVIRONMENT_SET_SUPPORT_NO_GAME,
+        &mut supports_no_game as *mut bool as *mut libc::c_void,
+    );
+}
+
+unsafe extern "C" fn set_video_refresh(callback: VideoRefreshFn) {
+    CALLBACKS.lock().unwrap().video_refresh = Some(callback);
+}
+
+unsafe extern "C" fn set_audio_sample(callback: AudioSampleFn) {
+    CALLBACKS.lock().unwrap().audio_sample = Some(callback);
+}
+
+unsafe extern "C" fn set_audio_sample_batch(callback: AudioSampleBatchFn) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(callback);
+}
+
+unsafe extern "C" fn set_input_poll(callback: InputPollFn) {
+    CALLBACKS.lock().unwrap().input_poll = Some(callback);
+}
+
+unsafe extern "C" fn set_input_state(callback: InputStateFn) {
+    CALLBACKS.lock().unwrap().input_state = Some(callback);
+}
+
+unsafe extern "C" fn init() {}
+
+unsafe extern "C" fn deinit() {}
+
+unsafe extern "C" fn api_version() -> libc::c_uint {
+    libretro_sys::API_VERSION
+}
+
+unsafe extern "C" fn get_system_info(info: *mut SystemInfo) {
+    let info = &mut *info;
+    info.library_name = c"mock".as_ptr();
+    info.library_version = c"1.0".as_ptr();
+    info.valid_extensions = c"".as_ptr();
+    info.need_fullpath = false;
+    info.block_extract = false;
+}
+
+unsafe extern "C" fn get_system_av_info(info: *mut SystemAvInfo) {
+    let info = &mut *info;
+    info.geometry.base_width = FRAME_WIDTH;
+    info.geometry.base_height = FRAME_HEIGHT;
+    info.geometry.max_width = FRAME_WIDTH;
+    info.geometry.max_height = FRAME_HEIGHT;
+    info.geometry.aspect_ratio = 2.0;
+    info.timing.fps = 60.0;
+    info.timing.sample_rate = 48_000.0;
+}
+
+unsafe extern "C" fn set_controller_port_device(_port: libc::c_uint, _device: libc::c_uint) {}
+
+unsafe extern "C" fn reset() {
+    FRAME_COUNT.store(0, Ordering::SeqCst);
+}
+
+unsafe extern "C" fn run() {
+    // Copied out, since the callbacks can call back into the core
+    let (video_refresh, audio_sample, audio_sample_batch, input_poll, input_state) = {
+        let callbacks = CALLBACKS.lock().unwrap();
+        (
+            callbacks.video_refresh.unwrap(),
+            callbacks.audio_sample.unwrap(),
+            callbacks.audio_sample_batch.unwrap(),
+            callbacks.input_poll.unwrap(),
+            callbacks.input_state.unwrap(),
+        )
+    };
+    let frame = FRAME_COUNT.fetch_add(1, Ordering::SeqCst) + 1;
+
+    input_poll();
+    let a_held = input_state(0, DEVICE_JOYPAD, 0, DEVICE_ID_JOYPAD_A) != 0;
+
+    let color = if a_held { RED } else { BLUE };
+    let mut frame_buffer = [0u8; FRAME_PITCH * FRAME_HEIGHT as usize];
+    for row in frame_buffer.chunks_exact_mut(FRAME_PITCH) {
+        for pixel in row[..FRAME_WIDTH as usize * 2].chunks_exact_mut(2) {
+            pixel.copy_from_slice(&color.to_ne_bytes());
+        }
+    }
+    video_refresh(
+        frame_buffer.as_ptr() as *const libc::c_void,
+        FRAME_WIDTH,
+        FRAME_HEIGHT,
+        FRAME_PITCH,
+    );
+
+    let samples = [frame, -frame].repeat(BATCH_FRAMES);
+    audio_sample_batch(samples.as_ptr(), BATCH_FRAMES);
+    audio_sample(frame, -frame);
+}
+
+unsafe extern "C" fn serialize_size() -> libc::size_t {
+    0
+}
+
+unsafe extern "C" fn serialize(_data: *mut libc::c_void, _size: libc::size_t) {}
+
+unsafe extern "C" fn unserialize(_data: *const libc::c_void, _size: libc::size_t) -> bool {
+    false
+}
+
+unsafe extern "C" fn cheat_reset() {}
+
+unsafe extern "C" fn cheat_set(_index: libc::c_uint, _enabled: bool, _code: *const libc::c_char) {}
+
+unsafe extern "C" fn load_game(_game: *const GameInfo) -> bool {
+    FRAME_COUNT.store(0, Ordering::SeqCst);
+    let environment = CALLBACKS.lock().unwrap().environment.unwrap();
+    let mut pixel_format = PixelFormat::RGB565 as libc::c_uint;
+    environment(
+        libretro_sys::ENVIRONMENT_SET_PIXEL_FORMAT,
+        &mut pixel_format as *mut libc::c_uint as *mut libc::c_void,
+    )
+}
+
+unsafe extern "C" fn load_game_special(
+    _game_type: libc::c_uint,
+    _info: *const GameInfo,
+    _num_info: libc::size_t,
+) -> bool {
+    false
+}
+
+unsafe extern "C" fn unload_game() {}
+
+unsafe extern "C" fn get_region() -> libc::c_uint {
+    libretro_sys::Region::NTSC as libc::c_uint
+}
+
+unsafe extern "C" fn get_memory_data(_id: libc::c_uint) -> *mut libc::c_void {
+    std::ptr::null_mut()
+}
+
+unsafe extern "C" fn get_memory_size(_id: libc::c_uint) -> libc::size_t {
+    0
+}
+
+pub fn core_api() -> CoreAPI {
+    CoreAPI {
+        retro_set_environment: set_environment,
+        retro_set_video_refresh: set_video_refresh,
+        retro_set_audio_sample: set_audio_sample,
+        retro_set_audio_sample_batch: set_audio_sample_batch,
+        retro_set_input_poll: set_input_poll,
+        retro_set_input_state: set_input_state,
+        retro_init: init,
+        retro_deinit: deinit,
+        retro_api_version: api_version,
+        retro_get_system_info: get_system_info,
+        retro_get_system_av_info: get_system_av_info,
+        retro_set_controller_port_device: set_controller_port_device,
+        retro_reset: reset,
+        retro_run: run,
+        retro_serialize_size: serialize_size,
+        retro_serialize: serialize,
+        retro_unserialize: unserialize,
+        retro_cheat_reset: cheat_reset,
+        retro_cheat_set: cheat_set,
+        retro_load_game: load_game,
+        retro_load_game_special: load_game_special,
+        retro_unload_game: unload_game,
+        retro_get_region: get_region,
+        retro_get_memory_data: get_memory_data,
+        retro_get_memory_size: get_memory_size,
+    }
+}
+
+// Starts the mock core with the default config, as the frontend would start a real one.
+pub fn start() -> Emulator {
+    let state = EmulatorState::parse_from(["rustretro", "-L", "mock_libretro"]);
+    unsafe { Emulator::with_core_api(state, libretro::default_config(), core_api()) }
+        .expect("the mock core should start")
+}
+
+// Waits for the worker to convert every frame the core has sent, and takes the newest.
+pub fn next_frame() -> video::ConvertedFrame {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        if video::FRAMES_IN_FLIGHT.load(Ordering::SeqCst) == 0 {
+            if let Some(frame) = video::take_newest_frame() {
+                return frame;
+            }
+        }
+        assert!(Instant::now() < deadline, "no frame was converted");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+mod tests {
+    use super::*;
+
+    // Every pixel of a converted mock frame, as ARGB8888.
+    fn frame_pixels(frame: &video::ConvertedFrame) -> Vec<u32> {
+        frame
+            .pixels
+            .chunks_exact(4)
+            .map(video::read_pixel)
+            .collect()
+    }
+
+    #[test]
+    fn frames_are_converted_for_rendering() {
+        let _globals = crate::lock_test_globals();
+        let mut emulator = start();
+        let av_info = emulator.av_info().unwrap();
+        assert_eq!(av_info.geometry.base_width, FRAME_WIDTH);
+        assert_eq!(av_info.timing.fps, 60.0);
+
+        let pixel_format = video::set_up_pixel_format();
+        assert_eq!(pixel_format.bytes_per_pixel(), 2);
+        let mut frame_converter = video::FrameConverter::spawn(
+            &pixel_format,
+            emulator.state.alpha_mode,
+            video::Crop::from_config(&emulator.config),
+        );
+        assert!(emulator.run_frame());
+        let frame = next_frame();
+        frame_converter.shutdown();
+        emulator.shutdown(false);
+
+        assert_eq!((frame.width, frame.height), (FRAME_WIDTH, FRAME_HEIGHT));
+        // The pitch's padding is left out, and RGB565 blue comes out opaque ARGB8888 blue
+        let pixel_count = (FRAME_WIDTH * FRAME_HEIGHT) as usize;
+        assert_eq!(frame_pixels(&frame), vec![0xFF0000FF; pixel_count]);
+    }
+
+    #[test]
+    fn core_audio_reaches_the_audio_thread() {
+        let _globals = crate::lock_test_globals();
+        audio::AUDIO_BUFFER.lock().unwrap().clear();
+        let mut emulator = start();
+        assert!(emulator.run_frame());
+        assert!(emulator.run_frame());
+        emulator.shutdown(false);
+
+        // Each frame's batch, then its single sample, in order and nothing twice
+        let queued: Vec<i16> = audio::AUDIO_BUFFER.lock().unwrap().drain(..).collect();
+        let expected: Vec<i16> = (1..=2)
+            .flat_map(|frame: i16| [frame, -frame].repeat(BATCH_FRAMES + 1))
+            .collect();
+        assert_eq!(queued, expected);
+    }
+
+    #[test]
+    fn the_core_reads_pressed_buttons() {
+        let _globals = crate::lock_test_globals();
+        let mut emulator = start();
+        let pixel_format = video::set_up_pixel_format();
+        let mut frame_converter = video::FrameConverter::spawn(
+            &pixel_format,
+            emulator.state.alpha_mode,
+            video::Crop::from_config(&emulator.config),
+        );
+
+        emulator.set_button(0, DEVICE_ID_JOYPAD_A as usize, true);
+        assert!(emulator.run_frame());
+        let held = next_frame();
+        emulator.set_button(0, DEVICE_ID_JOYPAD_A as usize, false);
+        assert!(emulator.run_frame());
+        let released = next_frame();
+        frame_converter.shutdown();
+        emulator.shutdown(false);
+
+        let pixel_count = (FRAME_WIDTH * FRAME_HEIGHT) as usize;
+        assert_eq!(frame_pixels(&held), vec![0xFFFF0000; pixel_count]);
+        assert_eq!(frame_pixels(&released), vec![0xFF0000FF; pixel_count]);
+    }
+}
@@ -0,0 +1,156 @@
+// This implementation is based on the guide provided by [RetroGameDeveloper/RetroReversing].
+// Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
+// Copyright (c) 2023 Nicholas Ricciuti
+//
+// movie.rs
+//
+// This module records the joypad state of every frame the core runs to a movie file, and
+// plays it back in place of live input. A movie starts with a save state taken when
+// recording began, so playback always starts from the same point.
+//
+// File layout, little-endian:
+//   magic "RRMV", version (u8), ROM hash (u64), state length (u32), state bytes,
+//   then one entry per frame holding a u16 button mask for each player.
+
+use crate::input::{JOYPAD_BUTTONS, MAX_PLAYERS};
+use crate::libretro;
+use libretro_sys::CoreAPI;
+use std::ffi::c_void;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+const MOVIE_MAGIC: &[u8; 4] = b"RRMV";
+const MOVIE_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 4 + 1 + 8 + 4;
+const FRAME_SIZE: usize = MAX_PLAYERS * 2;
+
+pub type MovieButtons = [[bool; JOYPAD_BUTTONS]; MAX_PLAYERS];
+
+// Packs one player's buttons into a mask, bit N holding DEVICE_ID_JOYPAD_N.
+pub fn button_mask(buttons: &[bool; JOYPAD_BUTTONS]) -> u16 {
+    buttons
+        .iter()
+        .enumerate()
+        .fold(0u16, |mask, (id, &pressed)| mask | ((pressed as u16) << id))
+}
+
+pub fn buttons_from_mask(mask: u16) -> [bool; JOYPAD_BUTTONS] {
+    let mut buttons = [false; JOYPAD_BUTTONS];
+    for (id, pressed) in buttons.iter_mut().enumerate() {
+        *pressed = mask & (1 << id) != 0;
+    }
+    buttons
+}
+
+// FNV-1a, so movies made on one build can be checked on another.
+fn rom_hash(rom_path: &str) -> Result<u64, String> {
+    let rom = fs::read(rom_path).map_err(|e| format!("Failed to read {}: {}", rom_path, e))?;
+    Ok(rom.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    }))
+}
+
+pub struct MovieRecorder {
+    writer: BufWriter<File>,
+}
+
+impl MovieRecorder {
+    // Creates the movie file, starting it with a snapshot of the core's current state.
+    pub unsafe fn start(
+        movie_path: &str,
+        core_api: &CoreAPI,
+        rom_path: &str,
+    ) -> Result<Self, String> {
+        let mut state = Vec::new();
+        if !libretro::serialize_state(core_api, &mut state) {
+            return Err("Core does not support save states, which movies start from".to_string());
+        }
+        let file = File::create(movie_path)
+            .map_err(|e| format!("Failed to create movie {}: {}", movie_path, e))?;
+        let mut writer = BufWriter::new(file);
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MOVIE_MAGIC);
+        header.push(MOVIE_VERSION);
+        header.extend_from_slice(&rom_hash(rom_path)?.to_le_bytes());
+        header.extend_from_slice(&(state.len() as u32).to_le_bytes());
+        writer
+            .write_all(&header)
+            .and_then(|_| writer.write_all(&state))
+            .map_err(|e| format!("Failed to write movie {}: {}", movie_path, e))?;
+        log_info!("Recording movie to {}", movie_path);
+        Ok(MovieRecorder { writer })
+    }
+
+    // Appends the buttons the core sees during one frame.
+    pub fn record_frame(&mut self, buttons: &MovieButtons) -> std::io::Result<()> {
+        let mut frame = [0u8; FRAME_SIZE];
+        for (port, port_buttons) in buttons.iter().enumerate() {
+            frame[port * 2..port * 2 + 2].copy_from_slice(&button_mask(port_buttons).to_le_bytes());
+        }
+        self.writer.write_all(&frame)
+    }
+
+    pub fn finish(&mut self) {
+        if let Err(err) = self.writer.flush() {
+            log_error!("Failed to finish movie: {}", err);
+        }
+    }
+}
+
+pub struct MoviePlayer {
+    frames: Vec<u8>,
+    position: usize, // Offset of the next frame in `frames`
+}
+
+impl MoviePlayer {
+    // Reads a movie and restores the state it was recorded from.
+    pub unsafe fn start(
+        movie_path: &str,
+        core_api: &CoreAPI,
+        rom_path: &str,
+    ) -> Result<Self, String> {
+        let mut movie = fs::read(movie_path)
+            .map_err(|e| format!("Failed to read movie {}: {}", movie_path, e))?;
+        if movie.len() < HEADER_SIZE || &movie[..4] != MOVIE_MAGIC {
+            return Err(format!("{} is not a movie file", movie_path));
+        }
+        if movie[4] != MOVIE_VERSION {
+            return Err(format!("Unsupported movie version {}", movie[4]));
+        }
+        let recorded_hash = u64::from_le_bytes(movie[5..13].try_into().unwrap());
+        // A different dump of the same game may still play back fine, so only warn
+        match rom_hash(rom_path) {
+            Ok(hash) if hash != recorded_hash => {
+                log_warn!("Movie was recorded with a different ROM, playback may desync")
+            }
+            Ok(_) => {}
+            Err(err) => log_warn!("Couldn't check the movie's ROM: {}", err),
+        }
+
+        let state_length = u32::from_le_bytes(movie[13..17].try_into().unwrap()) as usize;
+        let state_end = HEADER_SIZE + state_length;
+        let state = movie
+            .get_mut(HEADER_SIZE..state_end)
+            .ok_or_else(|| format!("Truncated movie {}", movie_path))?;
+        if !(core_api.retro_unserialize)(state.as_mut_ptr() as *mut c_void, state.len()) {
+            return Err("Core rejected the movie's starting state".to_string());
+        }
+        log_info!("Playing movie {}", movie_path);
+        Ok(MoviePlayer {
+            frames: movie.split_off(state_end),
+            position: 0,
+        })
+    }
+
+    // Returns the buttons for the next frame, or None once the movie has ended.
+    pub fn next_frame(&mut self) -> Option<MovieButtons> {
+        let frame = self.frames.get(self.position..self.position + FRAME_SIZE)?;
+        self.position += FRAME_SIZE;
+        let mut buttons = [[false; JOYPAD_BUTTONS]; MAX_PLAYERS];
+        for (port, port_buttons) in buttons.iter_mut().enumerate() {
+            *port_buttons =
+                buttons_from_mask(u16::from_le_bytes([frame[port * 2], frame[port * 2 + 1]]));
+        }
+        Some(buttons)
+    }
+}
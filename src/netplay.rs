@@ -0,0 +1,163 @@
+// This implementation is based on the guide provided by [RetroGameDeveloper/RetroReversing].
+// Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
+// Copyright (c) 2023 Nicholas Ricciuti
+//
+// netplay.rs
+//
+// Two-player lockstep netplay over TCP. The host is player 1 and whoever joins player 2,
+// each playing with their own player 1 controls. Before every frame both sides send their buttons and wait for the other's, so
+// the cores on both ends always step with the same input. The session starts from the
+// host's save state, so both begin from the same point.
+//
+// Wire format, little-endian:
+//   handshake from each side: magic "RRNP", version (u8), content CRC32 (u32, 0 if unknown)
+//   then from the host: state length (u32), state bytes
+//   then each frame, from each side: frame number (u32), button mask (u16)
+
+use crate::input::{JOYPAD_BUTTONS, MAX_PLAYERS};
+use crate::libretro;
+use crate::movie::{button_mask, buttons_from_mask, MovieButtons};
+use libretro_sys::CoreAPI;
+use std::ffi::c_void;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+const NETPLAY_MAGIC: &[u8; 4] = b"RRNP";
+const NETPLAY_VERSION: u8 = 1;
+const HANDSHAKE_SIZE: usize = 4 + 1 + 4;
+// How long to wait for the peer's input before giving up on them.
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Netplay {
+    stream: TcpStream,
+    local_port: usize, // Port the local player's input goes to
+    frame: u32,        // Frames stepped since the session started
+}
+
+impl Netplay {
+    // Waits for a player to connect on `address`, then sends them our state to start from.
+    pub unsafe fn host(
+        address: &str,
+        core_api: &CoreAPI,
+        content_crc32: Option<u32>,
+    ) -> Result<Self, String> {
+        let listener = TcpListener::bind(address)
+            .map_err(|e| format!("Failed to listen for netplay on {}: {}", address, e))?;
+        log_info!("Waiting for a netplay peer on {}", address);
+        let (stream, peer_address) = listener
+            .accept()
+            .map_err(|e| format!("Failed to accept a netplay peer: {}", e))?;
+        log_info!("Netplay peer connected from {}", peer_address);
+        let mut netplay = Netplay::start(stream, 0, content_crc32)?;
+
+        let mut state = Vec::new();
+        if !libretro::serialize_state(core_api, &mut state) {
+            return Err("Core does not support save states, which netplay starts from".into());
+        }
+        netplay
+            .stream
+            .write_all(&(state.len() as u32).to_le_bytes())
+            .and_then(|_| netplay.stream.write_all(&state))
+            .map_err(|e| format!("Failed to send the starting state: {}", e))?;
+        Ok(netplay)
+    }
+
+    // Connects to the host at `address` and loads the state it starts the session from.
+    pub unsafe fn join(
+        address: &str,
+        core_api: &CoreAPI,
+        content_crc32: Option<u32>,
+    ) -> Result<Self, String> {
+        let stream = TcpStream::connect(address)
+            .map_err(|e| format!("Failed to connect to netplay host {}: {}", address, e))?;
+        log_info!("Connected to netplay host {}", address);
+        let mut netplay = Netplay::start(stream, 1, content_crc32)?;
+
+        let mut length = [0u8; 4];
+        netplay
+            .stream
+            .read_exact(&mut length)
+            .map_err(|e| format!("Failed to receive the starting state: {}", e))?;
+        let mut state = vec![0u8; u32::from_le_bytes(length) as usize];
+        netplay
+            .stream
+            .read_exact(&mut state)
+            .map_err(|e| format!("Failed to receive the starting state: {}", e))?;
+        if !(core_api.retro_unserialize)(state.as_mut_ptr() as *mut c_void, state.len()) {
+            return Err("Core rejected the host's starting state".to_string());
+        }
+        Ok(netplay)
+    }
+
+    // Swaps handshakes with the peer, checking they speak our protocol.
+    fn start(
+        mut stream: TcpStream,
+        local_port: usize,
+        content_crc32: Option<u32>,
+    ) -> Result<Self, String> {
+        // Every frame sends a few bytes and waits on the reply, so don't let them be held back
+        stream
+            .set_nodelay(true)
+            .and_then(|_| stream.set_read_timeout(Some(PEER_TIMEOUT)))
+            .map_err(|e| format!("Failed to set up the netplay connection: {}", e))?;
+
+        let mut handshake = Vec::with_capacity(HANDSHAKE_SIZE);
+        handshake.extend_from_slice(NETPLAY_MAGIC);
+        handshake.push(NETPLAY_VERSION);
+        handshake.extend_from_slice(&content_crc32.unwrap_or(0).to_le_bytes());
+        let mut peer_handshake = [0u8; HANDSHAKE_SIZE];
+        stream
+            .write_all(&handshake)
+            .and_then(|_| stream.read_exact(&mut peer_handshake))
+            .map_err(|e| format!("Netplay handshake failed: {}", e))?;
+        if &peer_handshake[..4] != NETPLAY_MAGIC {
+            return Err("The netplay peer isn't speaking our protocol".to_string());
+        }
+        if peer_handshake[4] != NETPLAY_VERSION {
+            return Err(format!(
+                "Unsupported netplay version {}, we speak {}",
+                peer_handshake[4], NETPLAY_VERSION
+            ));
+        }
+        // Like movies, a different dump of the same game may still stay in sync
+        let peer_crc32 = u32::from_le_bytes(peer_handshake[5..9].try_into().unwrap());
+        if let Some(crc32) = content_crc32 {
+            if peer_crc32 != 0 && peer_crc32 != crc32 {
+                log_warn!("The netplay peer loaded a different ROM, expect desyncs");
+            }
+        }
+        log_info!("Netplay started, you are player {}", local_port + 1);
+        Ok(Netplay {
+            stream,
+            local_port,
+            frame: 0,
+        })
+    }
+
+    // Sends the local player's buttons for the next frame and waits for the peer's.
+    // Returns the input both cores step with.
+    pub fn exchange(&mut self, local: &[bool; JOYPAD_BUTTONS]) -> io::Result<MovieButtons> {
+        let mut message = [0u8; 6];
+        message[..4].copy_from_slice(&self.frame.to_le_bytes());
+        message[4..].copy_from_slice(&button_mask(local).to_le_bytes());
+        self.stream.write_all(&message)?;
+
+        let mut peer_message = [0u8; 6];
+        self.stream.read_exact(&mut peer_message)?;
+        let peer_frame = u32::from_le_bytes(peer_message[..4].try_into().unwrap());
+        if peer_frame != self.frame {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("peer is on frame {}, we're on {}", peer_frame, self.frame),
+            ));
+        }
+        self.frame += 1;
+
+        let mut buttons = [[false; JOYPAD_BUTTONS]; MAX_PLAYERS];
+        buttons[self.local_port] = *local;
+        buttons[1 - self.local_port] =
+            buttons_from_mask(u16::from_le_bytes([peer_message[4], peer_message[5]]));
+        Ok(buttons)
+    }
+}
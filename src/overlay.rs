@@ -0,0 +1,316 @@
+// This implementation is based on the guide provided by [RetroGameDeveloper/RetroReversing].
+// Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
+// Copyright (c) 2023 Nicholas Ricciuti
+//
+// overlay.rs
+//
+// This module draws the performance overlay: FPS, frame time, audio buffer fill and
+// emulation speed, written as bitmap text into the top-left corner of the frame. Messages
+// from the core go in the bottom-left corner.
+
+use crate::audio;
+use crate::video;
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Number of recent frames the statistics are averaged over.
+const SAMPLE_FRAMES: usize = 60;
+// Glyph size in source pixels, plus one pixel of spacing.
+pub const GLYPH_WIDTH: usize = 3;
+pub const GLYPH_HEIGHT: usize = 5;
+pub const TEXT_COLOR: u32 = 0xFFFFFFFF;
+const BACKGROUND_COLOR: u32 = 0xFF000000;
+// Most core messages kept waiting, older ones are dropped first.
+const MAX_MESSAGES: usize = 8;
+// How long before it expires a message starts fading out.
+const MESSAGE_FADE: Duration = Duration::from_millis(500);
+
+// A message from the core, shown until `expires`.
+struct Message {
+    text: String,
+    expires: Instant,
+}
+
+static MESSAGES: Lazy<Mutex<VecDeque<Message>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+// Queues a message to show for `duration`. Only the newest one still due is drawn.
+pub fn show_message(text: String, duration: Duration) {
+    let mut messages = MESSAGES.lock().unwrap();
+    if messages.len() >= MAX_MESSAGES {
+        messages.pop_front();
+    }
+    messages.push_back(Message {
+        text,
+        expires: Instant::now() + duration,
+    });
+}
+
+// Draws the newest core message that hasn't expired along the bottom of an ARGB8888 frame,
+// dimming it as it runs out.
+pub fn draw_messages(frame: &mut [u8], width: usize, height: usize) {
+    let now = Instant::now();
+    let mut messages = MESSAGES.lock().unwrap();
+    messages.retain(|message| message.expires > now);
+    let Some(message) = messages.back() else {
+        return;
+    };
+
+    let remaining = message.expires - now;
+    let brightness = (remaining.as_secs_f64() / MESSAGE_FADE.as_secs_f64()).min(1.0);
+    let level = (brightness * 255.0) as u32;
+    let color = 0xFF000000 | level << 16 | level << 8 | level;
+
+    let scale = (height / 240).max(1);
+    let columns = (width / scale).saturating_sub(1) / (GLYPH_WIDTH + 1);
+    let text: String = message.text.chars().take(columns).collect();
+    let text_height = (GLYPH_HEIGHT + 2) * scale;
+    let origin_y = height.saturating_sub(text_height);
+    fill_rect(
+        frame,
+        width,
+        height,
+        (0, origin_y),
+        (text.len() * (GLYPH_WIDTH + 1) * scale + scale, text_height),
+        BACKGROUND_COLOR,
+    );
+    draw_text(
+        frame,
+        width,
+        height,
+        (scale, origin_y + scale),
+        scale,
+        &text,
+        color,
+    );
+}
+
+// 3x5 glyphs, one row per byte with the leftmost pixel in bit 2.
+// Lowercase letters are drawn as capitals.
+fn glyph(character: char) -> [u8; GLYPH_HEIGHT] {
+    match character.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        '[' => [0b110, 0b100, 0b100, 0b100, 0b110],
+        ']' => [0b011, 0b001, 0b001, 0b001, 0b011],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+        '&' => [0b010, 0b101, 0b010, 0b101, 0b011],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
+
+pub struct PerfOverlay {
+    pub visible: bool,
+    core_fps: f64, // Frame rate the core is meant to run at
+    last_frame: Option<Instant>,
+    frame_times: VecDeque<f64>, // Seconds between recent `retro_run` calls
+}
+
+impl PerfOverlay {
+    pub fn new(core_fps: f64) -> Self {
+        PerfOverlay {
+            visible: false,
+            core_fps,
+            last_frame: None,
+            frame_times: VecDeque::with_capacity(SAMPLE_FRAMES),
+        }
+    }
+
+    // Records that the core just ran a frame.
+    pub fn record_frame(&mut self) {
+        let now = Instant::now();
+        if let Some(last_frame) = self.last_frame {
+            if self.frame_times.len() >= SAMPLE_FRAMES {
+                self.frame_times.pop_front();
+            }
+            self.frame_times
+                .push_back(now.duration_since(last_frame).as_secs_f64());
+        }
+        self.last_frame = Some(now);
+    }
+
+    // Follows a change in the frame rate the core runs at.
+    pub fn set_core_fps(&mut self, core_fps: f64) {
+        self.core_fps = core_fps;
+        self.reset();
+    }
+
+    // Forgets the timings gathered so far, e.g. after a pause, so it doesn't skew the average.
+    pub fn reset(&mut self) {
+        self.last_frame = None;
+        self.frame_times.clear();
+    }
+
+    // Draws the statistics into an ARGB8888 frame, if the overlay is visible.
+    pub fn draw(&self, frame: &mut [u8], width: usize, height: usize) {
+        if !self.visible || self.frame_times.is_empty() {
+            return;
+        }
+        let frame_time = self.frame_times.iter().sum::<f64>() / self.frame_times.len() as f64;
+        let fps = if frame_time > 0.0 {
+            1.0 / frame_time
+        } else {
+            0.0
+        };
+        // How much frame times vary, which shows up as stutter even at the right average
+        let deviation = (self
+            .frame_times
+            .iter()
+            .map(|time| (time - frame_time).powi(2))
+            .sum::<f64>()
+            / self.frame_times.len() as f64)
+            .sqrt();
+        let speed = if self.core_fps > 0.0 {
+            fps / self.core_fps
+        } else {
+            0.0
+        };
+        let lines = [
+            format!("FPS {:.1}", fps),
+            format!("FT {:.1}MS", frame_time * 1000.0),
+            format!("SD {:.2}MS", deviation * 1000.0),
+            format!("AUD {:.0}%", audio::buffer_fill() * 100.0),
+            format!("AV {:.1}MS", audio::sync_offset() * 1000.0),
+            format!("SPD {:.2}X", speed),
+        ];
+
+        // Grow the text with the frame so it stays readable on high resolution cores
+        let scale = (height / 240).max(1);
+        let line_height = (GLYPH_HEIGHT + 1) * scale;
+        let text_width =
+            lines.iter().map(|line| line.len()).max().unwrap_or(0) * (GLYPH_WIDTH + 1) * scale;
+        fill_rect(
+            frame,
+            width,
+            height,
+            (0, 0),
+            (text_width + scale, lines.len() * line_height + scale),
+            BACKGROUND_COLOR,
+        );
+        for (row, line) in lines.iter().enumerate() {
+            draw_text(
+                frame,
+                width,
+                height,
+                (scale, scale + row * line_height),
+                scale,
+                line,
+                TEXT_COLOR,
+            );
+        }
+    }
+}
+
+// Writes `text` in `color` into an ARGB8888 frame, its top-left corner at `origin`, with
+// each glyph pixel drawn as a `scale` x `scale` square.
+pub fn draw_text(
+    frame: &mut [u8],
+    width: usize,
+    height: usize,
+    origin: (usize, usize),
+    scale: usize,
+    text: &str,
+    color: u32,
+) {
+    for (column, character) in text.chars().enumerate() {
+        let x = origin.0 + column * (GLYPH_WIDTH + 1) * scale;
+        draw_glyph(
+            frame,
+            width,
+            height,
+            (x, origin.1),
+            scale,
+            glyph(character),
+            color,
+        );
+    }
+}
+
+pub fn fill_rect(
+    frame: &mut [u8],
+    width: usize,
+    height: usize,
+    origin: (usize, usize),
+    size: (usize, usize),
+    color: u32,
+) {
+    for y in origin.1..(origin.1 + size.1).min(height) {
+        for x in origin.0..(origin.0 + size.0).min(width) {
+            let index = (y * width + x) * 4;
+            video::write_pixel(&mut frame[index..index + 4], color);
+        }
+    }
+}
+
+fn draw_glyph(
+    frame: &mut [u8],
+    width: usize,
+    height: usize,
+    origin: (usize, usize),
+    scale: usize,
+    rows: [u8; GLYPH_HEIGHT],
+    color: u32,
+) {
+    for (glyph_y, row) in rows.iter().enumerate() {
+        for glyph_x in 0..GLYPH_WIDTH {
+            if row & (0b100 >> glyph_x) != 0 {
+                fill_rect(
+                    frame,
+                    width,
+                    height,
+                    (origin.0 + glyph_x * scale, origin.1 + glyph_y * scale),
+                    (scale, scale),
+                    color,
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+// This implementation is based on the guide provided by [RetroGameDeveloper/RetroReversing].
+// Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
+// Copyright (c) 2023 Nicholas Ricciuti
+//
+// picker.rs
+//
+// The content picker shown when the emulator is started with a directory instead of a ROM.
+// It lists the files in it the core can load and lets the player choose one with the arrow
+// keys and enter, before the game starts.
+
+use crate::overlay::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::video;
+use pixels::wgpu::PresentMode;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use winit::dpi::LogicalSize;
+use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::platform::run_return::EventLoopExtRunReturn;
+use winit::window::WindowBuilder;
+
+const PICKER_WIDTH: usize = 320;
+const PICKER_HEIGHT: usize = 240;
+const PICKER_SCALE: u32 = 3; // Window size as a multiple of the picker's resolution
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 2;
+const MARGIN: usize = 4;
+// Rows of ROMs shown at once, below the title and a blank line.
+const VISIBLE_ROWS: usize = (PICKER_HEIGHT - 2 * MARGIN) / LINE_HEIGHT - 2;
+const MAX_COLUMNS: usize = (PICKER_WIDTH - 2 * MARGIN) / (GLYPH_WIDTH + 1);
+
+// Archives and playlists are unpacked by us, so they're listed whatever the core accepts.
+const FRONTEND_EXTENSIONS: [&str; 2] = ["zip", "m3u"];
+
+// Lists the files in `directory` with one of `extensions`, or every file if it's empty,
+// sorted by name.
+pub fn list_roms(directory: &Path, extensions: &[String]) -> io::Result<Vec<PathBuf>> {
+    let mut roms: Vec<PathBuf> = fs::read_dir(directory)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            let extension = path
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+                .unwrap_or_default();
+            extensions.is_empty()
+                || extensions.contains(&extension)
+                || FRONTEND_EXTENSIONS.contains(&extension.as_str())
+        })
+        .collect();
+    roms.sort_by_key(|path| path.file_name().map(|name| name.to_ascii_lowercase()));
+    Ok(roms)
+}
+
+// Shows `roms`, which must not be empty, in a window of its own and returns the one picked,
+// or None if the window was closed or escape pressed. Starts on `last_rom` if it's listed.
+pub fn pick_rom(
+    event_loop: &mut EventLoop<()>,
+    directory: &Path,
+    roms: &[PathBuf],
+    last_rom: Option<&Path>,
+) -> Result<Option<PathBuf>, String> {
+    let window = WindowBuilder::new()
+        .with_title("Retro Emulator - pick a game")
+        .with_inner_size(LogicalSize::new(
+            PICKER_WIDTH as u32 * PICKER_SCALE,
+            PICKER_HEIGHT as u32 * PICKER_SCALE,
+        ))
+        .build(event_loop)
+        .map_err(|e| format!("Failed to create the picker window: {}", e))?;
+    let mut pixels = video::build_pixels(
+        &window,
+        PICKER_WIDTH as u32,
+        PICKER_HEIGHT as u32,
+        PresentMode::AutoVsync,
+    )
+    .map_err(|e| format!("Failed to set up the picker window: {}", e))?;
+
+    let title = format!("{} - ENTER TO PLAY, ESC TO QUIT", directory.display());
+    let mut selected = last_rom
+        .and_then(|last_rom| roms.iter().position(|rom| rom == last_rom))
+        .unwrap_or(0);
+    let mut picked = None;
+    event_loop.run_return(|event, _, control_flow| {
+        *control_flow = ControlFlow::Wait;
+        match event {
+            Event::WindowEvent { event, .. } => match event {
+                WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                WindowEvent::Resized(size)
+                | WindowEvent::ScaleFactorChanged {
+                    new_inner_size: &mut size,
+                    ..
+                } => {
+                    let _ = pixels.resize_surface(size.width, size.height);
+                    window.request_redraw();
+                }
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(key),
+                            ..
+                        },
+                    ..
+                } => {
+                    let last = roms.len() - 1;
+                    selected = match key {
+                        VirtualKeyCode::Up => selected.saturating_sub(1),
+                        VirtualKeyCode::Down => (selected + 1).min(last),
+                        VirtualKeyCode::PageUp => selected.saturating_sub(VISIBLE_ROWS),
+                        VirtualKeyCode::PageDown => (selected + VISIBLE_ROWS).min(last),
+                        VirtualKeyCode::Home => 0,
+                        VirtualKeyCode::End => last,
+                        VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter => {
+                            picked = Some(roms[selected].clone());
+                            *control_flow = ControlFlow::Exit;
+                            selected
+                        }
+                        VirtualKeyCode::Escape => {
+                            *control_flow = ControlFlow::Exit;
+                            selected
+                        }
+                        _ => selected,
+                    };
+                    window.request_redraw();
+                }
+                _ => {}
+            },
+            Event::RedrawRequested(_) => {
+                draw_list(pixels.frame_mut(), &title, roms, selected);
+                if pixels.render().is_err() {
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
+            _ => {}
+        }
+    });
+    Ok(picked)
+}
+
+// Draws the title and the page of ROM names around `selected`, marking it with `>`.
+fn draw_list(frame: &mut [u8], title: &str, roms: &[PathBuf], selected: usize) {
+    overlay::fill_rect(
+        frame,
+        PICKER_WIDTH,
+        PICKER_HEIGHT,
+        (0, 0),
+        (PICKER_WIDTH, PICKER_HEIGHT),
+        0xFF000000,
+    );
+    let draw_line = |frame: &mut [u8], row: usize, text: &str| {
+        let text: String = text.chars().take(MAX_COLUMNS).collect();
+        let origin = (MARGIN, MARGIN + row * LINE_HEIGHT);
+        overlay::draw_text(
+            frame,
+            PICKER_WIDTH,
+            PICKER_HEIGHT,
+            origin,
+            1,
+            &text,
+            overlay::TEXT_COLOR,
+        );
+    };
+    draw_line(frame, 0, title);
+
+    // Keep the selection on screen, scrolling a page at a time
+    let first = selected / VISIBLE_ROWS * VISIBLE_ROWS;
+    for (row, rom) in roms.iter().enumerate().skip(first).take(VISIBLE_ROWS) {
+        let name = rom.file_name().unwrap_or_default().to_string_lossy();
+        let marker = if row == selected { '>' } else { ' ' };
+        draw_line(frame, 2 + row - first, &format!("{} {}", marker, name));
+    }
+}
@@ -0,0 +1,455 @@
+// This implementation is based on the guide provided by [RetroGameDeveloper/RetroReversing].
+// Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
+// Copyright (c) 2023 Nicholas Ricciuti
+//
+// renderer.rs
+//
+// This module draws the emulator frame onto the window. The renderer bundled with `pixels`
+// only scales by whole numbers, so we use our own pipeline that draws the frame into a
+// viewport chosen by the scale mode and clears the rest of the window to black.
+// Optionally the frame is drawn through a post-processing shader, such as the CRT one.
+
+use pixels::{wgpu, Pixels, PixelsContext};
+
+// The vertex stage and bindings every frame shader is joined with.
+const COMMON_SHADER: &str = include_str!("shaders/common.wgsl");
+const PLAIN_SHADER: &str = include_str!("shaders/frame.wgsl");
+// Used when `video_shader` doesn't name a shader file.
+const DEFAULT_SHADER: &str = include_str!("shaders/crt.wgsl");
+
+// Reads the post-processing shader from `shader_path`, falling back to the built-in CRT
+// shader when no path is set or the file can't be read.
+pub fn load_shader(shader_path: &str) -> String {
+    if shader_path.is_empty() {
+        return DEFAULT_SHADER.to_string();
+    }
+    let shader_path = shellexpand::tilde(shader_path);
+    match std::fs::read_to_string(shader_path.as_ref()) {
+        Ok(source) => source,
+        Err(err) => {
+            log_error!(
+                "Failed to read shader {}, using the built-in one: {}",
+                shader_path,
+                err
+            );
+            DEFAULT_SHADER.to_string()
+        }
+    }
+}
+
+// How the frame is fitted into the window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleMode {
+    Stretch, // Fill the whole window, ignoring the aspect ratio
+    Aspect,  // Fill as much as possible while keeping the core's aspect ratio
+    Integer, // Scale by whole multiples, as close to the aspect ratio as they allow
+}
+
+impl ScaleMode {
+    // Parses the `video_scale_mode` config value.
+    pub fn from_config(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "stretch" => Some(ScaleMode::Stretch),
+            "aspect" => Some(ScaleMode::Aspect),
+            "integer" => Some(ScaleMode::Integer),
+            _ => None,
+        }
+    }
+
+    // The mode selected by the next press of the scale mode hotkey.
+    pub fn next(self) -> Self {
+        match self {
+            ScaleMode::Stretch => ScaleMode::Aspect,
+            ScaleMode::Aspect => ScaleMode::Integer,
+            ScaleMode::Integer => ScaleMode::Stretch,
+        }
+    }
+}
+
+// Computes the (x, y, width, height) of the area the frame is drawn into, in the surface's
+// physical pixels.
+pub fn destination_rect(
+    scale_mode: ScaleMode,
+    frame_size: (u32, u32),
+    aspect_ratio: f64,
+    surface_size: (u32, u32),
+) -> (f32, f32, f32, f32) {
+    let (frame_width, frame_height) = (frame_size.0.max(1) as f64, frame_size.1.max(1) as f64);
+    let (surface_width, surface_height) = (surface_size.0 as f64, surface_size.1 as f64);
+
+    // Whole pixels, so the picture's edges don't land between two on the surface
+    let fit_aspect = || {
+        if surface_width / surface_height > aspect_ratio {
+            ((surface_height * aspect_ratio).round(), surface_height)
+        } else {
+            (surface_width, (surface_width / aspect_ratio).round())
+        }
+    };
+
+    let (width, height) = match scale_mode {
+        ScaleMode::Stretch => (surface_width, surface_height),
+        ScaleMode::Aspect => fit_aspect(),
+        ScaleMode::Integer => {
+            // Each source pixel becomes an even block. The height picks the largest multiple
+            // that fits and the width uses the whole multiple closest to the aspect ratio, so
+            // cores with non-square pixels keep their shape without uneven columns
+            let max_horizontal_scale = (surface_width / frame_width).floor();
+            let vertical_scale = (surface_height / frame_height).floor();
+            if vertical_scale >= 1.0 && max_horizontal_scale >= 1.0 {
+                let horizontal_scale = (frame_height * vertical_scale * aspect_ratio / frame_width)
+                    .round()
+                    .clamp(1.0, max_horizontal_scale);
+                (
+                    frame_width * horizontal_scale,
+                    frame_height * vertical_scale,
+                )
+            } else {
+                // A window smaller than the frame can't hold even one multiple
+                fit_aspect()
+            }
+        }
+    };
+
+    (
+        ((surface_width - width) / 2.0).floor() as f32,
+        ((surface_height - height) / 2.0).floor() as f32,
+        width.max(1.0) as f32,
+        height.max(1.0) as f32,
+    )
+}
+
+pub struct FrameRenderer {
+    pipeline: wgpu::RenderPipeline,
+    shader_pipeline: Option<wgpu::RenderPipeline>, // None if the shader failed to build
+    pub shader_enabled: bool,
+    bind_group_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    frame_info: wgpu::Buffer, // The shaders' FrameInfo uniform
+    bind_group: wgpu::BindGroup,
+    texture_extent: wgpu::Extent3d,
+    surface_size: (u32, u32),
+    pub scale_mode: ScaleMode,
+    pub aspect_ratio: f64, // Display aspect ratio reported by the core
+    smooth: bool,          // Bilinear filtering instead of nearest neighbour
+}
+
+impl FrameRenderer {
+    pub fn new(
+        pixels: &Pixels,
+        scale_mode: ScaleMode,
+        aspect_ratio: f64,
+        smooth: bool,
+        surface_size: (u32, u32),
+        shader_source: &str,
+        shader_enabled: bool,
+    ) -> Self {
+        let device = pixels.device();
+        let sampler = create_sampler(device, smooth);
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("frame_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("frame_pipeline_layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let format = pixels.render_texture_format();
+        let pipeline = create_pipeline(device, &pipeline_layout, format, PLAIN_SHADER)
+            .expect("The built-in frame shader failed to build");
+        // A broken user shader only costs the effect, not the picture
+        let shader_pipeline = create_pipeline(device, &pipeline_layout, format, shader_source)
+            .map_err(|err| log_error!("Failed to build the shader, it's disabled: {}", err))
+            .ok();
+
+        let frame_info = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("frame_info"),
+            size: 4 * std::mem::size_of::<f32>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let context = pixels.context();
+        let bind_group = create_bind_group(
+            &context.device,
+            &bind_group_layout,
+            &sampler,
+            &frame_info,
+            &context.texture,
+        );
+
+        FrameRenderer {
+            pipeline,
+            shader_enabled: shader_enabled && shader_pipeline.is_some(),
+            shader_pipeline,
+            bind_group_layout,
+            sampler,
+            frame_info,
+            bind_group,
+            texture_extent: context.texture_extent,
+            surface_size,
+            scale_mode,
+            aspect_ratio,
+            smooth,
+        }
+    }
+
+    // Turns the post-processing shader on or off. Returns false if there's no shader to use.
+    pub fn toggle_shader(&mut self) -> bool {
+        if self.shader_pipeline.is_none() {
+            return false;
+        }
+        self.shader_enabled = !self.shader_enabled;
+        true
+    }
+
+    pub fn smooth(&self) -> bool {
+        self.smooth
+    }
+
+    // Switches between nearest neighbour and bilinear filtering when the frame is scaled.
+    pub fn set_smooth(&mut self, pixels: &Pixels, smooth: bool) {
+        let context = pixels.context();
+        self.smooth = smooth;
+        self.sampler = create_sampler(&context.device, smooth);
+        self.bind_group = create_bind_group(
+            &context.device,
+            &self.bind_group_layout,
+            &self.sampler,
+            &self.frame_info,
+            &context.texture,
+        );
+    }
+
+    // Tracks the window's physical size so the destination rectangle follows resizes and
+    // display scale changes.
+    pub fn resize_surface(&mut self, width: u32, height: u32) {
+        self.surface_size = (width, height);
+    }
+
+    // Window pixels per frame pixel along each axis, for mapping mouse motion onto the frame.
+    pub fn frame_scale(&self) -> (f64, f64) {
+        let frame_size = (self.texture_extent.width, self.texture_extent.height);
+        let (_, _, width, height) = destination_rect(
+            self.scale_mode,
+            frame_size,
+            self.aspect_ratio,
+            self.surface_size,
+        );
+        (
+            width as f64 / frame_size.0.max(1) as f64,
+            height as f64 / frame_size.1.max(1) as f64,
+        )
+    }
+
+    // Rebinds the frame texture, which `pixels` recreates whenever the buffer is resized.
+    pub fn update_texture(&mut self, pixels: &Pixels) {
+        let context = pixels.context();
+        if context.texture_extent != self.texture_extent {
+            self.bind_group = create_bind_group(
+                &context.device,
+                &self.bind_group_layout,
+                &self.sampler,
+                &self.frame_info,
+                &context.texture,
+            );
+            self.texture_extent = context.texture_extent;
+        }
+    }
+
+    // Presents the current frame texture again, used when the window changes size.
+    pub fn redraw(&self, pixels: &Pixels) -> Result<(), pixels::Error> {
+        // A minimized window has no surface to present to
+        if self.surface_size.0 == 0 || self.surface_size.1 == 0 {
+            return Ok(());
+        }
+        pixels.render_with(|encoder, render_target, context| {
+            self.render(encoder, render_target, context);
+            Ok(())
+        })
+    }
+
+    // Presents an all-black frame, for the refreshes black frame insertion blanks.
+    pub fn present_black(&self, pixels: &Pixels) -> Result<(), pixels::Error> {
+        if self.surface_size.0 == 0 || self.surface_size.1 == 0 {
+            return Ok(());
+        }
+        pixels.render_with(|encoder, render_target, _| {
+            encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("black_frame_render_pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: render_target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            Ok(())
+        })
+    }
+
+    // Draws the frame texture into the window, letterboxing whatever it doesn't cover.
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        render_target: &wgpu::TextureView,
+        context: &PixelsContext,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("frame_render_pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: render_target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+
+        let (x, y, width, height) = destination_rect(
+            self.scale_mode,
+            (context.texture_extent.width, context.texture_extent.height),
+            self.aspect_ratio,
+            self.surface_size,
+        );
+        let frame_info = [
+            context.texture_extent.width as f32,
+            context.texture_extent.height as f32,
+            width,
+            height,
+        ];
+        context
+            .queue
+            .write_buffer(&self.frame_info, 0, bytemuck::cast_slice(&frame_info));
+        let pipeline = match &self.shader_pipeline {
+            Some(shader_pipeline) if self.shader_enabled => shader_pipeline,
+            _ => &self.pipeline,
+        };
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_viewport(x, y, width, height, 0.0, 1.0);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+// Builds a pipeline drawing the frame with `fragment_source`, which is joined onto the
+// common vertex stage. Errors are caught instead of left to wgpu, which would panic.
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    fragment_source: &str,
+) -> Result<wgpu::RenderPipeline, wgpu::Error> {
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("frame_shader"),
+        source: wgpu::ShaderSource::Wgsl(format!("{}\n{}", COMMON_SHADER, fragment_source).into()),
+    });
+    let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("frame_pipeline"),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        multiview: None,
+    });
+    match pollster::block_on(device.pop_error_scope()) {
+        Some(err) => Err(err),
+        None => Ok(pipeline),
+    }
+}
+
+fn create_sampler(device: &wgpu::Device, smooth: bool) -> wgpu::Sampler {
+    let filter = if smooth {
+        wgpu::FilterMode::Linear
+    } else {
+        wgpu::FilterMode::Nearest
+    };
+    device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("frame_sampler"),
+        address_mode_u: wgpu::AddressMode::ClampToEdge,
+        address_mode_v: wgpu::AddressMode::ClampToEdge,
+        address_mode_w: wgpu::AddressMode::ClampToEdge,
+        mag_filter: filter,
+        min_filter: filter,
+        mipmap_filter: wgpu::FilterMode::Nearest,
+        ..Default::default()
+    })
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    sampler: &wgpu::Sampler,
+    frame_info: &wgpu::Buffer,
+    texture: &wgpu::Texture,
+) -> wgpu::BindGroup {
+    let texture_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("frame_bind_group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&texture_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: frame_info.as_entire_binding(),
+            },
+        ],
+    })
+}
@@ -0,0 +1,26 @@
+// Shared by every frame shader: draws the emulator frame over the whole viewport with a
+// single oversized triangle. The shader it's joined with only has to provide `fs_main`.
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) tex_coord: vec2<f32>,
+}
+
+// Size of the core's frame and of the viewport it's drawn to, in pixels.
+struct FrameInfo {
+    source_size: vec2<f32>,
+    output_size: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) vertex_index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32((vertex_index << 1u) & 2u), f32(vertex_index & 2u));
+    var out: VertexOutput;
+    out.position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    out.tex_coord = uv;
+    return out;
+}
+
+@group(0) @binding(0) var frame_texture: texture_2d<f32>;
+@group(0) @binding(1) var frame_sampler: sampler;
+@group(0) @binding(2) var<uniform> frame_info: FrameInfo;
@@ -0,0 +1,19 @@
+// A simple CRT look: dark gaps between the frame's lines and an aperture grille
+// tinting every third column of the window red, green or blue.
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(frame_texture, frame_sampler, in.tex_coord).rgb;
+
+    // Brightest through the middle of each source line, darkest between them
+    let line = fract(in.tex_coord.y * frame_info.source_size.y);
+    let scanline = 0.55 + 0.45 * sin(line * 3.14159265);
+
+    let column = u32(in.position.x) % 3u;
+    let mask = vec3<f32>(
+        select(0.85, 1.15, column == 0u),
+        select(0.85, 1.15, column == 1u),
+        select(0.85, 1.15, column == 2u),
+    );
+    return vec4<f32>(color * scanline * mask, 1.0);
+}
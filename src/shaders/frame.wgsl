@@ -0,0 +1,6 @@
+// Draws the emulator frame as it is.
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return textureSample(frame_texture, frame_sampler, in.tex_coord);
+}
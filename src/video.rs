@@ -7,14 +7,118 @@
 // This module handles video output for the emulator, including pixel format conversions,
 // rendering frames, and interfacing with the libretro video callbacks.
 
-use crate::{libretro::EmulatorState, VideoData, PIXEL_FORMAT_CHANNEL, VIDEO_DATA_CHANNEL};
+use crate::overlay::{self, PerfOverlay};
+use crate::renderer::FrameRenderer;
+use crate::vrr;
+use crate::{VideoData, PIXEL_FORMAT_CHANNEL, VIDEO_DATA_SLOT};
 use libretro_sys::PixelFormat;
-use pixels::Pixels;
+use once_cell::sync::Lazy;
+use pixels::wgpu::{PresentMode, TextureFormat};
+use pixels::{Pixels, PixelsBuilder, SurfaceTexture};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use winit::event_loop::ControlFlow;
+use winit::window::Window;
+
+// Most converted frames kept waiting for the render thread, older ones are dropped first.
+const MAX_QUEUED_FRAMES: usize = 2;
+const FRAME_DUMP_MAGIC: &[u8; 4] = b"RRFD";
+// Frame buffers kept for reuse. One is being converted while the core fills the next.
+const MAX_POOLED_FRAME_BUFFERS: usize = 2;
+// How long the render thread waits for the worker to finish a frame the core just sent.
+const CONVERSION_WAIT: Duration = Duration::from_millis(4);
+// How often the conversion worker checks whether it should shut down.
+const WORKER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Frame buffers already handed back by the converter, so the video callback doesn't have
+// to allocate a new one for every frame.
+static FRAME_BUFFER_POOL: Lazy<Mutex<Vec<Vec<u8>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+// Frames converted to ARGB8888 by the worker, waiting to be shown.
+static CONVERTED_FRAMES: Lazy<Mutex<VecDeque<ConvertedFrame>>> =
+    Lazy::new(|| Mutex::new(VecDeque::with_capacity(MAX_QUEUED_FRAMES)));
+static CONVERTED_CONDVAR: Condvar = Condvar::new();
+// Where to dump the next frame the worker converts, set by `request_frame_dump`.
+static FRAME_DUMP_PATH: Lazy<Mutex<Option<PathBuf>>> = Lazy::new(|| Mutex::new(None));
+// Frames the core has sent that the worker hasn't finished converting yet.
+pub(crate) static FRAMES_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+// Set while the current frame is being skipped, so the video callback drops it.
+static SKIP_VIDEO: AtomicBool = AtomicBool::new(false);
+// Set while the current frame is hidden, which unlike a skipped one the core still draws.
+static BLANK_VIDEO: AtomicBool = AtomicBool::new(false);
+// Set once a frame too short for the pixel format has been reported, so it isn't every frame.
+static PITCH_WARNED: AtomicBool = AtomicBool::new(false);
+// Quarter turns counterclockwise the picture is shown at, as the core asked through
+// ENVIRONMENT_SET_ROTATION and on top of that as the rotate hotkey turned it.
+static CORE_ROTATION: AtomicU32 = AtomicU32::new(0);
+static USER_ROTATION: AtomicU32 = AtomicU32::new(0);
+
+// Lookup table converting RGB565 pixels to ARGB8888, built once on first use.
+static RGB565_TO_ARGB8888_TABLE: Lazy<Box<[u32; 65536]>> = Lazy::new(|| {
+    let mut table = Box::new([0; 65536]);
+    for (i, entry) in table.iter_mut().enumerate() {
+        let r = (i >> 11) & 0x1F;
+        let g = (i >> 5) & 0x3F;
+        let b = i & 0x1F;
+
+        let r = ((r * 527 + 23) >> 6) as u32;
+        let g = ((g * 259 + 33) >> 6) as u32;
+        let b = ((b * 527 + 23) >> 6) as u32;
+
+        *entry = 0xFF000000 | (r << 16) | (g << 8) | b;
+    }
+    table
+});
+
+// Lookup tables converting ARGB1555 pixels to ARGB8888, built once on first use, and the
+// same for cores that put blue in the top five bits and red in the bottom ones.
+static ARGB1555_TO_ARGB8888_TABLE: Lazy<Box<[u32; 65536]>> = Lazy::new(|| argb1555_table(false));
+static ABGR1555_TO_ARGB8888_TABLE: Lazy<Box<[u32; 65536]>> = Lazy::new(|| argb1555_table(true));
+// Set by `video_1555_channel_order = bgr`, for cores whose 1555 pixels show up with red
+// and blue swapped.
+static BGR1555: AtomicBool = AtomicBool::new(false);
+
+// Builds a 1555 table, which covers all 16 bits so pixels with the alpha bit set don't
+// index past the end.
+fn argb1555_table(bgr: bool) -> Box<[u32; 65536]> {
+    let mut table = Box::new([0; 65536]);
+    for (i, entry) in table.iter_mut().enumerate() {
+        let a = (i >> 15) & 0x01;
+        let (r, b) = if bgr {
+            (i & 0x1F, (i >> 10) & 0x1F)
+        } else {
+            ((i >> 10) & 0x1F, i & 0x1F)
+        };
+        let g = (i >> 5) & 0x1F;
+
+        let a = (a * 255) as u32;
+        let r = ((r * 527 + 23) >> 6) as u32;
+        let g = ((g * 527 + 23) >> 6) as u32;
+        let b = ((b * 527 + 23) >> 6) as u32;
+
+        *entry = (a << 24) | (r << 16) | (g << 8) | b;
+    }
+    table
+}
 
 // Represents the pixel format used by the emulator.
+#[derive(Clone)]
 pub struct EmulatorPixelFormat(pub PixelFormat);
 
+// How the alpha channel of the core's pixels is treated. Libretro's ARGB8888 and ARGB1555
+// are really XRGB8888 and 0RGB1555, so the top bits are padding rather than transparency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum AlphaMode {
+    #[default]
+    Opaque, // Force every pixel to full alpha
+    Preserve, // Pass the core's alpha bits through untouched
+}
+
 // Provides a default pixel format for the emulator.
 impl Default for EmulatorPixelFormat {
     fn default() -> Self {
@@ -22,39 +126,312 @@ impl Default for EmulatorPixelFormat {
     }
 }
 
-pub fn is_vrr_ready(monitor: &winit::monitor::MonitorHandle, original_framerate: f64) -> bool {
-    let mut min_refresh_rate = f64::MAX;
-    let mut max_refresh_rate = f64::MIN;
-    let mut count_not_divisible_by_ten = 0;
+impl EmulatorPixelFormat {
+    pub fn bytes_per_pixel(&self) -> u8 {
+        bytes_per_pixel(self.0)
+    }
+}
 
-    for video_mode in monitor.video_modes() {
-        let refresh_rate = video_mode.refresh_rate_millihertz() as f64 / 1000.0;
-        if refresh_rate < min_refresh_rate {
-            min_refresh_rate = refresh_rate;
-        }
-        if refresh_rate > max_refresh_rate {
-            max_refresh_rate = refresh_rate;
-        }
+// Parses the `video_present_mode` config value.
+pub fn present_mode_from_config(value: &str) -> Option<PresentMode> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "auto_vsync" => Some(PresentMode::AutoVsync),
+        "auto_no_vsync" => Some(PresentMode::AutoNoVsync),
+        "fifo" => Some(PresentMode::Fifo),
+        "mailbox" => Some(PresentMode::Mailbox),
+        _ => None,
+    }
+}
 
-        // Check if refresh rate is not divisible by 5 and is not 144Hz
-        if refresh_rate % 10.0 != 0.0
-            && refresh_rate.round() as i32 != 144
-            && refresh_rate.round() as i32 != 75
-        {
-            count_not_divisible_by_ten += 1;
+// The present mode selected by the next press of the present mode hotkey.
+pub fn next_present_mode(present_mode: PresentMode) -> PresentMode {
+    match present_mode {
+        PresentMode::AutoVsync => PresentMode::AutoNoVsync,
+        PresentMode::AutoNoVsync => PresentMode::Fifo,
+        PresentMode::Fifo => PresentMode::Mailbox,
+        _ => PresentMode::AutoVsync,
+    }
+}
+
+// Whether presenting waits for the monitor's refresh, which paces the main loop.
+// Mailbox never blocks, so it runs like vsync off.
+pub fn is_vsync(present_mode: PresentMode) -> bool {
+    matches!(
+        present_mode,
+        PresentMode::AutoVsync | PresentMode::Fifo | PresentMode::FifoRelaxed
+    )
+}
+
+// The texture the frame buffer is uploaded to. Its bytes are B, G, R, A, which is how an
+// ARGB8888 pixel is laid out little-endian, so `write_pixel` stores them that way on any host.
+pub const FRAME_TEXTURE_FORMAT: TextureFormat = TextureFormat::Bgra8UnormSrgb;
+
+// Stores an ARGB8888 pixel into the frame buffer in `FRAME_TEXTURE_FORMAT`'s byte order.
+pub fn write_pixel(dest: &mut [u8], argb8888: u32) {
+    dest.copy_from_slice(&argb8888.to_le_bytes());
+}
+
+// Reads back a pixel stored by `write_pixel`.
+pub fn read_pixel(source: &[u8]) -> u32 {
+    u32::from_le_bytes([source[0], source[1], source[2], source[3]])
+}
+
+// Creates the frame buffer and the window surface it's presented to.
+pub fn build_pixels(
+    window: &Window,
+    width: u32,
+    height: u32,
+    present_mode: PresentMode,
+) -> Result<Pixels, pixels::Error> {
+    let window_size = window.inner_size();
+    PixelsBuilder::new(
+        width,
+        height,
+        SurfaceTexture::new(window_size.width, window_size.height, window),
+    )
+    .texture_format(FRAME_TEXTURE_FORMAT)
+    .present_mode(present_mode)
+    .build()
+}
+
+// How long before a frame is due `wait_until` stops sleeping and starts spinning. Sleeps
+// overshoot by up to a timer tick, which is a millisecond or more on some systems.
+pub const SPIN_MARGIN: Duration = Duration::from_millis(2);
+
+// Waits until `deadline`, sleeping for most of the time and spinning for the last
+// `SPIN_MARGIN` of it, so the frame starts on time at the cost of some CPU.
+pub fn wait_until(deadline: Instant) {
+    let sleep_until = deadline.checked_sub(SPIN_MARGIN).unwrap_or(deadline);
+    let now = Instant::now();
+    if sleep_until > now {
+        thread::sleep(sleep_until - now);
+    }
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+// How fast the main loop runs the core, and how much the audio rate is stretched to match.
+pub struct FramePacing {
+    pub target_fps: f64,
+    pub swap_interval: f64,
+    pub sample_rate_factor: f64,
+    // Refreshes blanked after each of the core's frames
+    pub black_frames: u32,
+}
+
+impl FramePacing {
+    // How long each of the core's frames stays up.
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs_f64(self.swap_interval / self.target_fps)
+    }
+
+    // How long each pass of the main loop takes. With black frames it's a single refresh,
+    // presenting either the core's frame or a black one.
+    pub fn pass_duration(&self) -> Duration {
+        self.frame_duration() / (self.black_frames + 1)
+    }
+}
+
+// Settings overriding how `frame_pacing` matches the core to the display.
+#[derive(Clone, Copy)]
+pub struct PacingOverrides {
+    pub fps: Option<f64>,
+    pub swap_interval: Option<u32>,
+    pub black_frame_insertion: bool,
+}
+
+impl PacingOverrides {
+    // Reads video_swap_interval, "auto" or a number of refreshes per frame, and
+    // video_black_frame_insertion. A bad interval is reported and left to "auto".
+    pub fn from_config(config: &HashMap<String, String>, fps: Option<f64>) -> Self {
+        let swap_interval = match config["video_swap_interval"].as_str() {
+            "" | "auto" => None,
+            value => match value.parse::<u32>() {
+                Ok(interval) if interval > 0 => Some(interval),
+                _ => {
+                    log_warn!("Invalid video_swap_interval '{}', using auto", value);
+                    None
+                }
+            },
+        };
+        PacingOverrides {
+            fps,
+            swap_interval,
+            black_frame_insertion: config["video_black_frame_insertion"] == "true",
         }
     }
+}
+
+// A forced fps runs the core at exactly that rate, whatever the display or VRR support,
+// with the audio stretched to keep up. A VRR display refreshes whenever a frame is ready,
+// so the core runs at its own rate too.
+//
+// Otherwise vsync shows each frame for a whole number of refreshes, `swap_interval`, and the
+// core runs at the monitor's rate divided by it, with the audio stretched by the same
+// amount. By default the interval is the nearest whole ratio: exact on a 120Hz display
+// for a 60 FPS core, while on 75Hz a new frame every refresh keeps motion smooth but runs
+// 25% fast. An interval of 2 there runs it at 37.5 FPS instead, so no setting is both even
+// and at speed on such a display; VRR or --fps with vsync off are the way to get both.
+// Black frame insertion blanks all but the first refresh of each frame, which sharpens
+// motion on an interval of 2 or more at the cost of brightness and flicker.
+pub fn frame_pacing(
+    vsync: bool,
+    monitor_refresh_rate_hz: f64,
+    original_framerate: f64,
+    is_vrr_ready: bool,
+    overrides: PacingOverrides,
+) -> FramePacing {
+    if let Some(fps) = overrides.fps {
+        return FramePacing {
+            target_fps: fps,
+            swap_interval: 1.0,
+            sample_rate_factor: fps / original_framerate,
+            black_frames: 0,
+        };
+    }
+    if !vsync || is_vrr_ready {
+        // Nothing ties the loop to a fixed refresh, so run at the core's own rate
+        return FramePacing {
+            target_fps: original_framerate,
+            swap_interval: 1.0,
+            sample_rate_factor: 1.0,
+            black_frames: 0,
+        };
+    }
+    let swap_interval = overrides.swap_interval.map_or_else(
+        || {
+            (monitor_refresh_rate_hz / original_framerate)
+                .round()
+                .max(1.0)
+        },
+        f64::from,
+    );
+    FramePacing {
+        target_fps: monitor_refresh_rate_hz,
+        swap_interval,
+        sample_rate_factor: monitor_refresh_rate_hz / swap_interval / original_framerate,
+        black_frames: if overrides.black_frame_insertion {
+            swap_interval as u32 - 1
+        } else {
+            0
+        },
+    }
+}
+
+// Refresh rates displays commonly offer. A mode list made only of these says nothing about
+// VRR, while drivers exposing a VRR range tend to add rates that aren't on the list.
+const STANDARD_REFRESH_RATES: [f64; 22] = [
+    24.0, 25.0, 30.0, 48.0, 50.0, 60.0, 70.0, 72.0, 75.0, 85.0, 90.0, 100.0, 120.0, 144.0, 165.0,
+    170.0, 175.0, 180.0, 200.0, 240.0, 300.0, 360.0,
+];
+// How far a mode may be from a standard rate, or its 1000/1001 NTSC variant, and still count.
+const REFRESH_RATE_TOLERANCE_HZ: f64 = 0.05;
+// How far the core framerate may sit outside the listed range, so e.g. a 60.1 FPS core
+// still counts as fitting a 60Hz display.
+const FRAMERATE_TOLERANCE_HZ: f64 = 0.5;
+
+fn is_standard_refresh_rate(refresh_rate: f64) -> bool {
+    STANDARD_REFRESH_RATES.iter().any(|&standard| {
+        (refresh_rate - standard).abs() <= REFRESH_RATE_TOLERANCE_HZ
+            || (refresh_rate - standard * 1000.0 / 1001.0).abs() <= REFRESH_RATE_TOLERANCE_HZ
+    })
+}
+
+// Guesses VRR support from a display's refresh rates: it needs at least
+// `nonstandard_rate_threshold` distinct non-standard rates, and the core's framerate has to
+// fall inside the listed range.
+pub fn refresh_rates_suggest_vrr(
+    refresh_rates: &[f64],
+    original_framerate: f64,
+    nonstandard_rate_threshold: usize,
+) -> bool {
+    let Some((min_refresh_rate, max_refresh_rate)) = refresh_rate_range(refresh_rates) else {
+        return false;
+    };
+
+    // The same rate is listed once per resolution, so count each one only once
+    let mut nonstandard_rates: Vec<f64> = refresh_rates
+        .iter()
+        .copied()
+        .filter(|&refresh_rate| !is_standard_refresh_rate(refresh_rate))
+        .collect();
+    nonstandard_rates.sort_by(f64::total_cmp);
+    nonstandard_rates.dedup_by(|a, b| (*a - *b).abs() <= REFRESH_RATE_TOLERANCE_HZ);
+
+    nonstandard_rates.len() >= nonstandard_rate_threshold
+        && min_refresh_rate - FRAMERATE_TOLERANCE_HZ <= original_framerate
+        && original_framerate <= max_refresh_rate + FRAMERATE_TOLERANCE_HZ
+}
 
-    println!(
+fn refresh_rate_range(refresh_rates: &[f64]) -> Option<(f64, f64)> {
+    refresh_rates
+        .iter()
+        .fold(None, |range, &refresh_rate| match range {
+            None => Some((refresh_rate, refresh_rate)),
+            Some((min, max)) => Some((f64::min(min, refresh_rate), f64::max(max, refresh_rate))),
+        })
+}
+
+// Decides whether the core's framerate can be shown as is through VRR.
+// `nonstandard_rate_threshold` comes from the `video_vrr_nonstandard_rates` config value.
+pub fn is_vrr_ready(
+    monitor: &winit::monitor::MonitorHandle,
+    original_framerate: f64,
+    nonstandard_rate_threshold: usize,
+) -> bool {
+    let monitor_name = monitor.name();
+    modes_support_vrr(
+        monitor_name.as_deref(),
+        monitor
+            .video_modes()
+            .map(|video_mode| video_mode.refresh_rate_millihertz()),
+        original_framerate,
+        nonstandard_rate_threshold,
+        || vrr::check_vrr_status(monitor_name.as_deref()),
+    )
+}
+
+// The body of `is_vrr_ready`, over the refresh rates of the display's video modes in
+// millihertz. `vrr_status` asks the system whether VRR is on, if it can tell.
+fn modes_support_vrr(
+    monitor_name: Option<&str>,
+    refresh_rates_millihertz: impl Iterator<Item = u32>,
+    original_framerate: f64,
+    nonstandard_rate_threshold: usize,
+    vrr_status: impl FnOnce() -> Option<bool>,
+) -> bool {
+    // Modes without a known rate say nothing about the range
+    let refresh_rates: Vec<f64> = refresh_rates_millihertz
+        .map(|refresh_rate_millihertz| refresh_rate_millihertz as f64 / 1000.0)
+        .filter(|&refresh_rate| refresh_rate > 0.0)
+        .collect();
+    let Some((min_refresh_rate, max_refresh_rate)) = refresh_rate_range(&refresh_rates) else {
+        log_info!(
+            "Monitor '{}' lists no refresh rates, assuming no VRR",
+            monitor_name.unwrap_or_default()
+        );
+        return false;
+    };
+
+    log_info!(
         "Min and Max refresh rates for monitor '{}': {}Hz, {}Hz",
-        monitor.name().unwrap(),
+        monitor_name.unwrap_or_default(),
         min_refresh_rate,
         max_refresh_rate
     );
 
-    return count_not_divisible_by_ten > 1
-        && min_refresh_rate <= original_framerate
-        && original_framerate <= max_refresh_rate;
+    // Trust the system when it knows. A VRR range usually reaches well below the lowest
+    // listed mode, so only the top of the range limits which cores can use it
+    if let Some(vrr_capable) = vrr_status() {
+        return vrr_capable && original_framerate <= max_refresh_rate + FRAMERATE_TOLERANCE_HZ;
+    }
+
+    refresh_rates_suggest_vrr(
+        &refresh_rates,
+        original_framerate,
+        nonstandard_rate_threshold,
+    )
 }
 
 // Callback function that the libretro core will use to pass video frame data.
@@ -65,7 +442,11 @@ pub unsafe extern "C" fn libretro_set_video_refresh_callback(
     pitch: libc::size_t,
 ) {
     if frame_buffer_data.is_null() {
-        println!("frame_buffer_data was null");
+        log_debug!("frame_buffer_data was null");
+        return;
+    }
+    // Skipped and hidden frames are never shown, so they aren't copied or converted either
+    if SKIP_VIDEO.load(Ordering::SeqCst) || BLANK_VIDEO.load(Ordering::SeqCst) {
         return;
     }
 
@@ -75,132 +456,1103 @@ pub unsafe extern "C" fn libretro_set_video_refresh_callback(
         length_of_frame_buffer as usize,
     );
 
-    // Here, we just pass the raw frame buffer data without converting it
+    // Here, we just pass the raw frame buffer data; the conversion worker picks it up
+    let mut frame_buffer = FRAME_BUFFER_POOL.lock().unwrap().pop().unwrap_or_default();
+    frame_buffer.clear();
+    frame_buffer.extend_from_slice(buffer_slice);
     let video_data = VideoData {
-        frame_buffer: buffer_slice.to_vec(),
+        frame_buffer,
+        width,
+        height,
         pitch: pitch as u32,
     };
 
-    if let Err(e) = VIDEO_DATA_CHANNEL.0.send(video_data) {
-        eprintln!("Failed to send video data: {:?}", e);
-        // Handle error appropriately
+    let (pending_frame, frame_ready) = &*VIDEO_DATA_SLOT;
+    // A frame still waiting is stale by now, so it's overwritten instead of queued behind
+    match pending_frame.lock().unwrap().replace(video_data) {
+        Some(stale) => recycle_frame_buffer(stale.frame_buffer),
+        None => {
+            FRAMES_IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    frame_ready.notify_one();
+}
+
+// Whether the frame about to run is skipped, which the core can ask about through
+// ENVIRONMENT_GET_AUDIO_VIDEO_ENABLE to skip drawing it.
+pub fn set_skip_video(skip: bool) {
+    SKIP_VIDEO.store(skip, Ordering::SeqCst);
+}
+
+pub fn video_skipped() -> bool {
+    SKIP_VIDEO.load(Ordering::SeqCst)
+}
+
+// Reads `video_1555_channel_order`, "rgb" as libretro defines 0RGB1555, or "bgr".
+pub fn set_1555_channel_order(config: &HashMap<String, String>) {
+    let bgr = match config["video_1555_channel_order"].as_str() {
+        "rgb" => false,
+        "bgr" => true,
+        other => {
+            log_warn!("Invalid video_1555_channel_order '{}', using rgb", other);
+            false
+        }
+    };
+    BGR1555.store(bgr, Ordering::SeqCst);
+}
+
+// Whether the frame about to run is hidden, leaving the window as it was. The core isn't
+// told, so it draws the frame as usual.
+pub fn set_blank_video(blank: bool) {
+    BLANK_VIDEO.store(blank, Ordering::SeqCst);
+}
+
+pub fn set_core_rotation(quarter_turns: u32) {
+    CORE_ROTATION.store(quarter_turns % 4, Ordering::SeqCst);
+}
+
+// Turns the picture another quarter counterclockwise.
+pub fn rotate_display() {
+    USER_ROTATION.store(
+        (USER_ROTATION.load(Ordering::SeqCst) + 1) % 4,
+        Ordering::SeqCst,
+    );
+}
+
+// Total quarter turns counterclockwise frames are rotated by.
+pub fn rotation() -> u32 {
+    (CORE_ROTATION.load(Ordering::SeqCst) + USER_ROTATION.load(Ordering::SeqCst)) % 4
+}
+
+// Pixels cut off each edge of the core's frames, from `video_crop_top` and the like, to
+// hide the overscan area TVs never showed. Applied before rotation, so the edges are the
+// core's own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Crop {
+    pub top: u32,
+    pub bottom: u32,
+    pub left: u32,
+    pub right: u32,
+}
+
+impl Crop {
+    pub fn from_config(config: &HashMap<String, String>) -> Self {
+        let edge = |key: &str| config[key].trim().parse::<u32>().unwrap_or(0);
+        Crop {
+            top: edge("video_crop_top"),
+            bottom: edge("video_crop_bottom"),
+            left: edge("video_crop_left"),
+            right: edge("video_crop_right"),
+        }
+    }
+
+    // The size of a `width` x `height` frame once cropped. Crops that would leave
+    // nothing of the frame are ignored along that axis.
+    pub fn cropped_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let cropped_width = width
+            .checked_sub(self.left.saturating_add(self.right))
+            .filter(|&cropped| cropped > 0)
+            .unwrap_or(width);
+        let cropped_height = height
+            .checked_sub(self.top.saturating_add(self.bottom))
+            .filter(|&cropped| cropped > 0)
+            .unwrap_or(height);
+        (cropped_width, cropped_height)
+    }
+
+    // The aspect ratio of what's left of a picture shown at `aspect_ratio`.
+    pub fn cropped_aspect_ratio(&self, aspect_ratio: f64, width: u32, height: u32) -> f64 {
+        let (cropped_width, cropped_height) = self.cropped_size(width, height);
+        aspect_ratio * (cropped_width as f64 / width.max(1) as f64)
+            / (cropped_height as f64 / height.max(1) as f64)
+    }
+
+    fn apply(&self, frame: ConvertedFrame) -> ConvertedFrame {
+        let (width, height) = self.cropped_size(frame.width, frame.height);
+        if (width, height) == (frame.width, frame.height) {
+            return frame;
+        }
+        let left = if width == frame.width { 0 } else { self.left };
+        let top = if height == frame.height { 0 } else { self.top };
+        let row_length = width as usize * 4;
+        let mut pixels = Vec::with_capacity(row_length * height as usize);
+        for row in frame
+            .pixels
+            .chunks_exact(frame.width as usize * 4)
+            .skip(top as usize)
+            .take(height as usize)
+        {
+            let start = left as usize * 4;
+            pixels.extend_from_slice(&row[start..start + row_length]);
+        }
+        ConvertedFrame {
+            pixels,
+            width,
+            height,
+        }
+    }
+}
+
+// The shape of each pixel the core draws, from `video_pixel_aspect`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelAspect {
+    Core,       // Trust the display aspect ratio the core reports
+    Ratio(f64), // Pixels are this many times as wide as they are tall
+}
+
+impl PixelAspect {
+    // Accepts `core`, the presets `square`, `ntsc` (8:7, as on the NES and SNES) and `pal`,
+    // or a ratio written as `8:7` or `1.1428`.
+    pub fn from_config(value: &str) -> Option<Self> {
+        let value = value.trim().to_ascii_lowercase();
+        match value.as_str() {
+            "core" => return Some(PixelAspect::Core),
+            "square" => return Some(PixelAspect::Ratio(1.0)),
+            "ntsc" => return Some(PixelAspect::Ratio(8.0 / 7.0)),
+            "pal" => return Some(PixelAspect::Ratio(2_950_000.0 / 2_128_137.0)),
+            _ => {}
+        }
+        let ratio = match value.split_once(':') {
+            Some((width, height)) => {
+                width.trim().parse::<f64>().ok()? / height.trim().parse::<f64>().ok()?
+            }
+            None => value.parse::<f64>().ok()?,
+        };
+        (ratio.is_finite() && ratio > 0.0).then_some(PixelAspect::Ratio(ratio))
+    }
+}
+
+// The shape of the picture once rotated, which is the other way up after a quarter turn.
+pub fn rotated_aspect_ratio(aspect_ratio: f64) -> f64 {
+    if rotation() % 2 == 1 {
+        1.0 / aspect_ratio
+    } else {
+        aspect_ratio
+    }
+}
+
+// The size of a `width` x `height` picture once rotated.
+pub fn rotated_size(width: u32, height: u32) -> (u32, u32) {
+    if rotation() % 2 == 1 {
+        (height, width)
+    } else {
+        (width, height)
+    }
+}
+
+// Hands a frame buffer back for the video callback to copy a later frame into.
+fn recycle_frame_buffer(frame_buffer: Vec<u8>) {
+    let mut pool = FRAME_BUFFER_POOL.lock().unwrap();
+    if pool.len() < MAX_POOLED_FRAME_BUFFERS {
+        pool.push(frame_buffer);
+    }
+}
+
+// Reads a 16-bit pixel from a core frame buffer. Libretro hands these over as native-endian
+// uint16 values, so assembling the bytes in little-endian order would scramble the colors
+// on big-endian hosts.
+fn read_pixel16(frame_buffer: &[u8], index: usize) -> u16 {
+    u16::from_ne_bytes([frame_buffer[index], frame_buffer[index + 1]])
+}
+
+// A frame already converted to ARGB8888, ready to be copied into the `pixels` buffer.
+pub struct ConvertedFrame {
+    pub(crate) pixels: Vec<u8>,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+// Converts the core's frames to ARGB8888 on a worker thread, so the event loop only has
+// to copy finished frames into `pixels` and render them.
+pub struct FrameConverter {
+    worker: Option<JoinHandle<()>>,
+    running: Arc<AtomicBool>,
+    pixel_format: Arc<AtomicU32>, // Format of the frames the core sends, as a libretro value
+}
+
+impl FrameConverter {
+    pub fn spawn(pixel_format: &EmulatorPixelFormat, alpha_mode: AlphaMode, crop: Crop) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let pixel_format = Arc::new(AtomicU32::new(pixel_format.0 as u32));
+        let worker_running = Arc::clone(&running);
+        let worker_pixel_format = Arc::clone(&pixel_format);
+        let worker = thread::spawn(move || {
+            let (pending_frame, frame_ready) = &*VIDEO_DATA_SLOT;
+            while worker_running.load(Ordering::SeqCst) {
+                let mut pending_frame = pending_frame.lock().unwrap();
+                if pending_frame.is_none() {
+                    pending_frame = frame_ready
+                        .wait_timeout(pending_frame, WORKER_POLL_INTERVAL)
+                        .unwrap()
+                        .0;
+                }
+                let Some(video_data) = pending_frame.take() else {
+                    continue;
+                };
+                drop(pending_frame);
+                let mut converted = ConvertedFrame {
+                    pixels: vec![0; video_data.width as usize * video_data.height as usize * 4],
+                    width: video_data.width,
+                    height: video_data.height,
+                };
+                let pixel_format =
+                    PixelFormat::from_uint(worker_pixel_format.load(Ordering::SeqCst))
+                        .unwrap_or(PixelFormat::ARGB8888);
+                if !convert_frame(&video_data, pixel_format, alpha_mode, &mut converted.pixels) {
+                    recycle_frame_buffer(video_data.frame_buffer);
+                    FRAMES_IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+                    CONVERTED_CONDVAR.notify_one();
+                    continue;
+                }
+                if let Some(dump_path) = FRAME_DUMP_PATH.lock().unwrap().take() {
+                    write_frame_dump(dump_path, &video_data, pixel_format, &converted);
+                }
+                recycle_frame_buffer(video_data.frame_buffer);
+                converted = crop.apply(converted);
+                let quarter_turns = rotation();
+                if quarter_turns != 0 {
+                    converted = rotate_frame(&converted, quarter_turns);
+                }
+
+                // Keep the queue bounded when rendering falls behind, dropping the oldest frame
+                let mut converted_frames = CONVERTED_FRAMES.lock().unwrap();
+                if converted_frames.len() >= MAX_QUEUED_FRAMES {
+                    converted_frames.pop_front();
+                }
+                converted_frames.push_back(converted);
+                FRAMES_IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+                CONVERTED_CONDVAR.notify_one();
+            }
+        });
+        FrameConverter {
+            worker: Some(worker),
+            running,
+            pixel_format,
+        }
+    }
+
+    // Switches the format incoming frames are converted from.
+    pub fn set_pixel_format(&self, pixel_format: &EmulatorPixelFormat) {
+        self.pixel_format
+            .store(pixel_format.0 as u32, Ordering::SeqCst);
+    }
+
+    // Stops the worker and waits for it to finish the frame it's on.
+    pub fn shutdown(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                log_error!("Video conversion worker panicked");
+            }
+        }
     }
 }
 
 // Sets up the pixel format for the emulator based on the libretro core's specifications.
-pub fn set_up_pixel_format() -> (u8, EmulatorPixelFormat) {
-    let mut bpp = 2 as u8;
-    let mut set_format = PixelFormat::ARGB8888;
+pub fn set_up_pixel_format() -> EmulatorPixelFormat {
+    // Cores that never negotiate a format send 0RGB1555, the libretro default
+    let mut set_format = PixelFormat::ARGB1555;
 
     let pixel_format_receiver = &PIXEL_FORMAT_CHANNEL.1.lock().unwrap();
 
     for pixel_format in pixel_format_receiver.try_iter() {
-        bpp = match pixel_format {
-            PixelFormat::ARGB1555 | PixelFormat::RGB565 => 2,
-            PixelFormat::ARGB8888 => 4,
-        };
         set_format = pixel_format;
-        println!("Core will send us pixel data in format {:?}", pixel_format);
+        log_info!("Core will send us pixel data in format {:?}", pixel_format);
+    }
+
+    EmulatorPixelFormat(set_format)
+}
+
+// Size of one pixel in the given format. Everything sizing pixel data goes through this, so
+// the size can't disagree with the format.
+pub fn bytes_per_pixel(pixel_format: PixelFormat) -> u8 {
+    match pixel_format {
+        PixelFormat::ARGB1555 | PixelFormat::RGB565 => 2,
+        PixelFormat::ARGB8888 => 4,
+    }
+}
+
+// Rotates a converted frame counterclockwise by `quarter_turns`, swapping its width and
+// height for odd turns.
+fn rotate_frame(frame: &ConvertedFrame, quarter_turns: u32) -> ConvertedFrame {
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let (rotated_width, rotated_height) = if quarter_turns % 2 == 1 {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let mut pixels = vec![0; frame.pixels.len()];
+    for (index, pixel) in frame.pixels.chunks_exact(4).enumerate() {
+        let (x, y) = (index % width, index / width);
+        let (rotated_x, rotated_y) = match quarter_turns {
+            1 => (y, width - 1 - x),
+            2 => (width - 1 - x, height - 1 - y),
+            _ => (height - 1 - y, x),
+        };
+        let rotated_index = (rotated_y * rotated_width + rotated_x) * 4;
+        pixels[rotated_index..rotated_index + 4].copy_from_slice(pixel);
+    }
+    ConvertedFrame {
+        pixels,
+        width: rotated_width as u32,
+        height: rotated_height as u32,
+    }
+}
+
+// Converts a frame from the core's pixel format into the ARGB8888 `frame` buffer row by row.
+// Returns false, leaving `frame` as it was, if the frame can't be in that format.
+fn convert_frame(
+    video_data: &VideoData,
+    pixel_format: PixelFormat,
+    alpha_mode: AlphaMode,
+    frame: &mut [u8],
+) -> bool {
+    let width = video_data.width as usize;
+    let pitch = video_data.pitch as usize; // number of bytes per row
+    let bytes_per_pixel = bytes_per_pixel(pixel_format) as usize;
+    let alpha_mask: u32 = match alpha_mode {
+        AlphaMode::Opaque => 0xFF000000,
+        AlphaMode::Preserve => 0,
+    };
+    // Rows shorter than the width in this format mean the core sent another format than it
+    // negotiated, and reading them as this one would scramble the picture
+    if pitch < width * bytes_per_pixel {
+        if !PITCH_WARNED.swap(true, Ordering::SeqCst) {
+            log_warn!(
+                "Dropping {}x{} frames with a pitch of {} bytes, too short for {:?}",
+                width,
+                video_data.height,
+                pitch,
+                pixel_format
+            );
+        }
+        return false;
+    }
+
+    let dest_rows = frame.chunks_exact_mut(width * 4); // 4 bytes per pixel for ARGB8888
+    for (y, dest_row) in dest_rows.take(video_data.height as usize).enumerate() {
+        // Ensure we're not going out of bounds
+        let row_start = y * pitch;
+        let Some(source_row) = video_data
+            .frame_buffer
+            .get(row_start..row_start + width * bytes_per_pixel)
+        else {
+            break;
+        };
+        match pixel_format {
+            PixelFormat::RGB565 => convert_rgb565_row(source_row, dest_row),
+            PixelFormat::ARGB1555 => convert_argb1555_row(source_row, dest_row, alpha_mask),
+            PixelFormat::ARGB8888 => {
+                // Copy the ARGB8888 pixels, replacing the padding byte if asked to
+                for (source, dest) in source_row.chunks_exact(4).zip(dest_row.chunks_exact_mut(4)) {
+                    let argb8888 = u32::from_ne_bytes([source[0], source[1], source[2], source[3]])
+                        | alpha_mask;
+                    write_pixel(dest, argb8888);
+                }
+            }
+        }
+    }
+    true
+}
+
+// Converts a row of RGB565 pixels to ARGB8888, using SIMD for as much of it as the CPU allows.
+pub(crate) fn convert_rgb565_row(source: &[u8], dest: &mut [u8]) {
+    #[cfg(target_arch = "x86_64")]
+    let converted = unsafe {
+        if is_x86_feature_detected!("avx2") {
+            simd::rgb565_row_avx2(source, dest)
+        } else {
+            simd::rgb565_row_sse2(source, dest)
+        }
+    };
+    #[cfg(not(target_arch = "x86_64"))]
+    let converted = 0;
+
+    // The lookup table finishes whatever the SIMD path left over
+    rgb565_row_from_table(source, dest, converted);
+}
+
+// Converts the RGB565 pixels of a row from `start` on through the lookup table.
+pub(crate) fn rgb565_row_from_table(source: &[u8], dest: &mut [u8], start: usize) {
+    for x in start..dest.len() / 4 {
+        let rgb565 = read_pixel16(source, x * 2);
+        let argb8888 = RGB565_TO_ARGB8888_TABLE[rgb565 as usize];
+        write_pixel(&mut dest[x * 4..x * 4 + 4], argb8888);
+    }
+}
+
+// Converts a row of ARGB1555 pixels to ARGB8888, using SIMD for as much of it as the CPU allows.
+pub(crate) fn convert_argb1555_row(source: &[u8], dest: &mut [u8], alpha_mask: u32) {
+    // The SIMD path only knows the RGB order, so BGR rows all go through the table
+    let bgr = BGR1555.load(Ordering::SeqCst);
+    #[cfg(target_arch = "x86_64")]
+    let converted = if bgr {
+        0
+    } else {
+        unsafe {
+            if is_x86_feature_detected!("avx2") {
+                simd::argb1555_row_avx2(source, dest, alpha_mask)
+            } else {
+                simd::argb1555_row_sse2(source, dest, alpha_mask)
+            }
+        }
+    };
+    #[cfg(not(target_arch = "x86_64"))]
+    let converted = 0;
+
+    // The lookup table finishes whatever the SIMD path left over
+    argb1555_row_from_table(source, dest, converted, bgr, alpha_mask);
+}
+
+// Converts the ARGB1555 pixels of a row from `start` on through the lookup table for the
+// given channel order.
+pub(crate) fn argb1555_row_from_table(
+    source: &[u8],
+    dest: &mut [u8],
+    start: usize,
+    bgr: bool,
+    alpha_mask: u32,
+) {
+    let table = if bgr {
+        &ABGR1555_TO_ARGB8888_TABLE
+    } else {
+        &ARGB1555_TO_ARGB8888_TABLE
+    };
+    for x in start..dest.len() / 4 {
+        let argb1555 = read_pixel16(source, x * 2);
+        let argb8888 = table[argb1555 as usize] | alpha_mask;
+        write_pixel(&mut dest[x * 4..x * 4 + 4], argb8888);
+    }
+}
+
+// SIMD versions of the lookup tables. They use the same integer formulas the tables are
+// built from, so the output is bit-identical. Each function converts whole blocks of
+// pixels and returns how many it handled, leaving the rest of the row to the caller.
+// x86_64 is little-endian, so the core's pixels can be loaded directly, and the packed
+// results already have the B, G, R, A byte order `FRAME_TEXTURE_FORMAT` expects.
+#[cfg(target_arch = "x86_64")]
+mod simd {
+    use std::arch::x86_64::*;
+
+    // Expands 5-bit channels to 8 bits: (c * 527 + 23) >> 6.
+    #[target_feature(enable = "sse2")]
+    unsafe fn expand5_sse2(channel: __m128i) -> __m128i {
+        _mm_srli_epi16(
+            _mm_add_epi16(
+                _mm_mullo_epi16(channel, _mm_set1_epi16(527)),
+                _mm_set1_epi16(23),
+            ),
+            6,
+        )
+    }
+
+    // Expands 6-bit channels to 8 bits: (c * 259 + 33) >> 6.
+    #[target_feature(enable = "sse2")]
+    unsafe fn expand6_sse2(channel: __m128i) -> __m128i {
+        _mm_srli_epi16(
+            _mm_add_epi16(
+                _mm_mullo_epi16(channel, _mm_set1_epi16(259)),
+                _mm_set1_epi16(33),
+            ),
+            6,
+        )
+    }
+
+    // Packs 16-bit B, G, R and A lanes into eight ARGB8888 pixels.
+    #[target_feature(enable = "sse2")]
+    unsafe fn store_argb_sse2(dest: *mut u8, b: __m128i, g: __m128i, r: __m128i, a: __m128i) {
+        let blue_green = _mm_or_si128(b, _mm_slli_epi16(g, 8));
+        let red_alpha = _mm_or_si128(r, _mm_slli_epi16(a, 8));
+        _mm_storeu_si128(
+            dest as *mut __m128i,
+            _mm_unpacklo_epi16(blue_green, red_alpha),
+        );
+        _mm_storeu_si128(
+            dest.add(16) as *mut __m128i,
+            _mm_unpackhi_epi16(blue_green, red_alpha),
+        );
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn rgb565_row_sse2(source: &[u8], dest: &mut [u8]) -> usize {
+        let blocks = (source.len() / 2).min(dest.len() / 4) / 8;
+        let mask5 = _mm_set1_epi16(0x1F);
+        let mask6 = _mm_set1_epi16(0x3F);
+        let alpha = _mm_set1_epi16(0xFF);
+        for block in 0..blocks {
+            let pixels = _mm_loadu_si128(source.as_ptr().add(block * 16) as *const __m128i);
+            let r = expand5_sse2(_mm_and_si128(_mm_srli_epi16(pixels, 11), mask5));
+            let g = expand6_sse2(_mm_and_si128(_mm_srli_epi16(pixels, 5), mask6));
+            let b = expand5_sse2(_mm_and_si128(pixels, mask5));
+            store_argb_sse2(dest.as_mut_ptr().add(block * 32), b, g, r, alpha);
+        }
+        blocks * 8
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn argb1555_row_sse2(source: &[u8], dest: &mut [u8], alpha_mask: u32) -> usize {
+        let blocks = (source.len() / 2).min(dest.len() / 4) / 8;
+        let mask5 = _mm_set1_epi16(0x1F);
+        let forced_alpha = _mm_set1_epi16((alpha_mask >> 24) as i16);
+        for block in 0..blocks {
+            let pixels = _mm_loadu_si128(source.as_ptr().add(block * 16) as *const __m128i);
+            let a = _mm_or_si128(
+                _mm_mullo_epi16(_mm_srli_epi16(pixels, 15), _mm_set1_epi16(255)),
+                forced_alpha,
+            );
+            let r = expand5_sse2(_mm_and_si128(_mm_srli_epi16(pixels, 10), mask5));
+            let g = expand5_sse2(_mm_and_si128(_mm_srli_epi16(pixels, 5), mask5));
+            let b = expand5_sse2(_mm_and_si128(pixels, mask5));
+            store_argb_sse2(dest.as_mut_ptr().add(block * 32), b, g, r, a);
+        }
+        blocks * 8
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn expand5_avx2(channel: __m256i) -> __m256i {
+        _mm256_srli_epi16(
+            _mm256_add_epi16(
+                _mm256_mullo_epi16(channel, _mm256_set1_epi16(527)),
+                _mm256_set1_epi16(23),
+            ),
+            6,
+        )
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn expand6_avx2(channel: __m256i) -> __m256i {
+        _mm256_srli_epi16(
+            _mm256_add_epi16(
+                _mm256_mullo_epi16(channel, _mm256_set1_epi16(259)),
+                _mm256_set1_epi16(33),
+            ),
+            6,
+        )
+    }
+
+    // Packs 16-bit B, G, R and A lanes into sixteen ARGB8888 pixels. AVX2 unpacks within
+    // each 128-bit half, so the halves are swapped back into pixel order before storing.
+    #[target_feature(enable = "avx2")]
+    unsafe fn store_argb_avx2(dest: *mut u8, b: __m256i, g: __m256i, r: __m256i, a: __m256i) {
+        let blue_green = _mm256_or_si256(b, _mm256_slli_epi16(g, 8));
+        let red_alpha = _mm256_or_si256(r, _mm256_slli_epi16(a, 8));
+        let low = _mm256_unpacklo_epi16(blue_green, red_alpha);
+        let high = _mm256_unpackhi_epi16(blue_green, red_alpha);
+        _mm256_storeu_si256(
+            dest as *mut __m256i,
+            _mm256_permute2x128_si256(low, high, 0x20),
+        );
+        _mm256_storeu_si256(
+            dest.add(32) as *mut __m256i,
+            _mm256_permute2x128_si256(low, high, 0x31),
+        );
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn rgb565_row_avx2(source: &[u8], dest: &mut [u8]) -> usize {
+        let blocks = (source.len() / 2).min(dest.len() / 4) / 16;
+        let mask5 = _mm256_set1_epi16(0x1F);
+        let mask6 = _mm256_set1_epi16(0x3F);
+        let alpha = _mm256_set1_epi16(0xFF);
+        for block in 0..blocks {
+            let pixels = _mm256_loadu_si256(source.as_ptr().add(block * 32) as *const __m256i);
+            let r = expand5_avx2(_mm256_and_si256(_mm256_srli_epi16(pixels, 11), mask5));
+            let g = expand6_avx2(_mm256_and_si256(_mm256_srli_epi16(pixels, 5), mask6));
+            let b = expand5_avx2(_mm256_and_si256(pixels, mask5));
+            store_argb_avx2(dest.as_mut_ptr().add(block * 64), b, g, r, alpha);
+        }
+        // Let the SSE2 path pick up a final block of eight
+        let converted = blocks * 16;
+        converted + rgb565_row_sse2(&source[converted * 2..], &mut dest[converted * 4..])
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn argb1555_row_avx2(source: &[u8], dest: &mut [u8], alpha_mask: u32) -> usize {
+        let blocks = (source.len() / 2).min(dest.len() / 4) / 16;
+        let mask5 = _mm256_set1_epi16(0x1F);
+        let forced_alpha = _mm256_set1_epi16((alpha_mask >> 24) as i16);
+        for block in 0..blocks {
+            let pixels = _mm256_loadu_si256(source.as_ptr().add(block * 32) as *const __m256i);
+            let a = _mm256_or_si256(
+                _mm256_mullo_epi16(_mm256_srli_epi16(pixels, 15), _mm256_set1_epi16(255)),
+                forced_alpha,
+            );
+            let r = expand5_avx2(_mm256_and_si256(_mm256_srli_epi16(pixels, 10), mask5));
+            let g = expand5_avx2(_mm256_and_si256(_mm256_srli_epi16(pixels, 5), mask5));
+            let b = expand5_avx2(_mm256_and_si256(pixels, mask5));
+            store_argb_avx2(dest.as_mut_ptr().add(block * 64), b, g, r, a);
+        }
+        // Let the SSE2 path pick up a final block of eight
+        let converted = blocks * 16;
+        converted
+            + argb1555_row_sse2(
+                &source[converted * 2..],
+                &mut dest[converted * 4..],
+                alpha_mask,
+            )
     }
+}
 
-    (bpp, EmulatorPixelFormat(set_format))
+// Takes the newest frame converted by the worker, giving it a moment to finish the frame the
+// core just produced. Only the newest frame is worth showing, so older ones are dropped.
+pub(crate) fn take_newest_frame() -> Option<ConvertedFrame> {
+    let mut converted_frames = CONVERTED_FRAMES.lock().unwrap();
+    let deadline = Instant::now() + CONVERSION_WAIT;
+    while converted_frames.is_empty() && FRAMES_IN_FLIGHT.load(Ordering::SeqCst) > 0 {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        converted_frames = CONVERTED_CONDVAR
+            .wait_timeout(converted_frames, remaining)
+            .unwrap()
+            .0;
+    }
+    let newest = converted_frames.pop_back();
+    converted_frames.clear();
+    newest
 }
 
+// Shows the newest frame converted by the worker.
+// When the core changes resolution the buffer is resized and the new size is written
+// back to `video_height`/`video_width`.
 pub fn render_frame(
     pixels: &mut Pixels,
-    current_state: &EmulatorState,
-    video_height: u32,
-    video_width: u32,
+    frame_renderer: &mut FrameRenderer,
+    perf_overlay: &PerfOverlay,
+    video_height: &mut u32,
+    video_width: &mut u32,
 ) -> ControlFlow {
-    let mut rgb565_to_rgb8888_table: [u32; 65536] = [0; 65536];
-    for i in 0..65536 {
-        let r = (i >> 11) & 0x1F;
-        let g = (i >> 5) & 0x3F;
-        let b = i & 0x1F;
+    let Some(converted) = take_newest_frame() else {
+        return ControlFlow::Poll;
+    };
 
-        let r = ((r * 527 + 23) >> 6) as u32;
-        let g = ((g * 259 + 33) >> 6) as u32;
-        let b = ((b * 527 + 23) >> 6) as u32;
+    // Follow resolution changes, only reallocating when the size actually differs
+    if converted.width != *video_width || converted.height != *video_height {
+        if let Err(err) = pixels.resize_buffer(converted.width, converted.height) {
+            log_error!("Failed to resize frame buffer: {}", err);
+            return ControlFlow::Exit;
+        }
+        log_info!(
+            "Core resolution changed to {}x{}",
+            converted.width,
+            converted.height
+        );
+        *video_width = converted.width;
+        *video_height = converted.height;
+        frame_renderer.update_texture(pixels);
+    }
 
-        rgb565_to_rgb8888_table[i] = 0xFF000000 | (r << 16) | (g << 8) | b;
+    pixels.frame_mut().copy_from_slice(&converted.pixels);
+    perf_overlay.draw(
+        pixels.frame_mut(),
+        converted.width as usize,
+        converted.height as usize,
+    );
+    overlay::draw_messages(
+        pixels.frame_mut(),
+        converted.width as usize,
+        converted.height as usize,
+    );
+    // Render the frame buffer
+    if frame_renderer.redraw(pixels).is_err() {
+        return ControlFlow::Exit;
     }
+    ControlFlow::Poll
+}
 
-    let mut argb1555_to_argb8888_table: [u32; 32768] = [0; 32768];
-    for i in 0..32768 {
-        let a = (i >> 15) & 0x01;
-        let r = (i >> 10) & 0x1F;
-        let g = (i >> 5) & 0x1F;
-        let b = i & 0x1F;
+// Writes the displayed frame to a timestamped PNG in `screenshot_directory`.
+// The frame holds pixels stored by `write_pixel`, which are reordered into opaque RGBA.
+// Encoding happens on its own thread so the render thread only pays for the copy.
+pub fn save_screenshot(
+    frame: &[u8],
+    width: u32,
+    height: u32,
+    screenshot_directory: &str,
+    rom_name: &str,
+) {
+    let rgba: Vec<u8> = frame
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            let argb = read_pixel(pixel);
+            [(argb >> 16) as u8, (argb >> 8) as u8, argb as u8, 0xFF]
+        })
+        .collect();
 
-        let a = (a * 255) as u32;
-        let r = ((r * 527 + 23) >> 6) as u32;
-        let g = ((g * 527 + 23) >> 6) as u32;
-        let b = ((b * 527 + 23) >> 6) as u32;
+    let (screenshot_dir, screenshot_path) = capture_path(screenshot_directory, rom_name, "png");
+    thread::spawn(move || {
+        let result = fs::create_dir_all(&screenshot_dir)
+            .map_err(|e| e.to_string())
+            .and_then(|_| File::create(&screenshot_path).map_err(|e| e.to_string()))
+            .and_then(|file| {
+                let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+                encoder.set_color(png::ColorType::Rgba);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder
+                    .write_header()
+                    .and_then(|mut writer| writer.write_image_data(&rgba))
+                    .map_err(|e| e.to_string())
+            });
+        match result {
+            Ok(_) => log_info!("Screenshot saved to: {}", screenshot_path.display()),
+            Err(err) => log_warn!("Failed to save screenshot: {}", err),
+        }
+    });
+}
+
+// Picks a timestamped file in `directory` named after the game, returning the directory
+// too so it can be created first.
+fn capture_path(directory: &str, rom_name: &str, extension: &str) -> (PathBuf, PathBuf) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let game_name = Path::new(rom_name)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .replace(' ', "_");
+    let directory = PathBuf::from(shellexpand::tilde(directory).into_owned());
+    let path = directory.join(format!("{}_{}.{}", game_name, timestamp, extension));
+    (directory, path)
+}
+
+// Dumps the next frame the core sends, before and after conversion, to a `.framedump`
+// file in `directory`, for comparing what the core sent with what we made of it.
+//
+// File layout, little-endian:
+//   magic "RRFD", width (u32), height (u32), pitch (u32), libretro pixel format (u32),
+//   then pitch * height bytes exactly as the core sent them,
+//   then width * height converted pixels, each an ARGB8888 u32 (before any rotation).
+pub fn request_frame_dump(directory: &str, rom_name: &str) {
+    let (dump_dir, dump_path) = capture_path(directory, rom_name, "framedump");
+    if let Err(err) = fs::create_dir_all(&dump_dir) {
+        log_warn!("Failed to dump frame: {}", err);
+        return;
+    }
+    *FRAME_DUMP_PATH.lock().unwrap() = Some(dump_path);
+}
 
-        argb1555_to_argb8888_table[i] = (a << 24) | (r << 16) | (g << 8) | b;
+fn write_frame_dump(
+    dump_path: PathBuf,
+    video_data: &VideoData,
+    pixel_format: PixelFormat,
+    converted: &ConvertedFrame,
+) {
+    let mut dump = Vec::with_capacity(20 + video_data.frame_buffer.len() + converted.pixels.len());
+    dump.extend_from_slice(FRAME_DUMP_MAGIC);
+    for value in [
+        video_data.width,
+        video_data.height,
+        video_data.pitch,
+        pixel_format as u32,
+    ] {
+        dump.extend_from_slice(&value.to_le_bytes());
     }
+    dump.extend_from_slice(&video_data.frame_buffer);
+    for pixel in converted.pixels.chunks_exact(4) {
+        dump.extend_from_slice(&read_pixel(pixel).to_le_bytes());
+    }
+    thread::spawn(move || match fs::write(&dump_path, dump) {
+        Ok(_) => log_info!("Frame dumped to: {}", dump_path.display()),
+        Err(err) => log_warn!("Failed to dump frame: {}", err),
+    });
+}
 
-    // Copy the emulator frame data to the `pixels` frame
-    let video_data_receiver = VIDEO_DATA_CHANNEL.1.lock().unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Iterate over the video data received from the core
-    for video_data in video_data_receiver.try_iter() {
-        // Extract the video data dimensions
-        let pitch = video_data.pitch as usize; // number of bytes per row
+    // Lays 16-bit pixels out the way a core on this host hands them over.
+    fn pixels16(values: &[u16]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect()
+    }
+
+    // Converts a single row of `width` pixels and reads the ARGB8888 result back.
+    fn convert_row(
+        source: &[u8],
+        width: usize,
+        pixel_format: PixelFormat,
+        alpha_mode: AlphaMode,
+    ) -> Vec<u32> {
+        let video_data = VideoData {
+            frame_buffer: source.to_vec(),
+            width: width as u32,
+            height: 1,
+            pitch: (width * bytes_per_pixel(pixel_format) as usize) as u32,
+        };
+        let mut frame = vec![0; width * 4];
+        assert!(convert_frame(
+            &video_data,
+            pixel_format,
+            alpha_mode,
+            &mut frame
+        ));
+        frame.chunks_exact(4).map(read_pixel).collect()
+    }
 
-        // Get the pixels frame buffer
-        let frame = pixels.frame_mut();
+    // Repeats `pattern` over 40 pixels, enough for a full SIMD block plus a remainder.
+    fn repeated<T: Copy>(pattern: &[T]) -> Vec<T> {
+        pattern.iter().copied().cycle().take(40).collect()
+    }
 
-        // Assuming `current_state.pixel_format.0` gives you the source format...
-        let bytes_per_pixel_source = current_state.bytes_per_pixel as usize;
+    #[test]
+    fn converts_rgb565() {
+        let source = repeated(&[0xF800, 0x07E0, 0x001F, 0xFFFF, 0x0000]);
+        let expected = repeated(&[0xFFFF0000, 0xFF00FF00, 0xFF0000FF, 0xFFFFFFFF, 0xFF000000]);
+        let converted = convert_row(
+            &pixels16(&source),
+            source.len(),
+            PixelFormat::RGB565,
+            AlphaMode::Opaque,
+        );
+        assert_eq!(converted, expected);
+    }
 
-        for y in 0..video_height as usize {
-            for x in 0..(video_width as usize) {
-                let source_index = y * pitch + x * bytes_per_pixel_source;
-                let dest_index = (y * video_width as usize + x) * 4; // 4 bytes per pixel for ARGB8888
+    #[test]
+    fn converts_argb1555() {
+        let source = repeated(&[0x7C00, 0x03E0, 0x001F, 0xFC00, 0x0000]);
+        let opaque = convert_row(
+            &pixels16(&source),
+            source.len(),
+            PixelFormat::ARGB1555,
+            AlphaMode::Opaque,
+        );
+        assert_eq!(
+            opaque,
+            repeated(&[0xFFFF0000, 0xFF00FF00, 0xFF0000FF, 0xFFFF0000, 0xFF000000])
+        );
+        let preserved = convert_row(
+            &pixels16(&source),
+            source.len(),
+            PixelFormat::ARGB1555,
+            AlphaMode::Preserve,
+        );
+        assert_eq!(
+            preserved,
+            repeated(&[0x00FF0000, 0x0000FF00, 0x000000FF, 0xFFFF0000, 0x00000000])
+        );
+    }
 
-                // Ensure we're not going out of bounds
-                if source_index >= video_data.frame_buffer.len() || dest_index >= frame.len() {
-                    break;
-                }
+    #[test]
+    fn converts_argb8888() {
+        let source: Vec<u8> = [0x00123456u32, 0x80FF0000]
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect();
+        let opaque = convert_row(&source, 2, PixelFormat::ARGB8888, AlphaMode::Opaque);
+        assert_eq!(opaque, [0xFF123456, 0xFFFF0000]);
+        let preserved = convert_row(&source, 2, PixelFormat::ARGB8888, AlphaMode::Preserve);
+        assert_eq!(preserved, [0x00123456, 0x80FF0000]);
+    }
 
-                match current_state.pixel_format.0 {
-                    PixelFormat::RGB565 => {
-                        // Convert RGB565 to ARGB8888
-                        let first_byte = video_data.frame_buffer[source_index];
-                        let second_byte = video_data.frame_buffer[source_index + 1];
-                        let rgb565 = (first_byte as u16) | ((second_byte as u16) << 8);
-
-                        // Look up the converted pixel in the table
-                        let argb8888 = rgb565_to_rgb8888_table[rgb565 as usize];
-
-                        // Copy the converted pixel into the frame buffer
-                        frame[dest_index..dest_index + 4].copy_from_slice(&argb8888.to_ne_bytes());
-                    }
-                    PixelFormat::ARGB1555 => {
-                        // Convert ARGB1555 to ARGB8888
-                        let first_byte = video_data.frame_buffer[source_index];
-                        let second_byte = video_data.frame_buffer[source_index + 1];
-                        let argb1555 = (first_byte as u16) | ((second_byte as u16) << 8);
-
-                        // Look up the converted pixel in the table
-                        let argb8888 = argb1555_to_argb8888_table[argb1555 as usize];
-
-                        // Copy the converted pixel into the frame buffer
-                        frame[dest_index..dest_index + 4].copy_from_slice(&argb8888.to_ne_bytes());
-                    }
-                    PixelFormat::ARGB8888 => {
-                        // Directly copy ARGB8888 pixel
-                        let source_slice = &video_data.frame_buffer[source_index..source_index + 4];
-                        frame[dest_index..dest_index + 4].copy_from_slice(source_slice);
-                    }
-                }
-            }
+    #[test]
+    fn rejects_frames_too_short_for_the_format() {
+        // Two bytes a pixel is RGB565's pitch, not ARGB8888's
+        let video_data = VideoData {
+            frame_buffer: vec![0xAB; 8],
+            width: 4,
+            height: 1,
+            pitch: 8,
+        };
+        let mut frame = vec![0; 16];
+        assert!(!convert_frame(
+            &video_data,
+            PixelFormat::ARGB8888,
+            AlphaMode::Opaque,
+            &mut frame
+        ));
+        assert_eq!(frame, vec![0; 16]);
+    }
+
+    #[test]
+    fn reads_16_bit_pixels_in_host_byte_order() {
+        // Pure red in RGB565, laid out as a core on either kind of host would send it
+        let red: u16 = 0xF800;
+        #[cfg(target_endian = "little")]
+        let frame_buffer = red.to_le_bytes();
+        #[cfg(target_endian = "big")]
+        let frame_buffer = red.to_be_bytes();
+        assert_eq!(frame_buffer, red.to_ne_bytes());
+
+        let pixel = read_pixel16(&frame_buffer, 0);
+        assert_eq!(pixel, red);
+        assert_eq!(RGB565_TO_ARGB8888_TABLE[pixel as usize], 0xFFFF0000);
+    }
+
+    #[test]
+    fn foreign_byte_order_scrambles_16_bit_pixels() {
+        // The same red sent in the other host's byte order reads back as a different color
+        #[cfg(target_endian = "little")]
+        let frame_buffer = 0xF800u16.to_be_bytes();
+        #[cfg(target_endian = "big")]
+        let frame_buffer = 0xF800u16.to_le_bytes();
+
+        let pixel = read_pixel16(&frame_buffer, 0);
+        assert_eq!(pixel, 0x00F8);
+        assert_ne!(RGB565_TO_ARGB8888_TABLE[pixel as usize], 0xFFFF0000);
+    }
+
+    // Every 16-bit value in order, as one long row
+    fn every_pixel16() -> Vec<u8> {
+        pixels16(&(0..=u16::MAX).collect::<Vec<_>>())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn simd_rgb565_matches_the_table() {
+        let source = every_pixel16();
+        let mut expected = vec![0; source.len() * 2];
+        rgb565_row_from_table(&source, &mut expected, 0);
+
+        let mut sse2 = vec![0; expected.len()];
+        assert_eq!(unsafe { simd::rgb565_row_sse2(&source, &mut sse2) }, 65536);
+        assert!(sse2 == expected, "SSE2 RGB565 differs from the table");
+
+        if is_x86_feature_detected!("avx2") {
+            let mut avx2 = vec![0; expected.len()];
+            assert_eq!(unsafe { simd::rgb565_row_avx2(&source, &mut avx2) }, 65536);
+            assert!(avx2 == expected, "AVX2 RGB565 differs from the table");
         }
-        // Render the frame buffer
-        if pixels.render().is_err() {
-            return ControlFlow::Exit;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn simd_argb1555_matches_the_table() {
+        let source = every_pixel16();
+        for alpha_mask in [0xFF000000, 0] {
+            let mut expected = vec![0; source.len() * 2];
+            argb1555_row_from_table(&source, &mut expected, 0, false, alpha_mask);
+
+            let mut sse2 = vec![0; expected.len()];
+            assert_eq!(
+                unsafe { simd::argb1555_row_sse2(&source, &mut sse2, alpha_mask) },
+                65536
+            );
+            assert!(sse2 == expected, "SSE2 ARGB1555 differs from the table");
+
+            if is_x86_feature_detected!("avx2") {
+                let mut avx2 = vec![0; expected.len()];
+                assert_eq!(
+                    unsafe { simd::argb1555_row_avx2(&source, &mut avx2, alpha_mask) },
+                    65536
+                );
+                assert!(avx2 == expected, "AVX2 ARGB1555 differs from the table");
+            }
         }
     }
-    return ControlFlow::Poll;
-}
\ No newline at end of file
+
+    #[test]
+    fn sizes_pixels_by_format() {
+        assert_eq!(bytes_per_pixel(PixelFormat::ARGB8888), 4);
+        assert_eq!(bytes_per_pixel(PixelFormat::RGB565), 2);
+        assert_eq!(bytes_per_pixel(PixelFormat::ARGB1555), 2);
+        assert_eq!(
+            EmulatorPixelFormat(PixelFormat::RGB565).bytes_per_pixel(),
+            2
+        );
+        assert_eq!(EmulatorPixelFormat::default().bytes_per_pixel(), 4);
+    }
+
+    #[test]
+    fn a_single_refresh_rate_is_not_vrr() {
+        assert!(!refresh_rates_suggest_vrr(&[60.0], 60.0, 2));
+        // Even a non-standard one is a single fixed rate
+        assert!(!refresh_rates_suggest_vrr(&[59.95], 59.95, 2));
+    }
+
+    #[test]
+    fn standard_refresh_rates_are_not_vrr() {
+        // The same rates repeat for every resolution, NTSC variants included
+        let refresh_rates = [60.0, 59.94, 120.0, 144.0, 60.0, 59.94, 120.0, 144.0];
+        assert!(!refresh_rates_suggest_vrr(&refresh_rates, 60.0, 2));
+    }
+
+    #[test]
+    fn vrr_like_refresh_rates_suggest_vrr() {
+        let refresh_rates = [48.0, 53.7, 60.0, 75.0, 97.3, 119.88, 121.7, 144.0];
+        assert!(refresh_rates_suggest_vrr(&refresh_rates, 53.7, 2));
+        assert!(refresh_rates_suggest_vrr(&refresh_rates, 60.1, 2));
+        // Unless the core runs outside the range or the threshold asks for more rates
+        assert!(!refresh_rates_suggest_vrr(&refresh_rates, 47.0, 2));
+        assert!(!refresh_rates_suggest_vrr(&refresh_rates, 150.0, 2));
+        assert!(!refresh_rates_suggest_vrr(&refresh_rates, 60.0, 4));
+    }
+
+    #[test]
+    fn repeated_nonstandard_refresh_rates_count_once() {
+        let refresh_rates = [53.7, 53.7, 53.7, 60.0];
+        assert!(!refresh_rates_suggest_vrr(&refresh_rates, 60.0, 2));
+        assert!(refresh_rates_suggest_vrr(&refresh_rates, 60.0, 1));
+    }
+
+    #[test]
+    fn a_display_without_video_modes_is_not_vrr_ready() {
+        let ready = modes_support_vrr(Some("DP-1"), std::iter::empty(), 60.0, 2, || {
+            panic!("the system shouldn't be asked about a display without modes")
+        });
+        assert!(!ready);
+        // Modes of unknown rate are as good as none
+        assert!(!modes_support_vrr(
+            None,
+            [0, 0].into_iter(),
+            60.0,
+            2,
+            || Some(true)
+        ));
+    }
+
+    #[test]
+    fn the_system_vrr_status_wins_over_the_heuristic() {
+        let modes = [60000, 120000, 144000];
+        assert!(modes_support_vrr(
+            None,
+            modes.into_iter(),
+            60.0,
+            2,
+            || Some(true)
+        ));
+        assert!(!modes_support_vrr(None, modes.into_iter(), 60.0, 2, || {
+            Some(false)
+        }));
+        // Though the core can't run faster than the display
+        assert!(!modes_support_vrr(
+            None,
+            modes.into_iter(),
+            165.0,
+            2,
+            || Some(true)
+        ));
+    }
+
+    #[test]
+    fn writes_pixels_as_bgra_bytes() {
+        let mut dest = [0; 4];
+        write_pixel(&mut dest, 0xFFFF0000);
+        // Red lands in the texture's R byte, not its B byte
+        assert_eq!(dest, [0x00, 0x00, 0xFF, 0xFF]);
+        assert_eq!(read_pixel(&dest), 0xFFFF0000);
+
+        write_pixel(&mut dest, 0x800000FF);
+        assert_eq!(dest, [0xFF, 0x00, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn converts_1555_in_either_channel_order() {
+        let rgb = argb1555_table(false);
+        assert_eq!(rgb[0x7C00], 0x00FF0000); // red
+        assert_eq!(rgb[0x001F], 0x000000FF); // blue
+
+        // Swapped, the same bits mean the opposite colors
+        let bgr = argb1555_table(true);
+        assert_eq!(bgr[0x7C00], 0x000000FF);
+        assert_eq!(bgr[0x001F], 0x00FF0000);
+
+        // Green and alpha don't move
+        assert_eq!(rgb[0x83E0], 0xFF00FF00);
+        assert_eq!(bgr[0x83E0], 0xFF00FF00);
+    }
+}
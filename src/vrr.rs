@@ -0,0 +1,238 @@
+// This implementation is based on the guide provided by [RetroGameDeveloper/RetroReversing].
+// Original guide can be found at [https://www.retroreversing.com/CreateALibRetroFrontEndInRust].
+// Copyright (c) 2023 Nicholas Ricciuti
+//
+// vrr.rs
+//
+// This module asks the operating system whether a display supports variable refresh rate.
+// On Linux that's the `vrr_capable` property the kernel's DRM driver sets on each connector.
+// Windows has no API that reports VRR capability, only whether tearing is allowed, so
+// there, like everywhere else, the caller falls back to guessing from the refresh rates.
+
+// Returns whether the display named `monitor_name` supports VRR, or `None` if the system
+// can't tell us.
+#[cfg(target_os = "linux")]
+pub fn check_vrr_status(monitor_name: Option<&str>) -> Option<bool> {
+    let connectors = drm::connected_connectors();
+    // Displays are named after their connector, e.g. `DP-1`, on most setups. When the name
+    // doesn't match but there's only one display, that has to be the one
+    let connector = connectors
+        .iter()
+        .find(|connector| Some(connector.name.as_str()) == monitor_name)
+        .or(match connectors.as_slice() {
+            [only] => Some(only),
+            _ => None,
+        })?;
+    log_info!(
+        "Display connector {} reports VRR {}",
+        connector.name,
+        if connector.vrr_capable {
+            "supported"
+        } else {
+            "unsupported"
+        }
+    );
+    Some(connector.vrr_capable)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn check_vrr_status(_monitor_name: Option<&str>) -> Option<bool> {
+    None
+}
+
+// Just enough of the DRM ioctl interface (drm_mode.h) to read connector properties.
+#[cfg(target_os = "linux")]
+mod drm {
+    use std::ffi::CStr;
+    use std::fs::{self, File};
+    use std::os::unix::io::AsRawFd;
+
+    const DRM_IOCTL_BASE: u64 = b'd' as u64;
+    const DRM_MODE_CONNECTED: u32 = 1;
+
+    // Connector type names as the kernel prints them, indexed by DRM_MODE_CONNECTOR_*.
+    const CONNECTOR_TYPE_NAMES: [&str; 21] = [
+        "Unknown",
+        "VGA",
+        "DVI-I",
+        "DVI-D",
+        "DVI-A",
+        "Composite",
+        "SVIDEO",
+        "LVDS",
+        "Component",
+        "DIN",
+        "DP",
+        "HDMI-A",
+        "HDMI-B",
+        "TV",
+        "eDP",
+        "Virtual",
+        "DSI",
+        "DPI",
+        "Writeback",
+        "SPI",
+        "USB",
+    ];
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct CardResources {
+        fb_id_ptr: u64,
+        crtc_id_ptr: u64,
+        connector_id_ptr: u64,
+        encoder_id_ptr: u64,
+        count_fbs: u32,
+        count_crtcs: u32,
+        count_connectors: u32,
+        count_encoders: u32,
+        min_width: u32,
+        max_width: u32,
+        min_height: u32,
+        max_height: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct GetConnector {
+        encoders_ptr: u64,
+        modes_ptr: u64,
+        props_ptr: u64,
+        prop_values_ptr: u64,
+        count_modes: u32,
+        count_props: u32,
+        count_encoders: u32,
+        encoder_id: u32,
+        connector_id: u32,
+        connector_type: u32,
+        connector_type_id: u32,
+        connection: u32,
+        mm_width: u32,
+        mm_height: u32,
+        subpixel: u32,
+        pad: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct GetProperty {
+        values_ptr: u64,
+        enum_blob_ptr: u64,
+        prop_id: u32,
+        flags: u32,
+        name: [libc::c_char; 32],
+        count_values: u32,
+        count_enum_blobs: u32,
+    }
+
+    // Equivalent of the kernel's DRM_IOWR macro.
+    const fn iowr<T>(number: u64) -> u64 {
+        (3 << 30) | ((std::mem::size_of::<T>() as u64) << 16) | (DRM_IOCTL_BASE << 8) | number
+    }
+
+    const DRM_IOCTL_MODE_GETRESOURCES: u64 = iowr::<CardResources>(0xA0);
+    const DRM_IOCTL_MODE_GETCONNECTOR: u64 = iowr::<GetConnector>(0xA7);
+    const DRM_IOCTL_MODE_GETPROPERTY: u64 = iowr::<GetProperty>(0xAA);
+
+    pub struct Connector {
+        pub name: String,
+        pub vrr_capable: bool,
+    }
+
+    fn ioctl<T>(file: &File, request: u64, argument: &mut T) -> bool {
+        unsafe {
+            libc::ioctl(
+                file.as_raw_fd(),
+                request as _,
+                argument as *mut T as *mut libc::c_void,
+            ) == 0
+        }
+    }
+
+    // Lists the connected displays on every graphics card we're allowed to open.
+    pub fn connected_connectors() -> Vec<Connector> {
+        let Ok(entries) = fs::read_dir("/dev/dri") else {
+            return Vec::new();
+        };
+        entries
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("card"))
+            .filter_map(|entry| File::open(entry.path()).ok())
+            .flat_map(|card| card_connectors(&card))
+            .collect()
+    }
+
+    fn card_connectors(card: &File) -> Vec<Connector> {
+        // The first call reports the counts, the second fills in the arrays
+        let mut resources = CardResources::default();
+        if !ioctl(card, DRM_IOCTL_MODE_GETRESOURCES, &mut resources) {
+            return Vec::new();
+        }
+        let mut connector_ids = vec![0u32; resources.count_connectors as usize];
+        resources = CardResources {
+            connector_id_ptr: connector_ids.as_mut_ptr() as u64,
+            count_connectors: connector_ids.len() as u32,
+            ..Default::default()
+        };
+        if !ioctl(card, DRM_IOCTL_MODE_GETRESOURCES, &mut resources) {
+            return Vec::new();
+        }
+        connector_ids.truncate(resources.count_connectors as usize);
+
+        connector_ids
+            .into_iter()
+            .filter_map(|connector_id| read_connector(card, connector_id))
+            .collect()
+    }
+
+    fn read_connector(card: &File, connector_id: u32) -> Option<Connector> {
+        let mut connector = GetConnector {
+            connector_id,
+            ..Default::default()
+        };
+        if !ioctl(card, DRM_IOCTL_MODE_GETCONNECTOR, &mut connector)
+            || connector.connection != DRM_MODE_CONNECTED
+        {
+            return None;
+        }
+        let mut prop_ids = vec![0u32; connector.count_props as usize];
+        let mut prop_values = vec![0u64; connector.count_props as usize];
+        connector = GetConnector {
+            connector_id,
+            props_ptr: prop_ids.as_mut_ptr() as u64,
+            prop_values_ptr: prop_values.as_mut_ptr() as u64,
+            count_props: prop_ids.len() as u32,
+            ..Default::default()
+        };
+        if !ioctl(card, DRM_IOCTL_MODE_GETCONNECTOR, &mut connector) {
+            return None;
+        }
+        let prop_count = (connector.count_props as usize).min(prop_ids.len());
+
+        let vrr_capable = prop_ids[..prop_count]
+            .iter()
+            .zip(&prop_values)
+            .any(|(&prop_id, &value)| property_name(card, prop_id) == "vrr_capable" && value == 1);
+        let type_name = CONNECTOR_TYPE_NAMES
+            .get(connector.connector_type as usize)
+            .unwrap_or(&"Unknown");
+        Some(Connector {
+            name: format!("{}-{}", type_name, connector.connector_type_id),
+            vrr_capable,
+        })
+    }
+
+    fn property_name(card: &File, prop_id: u32) -> String {
+        let mut property = GetProperty {
+            prop_id,
+            ..Default::default()
+        };
+        if !ioctl(card, DRM_IOCTL_MODE_GETPROPERTY, &mut property) {
+            return String::new();
+        }
+        // The kernel always NUL terminates the name
+        unsafe { CStr::from_ptr(property.name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}